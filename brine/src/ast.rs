@@ -17,6 +17,7 @@
 #![allow(dead_code)]
 
 use std::fmt::Formatter;
+use std::io::Write;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Combinator {
@@ -84,6 +85,31 @@ impl SyntaxNode {
         self.do_output(0, max_width).0
     }
 
+    /// Write the node as a complete Unlambda 2 program.
+    ///
+    /// The file consists of:
+    /// - one comment line per line of `header`, each starting with `# `;
+    /// - the program, a single expression in Unlambda's prefix notation where `` `FA ``
+    ///   applies `F` to `A`. Applications that don't fit in `max_width` columns are split
+    ///   over several lines, with the argument indented under the function. Unlambda
+    ///   ignores whitespace between tokens, so this doesn't change the meaning of the program;
+    /// - a final newline.
+    ///
+    /// Only the combinators in [`Combinator`] are used. In particular, newlines are printed
+    /// with `.` followed by a literal newline instead of `r`. The node must not contain any
+    /// abstractions or references.
+    pub fn write_unlambda<W: Write>(
+        &self,
+        header: &str,
+        max_width: usize,
+        mut out: W,
+    ) -> std::io::Result<()> {
+        for line in header.lines() {
+            writeln!(out, "# {}", line)?;
+        }
+        writeln!(out, "{}", self.output(max_width))
+    }
+
     /// Returns a representation of the node, and a boolean indicating whether the representation
     /// contains a line break.
     fn do_output(&self, indent: usize, max_width: usize) -> (String, bool) {
@@ -111,3 +137,30 @@ impl SyntaxNode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Combinator, SyntaxNode};
+
+    #[test]
+    fn write_unlambda() {
+        let dot = |c| SyntaxNode::Combinator(Combinator::Dot(c));
+        let program = (0..10).fold(SyntaxNode::Combinator(Combinator::I), |func, i| {
+            SyntaxNode::application(func, dot(std::char::from_digit(i, 10).unwrap()))
+        });
+        let mut out = Vec::new();
+        program
+            .write_unlambda("first\nsecond", 12, &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("# first"));
+        assert_eq!(lines.next(), Some("# second"));
+        assert!(out.ends_with('\n'));
+        let program_lines: Vec<_> = lines.collect();
+        assert!(program_lines.len() > 1);
+        assert!(program_lines.iter().all(|line| line.chars().count() <= 12));
+        let tokens: String = program_lines.concat().split_whitespace().collect();
+        assert_eq!(tokens, "``````````i.0.1.2.3.4.5.6.7.8.9");
+    }
+}
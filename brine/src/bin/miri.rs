@@ -1,22 +1,333 @@
 extern crate brine;
 
-use brine::mir::{lexpr_to_mir, mir_to_lexpr, MirExpr};
-use brine::miri::run;
-use serde_lexpr::{from_str, to_string};
-use std::io::BufRead;
+use brine::check::{check_with_globals, Type};
+use brine::mir::{lexpr_to_mir, mir_to_lexpr, MirExpr, MirInternedStr};
+use brine::miri::{self, run_with_globals, Obj};
+use brine::unlambda::{self, Interpreter};
+use clap::{App, Arg};
+use lexpr::Value;
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use saltwater_parser::{pretty_print, Opt, Program};
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const HELP: &str = "\
+Enter MIR expressions to evaluate them. An expression can span several lines.
+(#:define name expr) evaluates `expr` and binds `name` to it in later entries.
+
+Commands:
+    :desugar <expr>  Show the desugared form of an expression
+    :check <expr>    Check an expression and show its type
+    :time <expr>     Evaluate an expression and show how long it took
+    :load <file>     Load a .mir file, or compile a C file and run its `main`
+    :defs            List the definitions made so far
+    :help            Show this message
+    :quit            Exit";
 
 fn main() {
-    let stdin = std::io::stdin();
-    for line in stdin.lock().lines() {
-        match lexpr::from_str(&line.unwrap())
-            .map_err(|e| e.to_string())
-            .and_then(|e| lexpr_to_mir(e))
-        {
-            Ok(p) => {
-                println!("=> {}", mir_to_lexpr(&p).to_string());
-                println!("=> {:?}", run(&p));
-            }
-            Err(e) => println!("!! {:?}", e),
+    let matches = App::new("miri")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("An interpreter for brine's MIR")
+        .arg(
+            Arg::with_name("FILE")
+                .help(
+                    "A .mir file to run, or a C file to compile and run. \
+                     Starts a REPL if not given.",
+                )
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("unlambda")
+                .long("unlambda")
+                .value_name("FILE")
+                .help(
+                    "Run an Unlambda program with stdin and stdout as its input and output. \
+                     If it evaluates to an integer, exit with it as the status.",
+                )
+                .conflicts_with("FILE"),
+        )
+        .get_matches();
+    if let Some(path) = matches.value_of("unlambda") {
+        run_unlambda(path);
+    } else if let Some(path) = matches.value_of("FILE") {
+        let mut session = Session::default();
+        if let Err(err) = session.load(Path::new(path)) {
+            eprintln!("miri: {}", err);
+            std::process::exit(1);
+        }
+    } else {
+        repl();
+    }
+}
+
+/// The definitions made so far.
+#[derive(Default)]
+struct Session {
+    globals: Vec<(MirInternedStr, Obj)>,
+    types: Vec<(MirInternedStr, Type)>,
+}
+
+/// What to do with an expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Eval,
+    Desugar,
+    Check,
+    Time,
+}
+
+impl Session {
+    /// Run a line of REPL input: a command, or expressions to evaluate.
+    fn entry(&mut self, text: &str) -> Result<(), String> {
+        let text = text.trim();
+        if !text.starts_with(':') {
+            return self.eval_text(text, Mode::Eval);
+        }
+        let (command, rest) = match text.find(char::is_whitespace) {
+            Some(i) => (&text[..i], text[i..].trim()),
+            None => (text, ""),
+        };
+        match command {
+            ":desugar" => self.eval_text(rest, Mode::Desugar),
+            ":check" => self.eval_text(rest, Mode::Check),
+            ":time" => self.eval_text(rest, Mode::Time),
+            ":load" => self.load(Path::new(rest)),
+            ":defs" => {
+                for (name, t) in &self.types {
+                    println!("{}: {}", name, t);
+                }
+                Ok(())
+            }
+            ":help" => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            _ => Err(format!("unknown command {}, try :help", command)),
+        }
+    }
+
+    /// Handle every expression in `text`, stopping at the first error.
+    fn eval_text(&mut self, text: &str, mode: Mode) -> Result<(), String> {
+        let mut parser = lexpr::Parser::from_str(text);
+        while let Some(value) = parser.next_value().map_err(|e| e.to_string())? {
+            match definition(&value)? {
+                Some((name, value)) => self.define(name, lexpr_to_mir(value)?)?,
+                None => self.handle(lexpr_to_mir(value)?, mode)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, expr: MirExpr, mode: Mode) -> Result<(), String> {
+        let expr = expr.desugar();
+        match mode {
+            Mode::Desugar => println!("{}", mir_to_lexpr(&expr)),
+            Mode::Check => println!(": {}", self.check(&expr)?),
+            Mode::Eval => println!("=> {}", self.eval(&expr)?),
+            Mode::Time => {
+                let start = Instant::now();
+                let value = self.eval(&expr)?;
+                println!("=> {}\n({:?})", value, start.elapsed());
+            }
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: MirInternedStr, expr: MirExpr) -> Result<(), String> {
+        let expr = expr.desugar();
+        let t = self.check(&expr)?;
+        let value = self.eval(&expr)?;
+        // the checker and interpreter look up names from the end and the start of their
+        // scope, so replace earlier definitions rather than shadowing them
+        self.types.retain(|(n, _)| *n != name);
+        self.types.push((name, t));
+        self.globals.retain(|(n, _)| *n != name);
+        self.globals.push((name, value));
+        println!("{} defined", name);
+        Ok(())
+    }
+
+    fn check(&self, expr: &MirExpr) -> Result<Type, String> {
+        check_with_globals(expr, &self.types).map_err(|errors| {
+            let errors: Vec<_> = errors.iter().map(|err| err.to_string()).collect();
+            errors.join("\n")
+        })
+    }
+
+    /// Check and run a desugared expression.
+    fn eval(&self, expr: &MirExpr) -> Result<Obj, String> {
+        self.check(expr)?;
+        self.run(expr).map_err(|err| err.to_string())
+    }
+
+    fn run(&self, expr: &MirExpr) -> Result<Obj, miri::Error> {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        let result = run_with_globals(expr, &self.globals, &mut std::io::stdin(), &mut stdout);
+        stdout.flush().ok();
+        result
+    }
+
+    /// Evaluate the expressions in a .mir file, or compile a C file and run it.
+    fn load(&mut self, path: &Path) -> Result<(), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("could not read {}: {}", path.display(), err))?;
+        if path.extension() != Some(OsStr::new("c")) {
+            return self.eval_text(&text, Mode::Eval);
+        }
+        let opt = Opt {
+            filename: PathBuf::from(path),
+            ..Opt::default()
+        };
+        let (program, source_map) = brine::compile_with_source_map(&text, opt);
+        let Program { result, files, .. } = program;
+        let expr = match result {
+            Ok(expr) => expr.desugar(),
+            Err(errors) => {
+                let errors: Vec<_> = errors
+                    .into_iter()
+                    .map(|err| pretty_print("error", err.data, err.location, &files))
+                    .collect();
+                return Err(errors.concat().trim_end().to_string());
+            }
+        };
+        self.check(&expr)?;
+        match self.run(&expr) {
+            Ok(value) => {
+                println!("=> {}", value);
+                Ok(())
+            }
+            // show where the error happened in the C program, if we know
+            Err(err) => match source_map.locate(&err.context) {
+                Some(location) => {
+                    let message = pretty_print("runtime error", err.kind, location, &files);
+                    Err(message.trim_end().to_string())
+                }
+                None => Err(err.to_string()),
+            },
+        }
+    }
+}
+
+/// The name and value of `(#:define name value)`, or `None` for other expressions.
+fn definition(value: &Value) -> Result<Option<(MirInternedStr, Value)>, String> {
+    let elems = match value.to_vec() {
+        Some(elems) => elems,
+        None => return Ok(None),
+    };
+    match elems.as_slice() {
+        [Value::Keyword(k), rest @ ..] if &**k == "define" => match rest {
+            [Value::Symbol(name), value] => Ok(Some((name.to_string().into(), value.clone()))),
+            _ => Err(format!(
+                "define must be (#:define name value), not {}",
+                value
+            )),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Whether `text` has as many closing parentheses as opening ones, outside of strings
+/// and comments.
+fn is_complete(text: &str) -> bool {
+    let mut depth = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            ';' => {
+                chars.find(|&c| c == '\n');
+            }
+            _ => {}
         }
     }
+    depth <= 0
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".miri_history"))
+}
+
+fn repl() {
+    let mut editor = Editor::<()>::new();
+    let history = history_path();
+    if let Some(history) = &history {
+        // there is no history the first time
+        editor.load_history(history).ok();
+    }
+    let mut session = Session::default();
+    println!("miri: type :help for help");
+    let mut entry = String::new();
+    loop {
+        let prompt = if entry.is_empty() { "miri> " } else { "  ... " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                entry.push_str(&line);
+                entry.push('\n');
+                if !is_complete(&entry) {
+                    continue;
+                }
+                let text = std::mem::take(&mut entry);
+                if text.trim().is_empty() {
+                    continue;
+                }
+                editor.add_history_entry(text.trim());
+                if text.trim() == ":quit" {
+                    break;
+                }
+                if let Err(err) = session.entry(&text) {
+                    println!("!! {}", err);
+                }
+            }
+            // ^C abandons the current entry
+            Err(ReadlineError::Interrupted) => entry.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("miri: {}", err);
+                break;
+            }
+        }
+    }
+    if let Some(history) = &history {
+        if let Err(err) = editor.save_history(history) {
+            eprintln!("miri: could not save history: {}", err);
+        }
+    }
+}
+
+fn run_unlambda(path: &str) {
+    let fail = |message: String| -> ! {
+        eprintln!("miri: {}", message);
+        std::process::exit(1);
+    };
+    let text = std::fs::read_to_string(path)
+        .unwrap_or_else(|err| fail(format!("could not read {}: {}", path, err)));
+    let program = unlambda::parse(&text).unwrap_or_else(|err| fail(err));
+    let stdout = std::io::stdout();
+    let value = Interpreter::new(std::io::stdin(), stdout.lock())
+        .eval(&program)
+        .unwrap_or_else(|err| fail(err.to_string()));
+    // decode separately, so a value which isn't an integer can't print anything
+    let status = Interpreter::new(std::io::empty(), std::io::sink())
+        .with_step_limit(1_000_000)
+        .decode_int(value);
+    if let Ok(Some(status)) = status {
+        stdout.lock().flush().ok();
+        std::process::exit(status as i32);
+    }
 }
@@ -0,0 +1,189 @@
+// Copyright 2020 Matthieu Felix
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::expr::{int_literal, Value};
+use crate::mir::{MirExpr, MirLiteral, Primitive};
+use crate::{function_ref, lift, unsupported, Compiler, Function, FUNCTIONS_NAME, STACK_POINTER};
+use saltwater_parser::hir::{Expr, ExprType};
+use saltwater_parser::{CompileResult, LiteralValue, Location, Type};
+
+impl Compiler {
+    pub fn call(
+        &mut self,
+        func: Expr,
+        args: Vec<Expr>,
+        ctype: Type,
+        location: Location,
+    ) -> CompileResult<Value> {
+        let callee = match func.expr {
+            ExprType::Id(symbol) => {
+                let name = symbol.get().id;
+                match self.functions.get(&name) {
+                    Some(&Function { index, arity }) => {
+                        if args.len() != arity {
+                            return unsupported(
+                                "calls with the wrong number of arguments",
+                                location,
+                            );
+                        }
+                        Value {
+                            val: function_ref(index),
+                            ctype: func.ctype,
+                            pure: true,
+                        }
+                    }
+                    None => {
+                        return self.builtin_call(&name.resolve_and_clone(), args, ctype, location)
+                    }
+                }
+            }
+            // a call through a function pointer, which is an index in the function table
+            ExprType::Deref(pointer) | ExprType::Noop(pointer) => {
+                // the frontend doesn't convert pointer variables to rvalues
+                // when it makes `fp()` into `(*fp)()`
+                let pointer = if pointer.lval {
+                    let ctype = pointer.ctype.clone();
+                    self.with_place(*pointer, |this, place| Ok(this.load(place, ctype)))?
+                } else {
+                    self.compile_expr(*pointer)?
+                };
+                self.map(pointer, func.ctype, |index| {
+                    MirExpr::apply(MirExpr::Ref(*FUNCTIONS_NAME), index)
+                })?
+            }
+            _ => unreachable!("only function designators can be called"),
+        };
+        if let Type::Function(func_type) = &callee.ctype {
+            if func_type.varargs {
+                return unsupported("variadic functions", location);
+            }
+        }
+        let mut values = vec![callee];
+        for arg in args {
+            values.push(self.compile_expr(arg)?);
+        }
+        self.bind_all(values.into_iter(), Vec::new(), |_, mut bound| {
+            // the callee's stack frame starts at the end of ours
+            let func = MirExpr::apply(bound.remove(0), MirExpr::Ref(*STACK_POINTER));
+            if bound.is_empty() {
+                bound.push(MirExpr::literal(MirLiteral::Null));
+            }
+            Ok(Value {
+                val: bound.into_iter().fold(func, MirExpr::apply),
+                ctype,
+                pure: false,
+            })
+        })
+    }
+
+    /// Bind the results of `values` in order, and pass them all to `rest`.
+    fn bind_all<F>(
+        &mut self,
+        mut values: std::vec::IntoIter<Value>,
+        mut bound: Vec<MirExpr>,
+        rest: F,
+    ) -> CompileResult<Value>
+    where
+        F: FnOnce(&mut Self, Vec<MirExpr>) -> CompileResult<Value>,
+    {
+        match values.next() {
+            Some(value) => self.bind(value, move |this, val| {
+                bound.push(val);
+                this.bind_all(values, bound, rest)
+            }),
+            None => rest(self, bound),
+        }
+    }
+
+    /// Calls to the I/O functions of the C library, which are lowered to primitives.
+    fn builtin_call(
+        &mut self,
+        name: &str,
+        mut args: Vec<Expr>,
+        ctype: Type,
+        location: Location,
+    ) -> CompileResult<Value> {
+        match (name, args.len()) {
+            ("putchar", 1) => {
+                let c = self.compile_expr(args.remove(0))?;
+                Ok(put_char(c, ctype))
+            }
+            ("getchar", 0) => Ok(Value {
+                val: lift_primitive(
+                    Primitive::GetChar,
+                    lift(Value {
+                        val: MirExpr::literal(MirLiteral::Null),
+                        ctype: Type::Void,
+                        pure: true,
+                    })
+                    .val,
+                ),
+                ctype,
+                pure: false,
+            }),
+            // returns the last character written, '\n', which is a nonnegative number
+            ("puts", 1) => {
+                let s = match string_literal(&args[0]) {
+                    Some(s) => s,
+                    None => return unsupported("calls to puts without a string literal", location),
+                };
+                let mut result = None;
+                for &byte in s.iter().take_while(|&&b| b != 0).chain(b"\n") {
+                    let c = Value {
+                        val: int_literal(i64::from(byte)),
+                        ctype: Type::Int(true),
+                        pure: true,
+                    };
+                    let put = put_char(c, ctype.clone());
+                    result = Some(match result {
+                        Some(prev) => self.seq(prev, put),
+                        None => put,
+                    });
+                }
+                Ok(result.unwrap())
+            }
+            _ => unsupported("calls to external functions", location),
+        }
+    }
+}
+
+fn put_char(c: Value, ctype: Type) -> Value {
+    Value {
+        val: lift_primitive(Primitive::PutChar, lift(c).val),
+        ctype,
+        pure: false,
+    }
+}
+
+/// Apply `primitive` to the result of the stateful computation `arg`.
+///
+/// The primitive runs when the computation does, so side effects happen in order.
+fn lift_primitive(primitive: Primitive, arg: MirExpr) -> MirExpr {
+    MirExpr::apply(
+        MirExpr::apply(
+            MirExpr::Primitive(Primitive::Lift),
+            MirExpr::Primitive(primitive),
+        ),
+        arg,
+    )
+}
+
+/// The bytes of a string literal, looking through the conversion to a pointer.
+pub(crate) fn string_literal(expr: &Expr) -> Option<&[u8]> {
+    match &expr.expr {
+        ExprType::Literal(LiteralValue::Str(s)) => Some(s),
+        ExprType::Cast(inner) | ExprType::Noop(inner) => string_literal(inner),
+        _ => None,
+    }
+}
@@ -20,9 +20,14 @@
 //!
 //! Each basic block has exactly one (implicit) parameter, just like each line
 //! in a do-block has one implicit parameter, the previous result.
+//!
+//! Block 0 is always the entry block.
 
 use crate::mir::{Lambda, MirExpr, MirInternedStr, MirLiteral, Primitive};
+use crate::RESULT_NAME;
 
+/// A basic block. `instr` takes the result of the previous block and returns a
+/// stateful computation; a block without an instruction passes its parameter through.
 #[derive(Debug, Clone, Default)]
 pub struct BasicBlock {
     instr: Option<Lambda>,
@@ -39,6 +44,13 @@ pub enum Jump {
     /// If the current value is true, jump to the first block; otherwise,
     /// jump to the second block.
     Br(BlockId, BlockId),
+
+    /// Jump to the block of the first case equal to the current value, or to
+    /// `default` if no case matches.
+    Switch {
+        cases: Vec<(i64, BlockId)>,
+        default: BlockId,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -49,6 +61,7 @@ pub struct Cfg {
 }
 
 lazy_static! {
+    static ref LOOP: MirInternedStr = MirInternedStr::get_or_intern("loop");
     static ref NEXT_BLOCK: MirInternedStr = MirInternedStr::get_or_intern("next_block");
     static ref DISCRIMINANT: MirInternedStr = MirInternedStr::get_or_intern("discriminant");
 }
@@ -78,9 +91,86 @@ impl Cfg {
         self.current_block = id;
     }
 
+    pub fn current_block(&self) -> BlockId {
+        self.current_block
+    }
+
+    /// Compile the whole graph into a single stateful expression.
+    ///
+    /// The result is a loop over a block dispatcher, roughly
+    ///
+    /// ```text
+    /// ((y (lambda (loop next_block _res) (switch next_block block_0 ... block_n))) 0 null)
+    /// ```
+    ///
+    /// where each block runs its instruction on `_res`, then either calls `loop` with
+    /// the id of its successor or, for the return block, returns its result.
+    /// Blocks without a jump fall through to the return block.
     pub fn to_mir(&self) -> MirExpr {
-        todo!()
+        let blocks = (0..self.blocks.len())
+            .map(|id| self.block_to_mir(id))
+            .collect();
+        let dispatcher = MirExpr::lambda(
+            *LOOP,
+            MirExpr::lambda(
+                *NEXT_BLOCK,
+                MirExpr::lambda(*RESULT_NAME, switch(MirExpr::Ref(*NEXT_BLOCK), blocks)),
+            ),
+        );
+        MirExpr::apply(
+            MirExpr::apply(
+                MirExpr::apply(MirExpr::Primitive(Primitive::Y), dispatcher),
+                MirExpr::literal(MirLiteral::Int(0)),
+            ),
+            MirExpr::literal(MirLiteral::Null),
+        )
     }
+
+    fn block_to_mir(&self, id: BlockId) -> MirExpr {
+        let block = &self.blocks[id];
+        let result = MirExpr::Ref(*RESULT_NAME);
+        let jump = match &block.jump {
+            Some(Jump::Jmp(target)) => jump_to(*target),
+            Some(Jump::Br(consequent, alternative)) => {
+                MirExpr::if_(result, jump_to(*consequent), jump_to(*alternative))
+            }
+            Some(Jump::Switch { cases, default }) => {
+                let cases = cases
+                    .iter()
+                    .map(|&(value, target)| (value, jump_to(target)))
+                    .collect();
+                switch_cases(result, cases, jump_to(*default))
+            }
+            None => match self.return_block_id {
+                Some(ret) if ret != id => jump_to(ret),
+                _ => MirExpr::apply(MirExpr::Primitive(Primitive::Pure), result),
+            },
+        };
+        match &block.instr {
+            Some(Lambda { arg, body }) => MirExpr::apply(
+                MirExpr::apply(
+                    MirExpr::Primitive(Primitive::Then),
+                    MirExpr::apply(
+                        MirExpr::lambda(*arg, body.clone()),
+                        MirExpr::Ref(*RESULT_NAME),
+                    ),
+                ),
+                MirExpr::lambda(*RESULT_NAME, jump),
+            ),
+            None => jump,
+        }
+    }
+}
+
+/// Continue the dispatcher loop at block `target`, passing it the current result.
+fn jump_to(target: BlockId) -> MirExpr {
+    MirExpr::apply(
+        MirExpr::apply(
+            MirExpr::Ref(*LOOP),
+            MirExpr::literal(MirLiteral::Int(target as i64)),
+        ),
+        MirExpr::Ref(*RESULT_NAME),
+    )
 }
 
 impl Default for Cfg {
@@ -98,22 +188,31 @@ impl Default for Cfg {
 ///
 /// If the discriminant is not within `0..exprs.len()` at runtime,
 /// behavior is undefined.
-fn switch(discriminant: MirExpr, mut exprs: Vec<MirExpr>) -> MirExpr {
-    MirExpr::let_(*DISCRIMINANT, discriminant, switch_helper(0, exprs))
+pub(crate) fn switch(discriminant: MirExpr, mut exprs: Vec<MirExpr>) -> MirExpr {
+    // the last expression is selected whenever none of the others are
+    let last = exprs.pop().expect("switch given empty exprs");
+    let cases = exprs
+        .into_iter()
+        .enumerate()
+        .map(|(i, expr)| (i as i64, expr))
+        .collect();
+    switch_cases(discriminant, cases, last)
 }
 
-fn switch_helper(current_pos: i64, mut exprs: Vec<MirExpr>) -> MirExpr {
-    let expr = exprs.pop().expect("switch given empty exprs");
-    if exprs.is_empty() {
-        expr
-    } else {
-        let discriminant = MirExpr::Ref(*DISCRIMINANT);
-        MirExpr::if_(
-            eq_expr(current_pos, discriminant),
-            expr,
-            switch_helper(current_pos + 1, exprs),
-        )
-    }
+/// Generates a switch statement which selects the expression of the first case
+/// equal to `discriminant`, or `default` if there is no such case.
+fn switch_cases(discriminant: MirExpr, cases: Vec<(i64, MirExpr)>, default: MirExpr) -> MirExpr {
+    let body = cases
+        .into_iter()
+        .rev()
+        .fold(default, |alternative, (value, consequent)| {
+            MirExpr::if_(
+                eq_expr(value, MirExpr::Ref(*DISCRIMINANT)),
+                consequent,
+                alternative,
+            )
+        });
+    MirExpr::let_(*DISCRIMINANT, discriminant, body)
 }
 
 fn eq_expr(n: i64, e: MirExpr) -> MirExpr {
@@ -125,3 +224,32 @@ fn eq_expr(n: i64, e: MirExpr) -> MirExpr {
         e,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{switch, switch_cases};
+    use crate::mir::{MirExpr, MirLiteral};
+    use crate::miri::{run, Obj};
+
+    #[test]
+    fn switch_selects_nth() {
+        let exprs: Vec<_> = (10..13)
+            .map(|i| MirExpr::literal(MirLiteral::Int(i)))
+            .collect();
+        for i in 0..3 {
+            let discriminant = MirExpr::literal(MirLiteral::Int(i));
+            let expr = switch(discriminant, exprs.clone()).desugar();
+            assert_eq!(run(&expr).unwrap(), Obj::Int(10 + i));
+        }
+    }
+
+    #[test]
+    fn switch_cases_falls_back_to_default() {
+        let int = |i| MirExpr::literal(MirLiteral::Int(i));
+        let cases = vec![(-1, int(10)), (5, int(11)), (2, int(12))];
+        for &(discriminant, expected) in &[(-1, 10), (5, 11), (2, 12), (0, 13), (3, 13)] {
+            let expr = switch_cases(int(discriminant), cases.clone(), int(13)).desugar();
+            assert_eq!(run(&expr).unwrap(), Obj::Int(expected));
+        }
+    }
+}
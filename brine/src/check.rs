@@ -0,0 +1,375 @@
+// Copyright 2020 Matthieu Felix
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! ## Scope and type checking
+//!
+//! Finds mistakes in MIR before it runs: references to names which aren't bound,
+//! and primitives or `if`s used on values of the wrong type.
+//!
+//! MIR is untyped, and well-behaved programs routinely mix types in the same place,
+//! like lists which are either null or pairs, or the self-application in the Y
+//! combinator. So the checker only infers a type when it is sure of it, and uses
+//! [`Type::Any`] otherwise. An error means the program would fail if that expression
+//! were ever evaluated.
+//!
+//! Lambdas are checked assuming nothing about their argument, except when they are
+//! applied right away, like a desugared `let`, where the argument is the type of
+//! the value they are applied to.
+
+use crate::mir::{MirExpr, MirInternedStr, MirLiteral, Primitive};
+use std::fmt::{self, Display, Formatter};
+
+/// The type of a MIR value, as far as the checker can tell.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    /// Could be anything
+    Any,
+    Int,
+    Bool,
+    Null,
+    Pair(Box<Type>, Box<Type>),
+    /// A lambda, which returns a value of the given type when applied
+    Function(Box<Type>),
+    /// A low-level primitive, applied to fewer arguments than it takes
+    Primitive(Primitive, Vec<Type>),
+}
+
+impl Type {
+    /// The most precise type which includes both `self` and `other`.
+    fn join(self, other: Type) -> Type {
+        match (self, other) {
+            (Type::Pair(a, b), Type::Pair(c, d)) => {
+                Type::Pair(Box::new(a.join(*c)), Box::new(b.join(*d)))
+            }
+            (Type::Function(a), Type::Function(b)) => Type::Function(Box::new(a.join(*b))),
+            (a, b) if a == b => a,
+            _ => Type::Any,
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Type::Any => write!(f, "any"),
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+            Type::Null => write!(f, "null"),
+            Type::Pair(car, cdr) => write!(f, "(pair {} {})", car, cdr),
+            Type::Function(_) | Type::Primitive(..) => write!(f, "function"),
+        }
+    }
+}
+
+/// What a primitive expects one of its arguments to be.
+#[derive(Debug, Clone, Copy)]
+enum Param {
+    Any,
+    Int,
+    Bool,
+    Pair,
+}
+
+impl Param {
+    fn accepts(self, t: &Type) -> bool {
+        matches!(
+            (self, t),
+            (Param::Any, _)
+                | (_, Type::Any)
+                | (Param::Int, Type::Int)
+                | (Param::Bool, Type::Bool)
+                | (Param::Pair, Type::Pair(..))
+        )
+    }
+}
+
+impl Display for Param {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Param::Any => write!(f, "any"),
+            Param::Int => write!(f, "int"),
+            Param::Bool => write!(f, "bool"),
+            Param::Pair => write!(f, "a pair"),
+        }
+    }
+}
+
+/// A problem found by the checker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub message: String,
+    /// The comments around the faulty expression, outermost first
+    pub context: Vec<String>,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for comment in &self.context {
+            write!(f, "{}: ", comment)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Check `expr`, and return its type, or every error found in it.
+pub fn check(expr: &MirExpr) -> Result<Type, Vec<Error>> {
+    check_with_globals(expr, &[])
+}
+
+/// Check `expr`, where each of `globals` is bound to a value of the given type.
+pub fn check_with_globals(
+    expr: &MirExpr,
+    globals: &[(MirInternedStr, Type)],
+) -> Result<Type, Vec<Error>> {
+    let mut checker = Checker {
+        scope: globals.to_vec(),
+        ..Checker::default()
+    };
+    let t = checker.check(expr);
+    if checker.errors.is_empty() {
+        Ok(t)
+    } else {
+        Err(checker.errors)
+    }
+}
+
+#[derive(Default)]
+struct Checker {
+    /// The variables in scope and their types, innermost last
+    scope: Vec<(MirInternedStr, Type)>,
+    /// The comments around the current expression, outermost first
+    context: Vec<String>,
+    errors: Vec<Error>,
+}
+
+impl Checker {
+    fn error(&mut self, message: String) {
+        self.errors.push(Error {
+            message,
+            context: self.context.clone(),
+        });
+    }
+
+    fn check(&mut self, expr: &MirExpr) -> Type {
+        match expr {
+            MirExpr::Let(l) => {
+                let value = self.check(&l.value);
+                self.check_under(l.ident, value, &l.body)
+            }
+            MirExpr::Lambda(l) => {
+                Type::Function(Box::new(self.check_under(l.arg, Type::Any, &l.body)))
+            }
+            MirExpr::If(if_) => {
+                let condition = self.check(&if_.condition);
+                if !Param::Bool.accepts(&condition) {
+                    self.error(format!(
+                        "the condition of an if should be bool, not {}",
+                        condition
+                    ));
+                }
+                let consequent = self.check(&if_.consequent);
+                let alternative = self.check(&if_.alternative);
+                consequent.join(alternative)
+            }
+            MirExpr::Apply(ap) => match &ap.func {
+                // an applied lambda is a `let`, so we know the type of its argument
+                MirExpr::Lambda(l) => {
+                    let arg = self.check(&ap.arg);
+                    self.check_under(l.arg, arg, &l.body)
+                }
+                func => {
+                    let func = self.check(func);
+                    let arg = self.check(&ap.arg);
+                    self.apply(func, arg)
+                }
+            },
+            MirExpr::Primitive(p) => match parameters(*p) {
+                Some(_) => Type::Primitive(*p, Vec::new()),
+                None => Type::Any,
+            },
+            MirExpr::Literal(literal) => match **literal {
+                MirLiteral::Bool(_) => Type::Bool,
+                MirLiteral::Int(_) => Type::Int,
+                MirLiteral::Null => Type::Null,
+            },
+            MirExpr::Ref(name) => match self.scope.iter().rev().find(|(n, _)| n == name) {
+                Some((_, t)) => t.clone(),
+                None => {
+                    self.error(format!("reference to undefined name {}", name));
+                    Type::Any
+                }
+            },
+            MirExpr::Comment(comment, body) => {
+                self.context.push(comment.clone());
+                let t = self.check(body);
+                self.context.pop();
+                t
+            }
+        }
+    }
+
+    /// Check `body`, with `name` bound to a value of type `t`.
+    fn check_under(&mut self, name: MirInternedStr, t: Type, body: &MirExpr) -> Type {
+        self.scope.push((name, t));
+        let t = self.check(body);
+        self.scope.pop();
+        t
+    }
+
+    fn apply(&mut self, func: Type, arg: Type) -> Type {
+        match func {
+            Type::Any => Type::Any,
+            Type::Function(result) => *result,
+            Type::Primitive(p, mut args) => {
+                let params = parameters(p).expect("only low-level primitives have a type");
+                let expected = params[args.len()];
+                if !expected.accepts(&arg) {
+                    self.error(format!(
+                        "argument {} of {} should be {}, not {}",
+                        args.len() + 1,
+                        serde_lexpr::to_string(&p).unwrap(),
+                        expected,
+                        arg
+                    ));
+                }
+                args.push(arg);
+                if args.len() < params.len() {
+                    Type::Primitive(p, args)
+                } else {
+                    result(p, args)
+                }
+            }
+            other => {
+                self.error(format!("cannot apply a value of type {}", other));
+                Type::Any
+            }
+        }
+    }
+}
+
+/// What a low-level primitive expects its arguments to be, or `None` for high-level ones.
+fn parameters(p: Primitive) -> Option<&'static [Param]> {
+    use Primitive::*;
+    Some(match p {
+        Plus | Minus | Times | Div | Mod | DivUnsigned | ModUnsigned | Truncate | SignExtend
+        | BitAnd | BitOr | BitXor | Shl | Shr | ShrUnsigned | Eq | Lt | Le | Gt | Ge => {
+            &[Param::Int, Param::Int]
+        }
+        BitNot | PutChar => &[Param::Int],
+        And | Or | Xor => &[Param::Bool, Param::Bool],
+        Neg | BoolToInt => &[Param::Bool],
+        Cons => &[Param::Any, Param::Any],
+        Car | Cdr => &[Param::Pair],
+        GetChar | IsNull => &[Param::Any],
+        Get(_) | Set(_) | Load | Store | Frame(_) | Pure | Lift | Then | Y => return None,
+    })
+}
+
+/// The type of a low-level primitive applied to all of its arguments.
+fn result(p: Primitive, mut args: Vec<Type>) -> Type {
+    use Primitive::*;
+    match p {
+        Plus | Minus | Times | Div | Mod | DivUnsigned | ModUnsigned | Truncate | SignExtend
+        | BitAnd | BitOr | BitXor | BitNot | Shl | Shr | ShrUnsigned | BoolToInt | PutChar
+        | GetChar => Type::Int,
+        Neg | And | Or | Xor | Eq | Lt | Le | Gt | Ge | IsNull => Type::Bool,
+        Cons => {
+            let cdr = args.pop().unwrap();
+            let car = args.pop().unwrap();
+            Type::Pair(Box::new(car), Box::new(cdr))
+        }
+        Car | Cdr => match args.pop() {
+            Some(Type::Pair(car, _)) if matches!(p, Car) => *car,
+            Some(Type::Pair(_, cdr)) => *cdr,
+            _ => Type::Any,
+        },
+        Get(_) | Set(_) | Load | Store | Frame(_) | Pure | Lift | Then | Y => {
+            unreachable!("high-level primitives are never fully applied")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, Error, Type};
+    use crate::mir::parse_mir;
+
+    fn check_str(program: &str) -> Result<Type, Vec<Error>> {
+        check(&parse_mir(program).unwrap())
+    }
+
+    fn assert_error(program: &str, expected: &str) {
+        let errors = check_str(program).expect_err(program);
+        let messages: Vec<_> = errors.iter().map(Error::to_string).collect();
+        assert_eq!(messages, vec![expected.to_string()], "{}", program);
+    }
+
+    #[test]
+    fn types() {
+        assert_eq!(check_str("(plus 1 2)"), Ok(Type::Int));
+        assert_eq!(check_str("(#:let (x (lt 1 2)) (neg x))"), Ok(Type::Bool));
+        assert_eq!(check_str("(car (cons #t ()))"), Ok(Type::Bool));
+        assert_eq!(
+            check_str("((#:lambda (x) (plus x 1)) 2)").unwrap(),
+            Type::Int
+        );
+        assert_eq!(check_str("(#:if #t 1 2)"), Ok(Type::Int));
+        assert_eq!(check_str("(#:if #t 1 ())"), Ok(Type::Any));
+        // the high-level primitives are checked before and after desugaring
+        let stateful = "(then (pure 1) (#:lambda (x) (pure (plus x 1))))";
+        assert_eq!(check_str(stateful), Ok(Type::Any));
+        assert!(check(&parse_mir(stateful).unwrap().desugar()).is_ok());
+        let fac = "(#:let (fac (#:lambda (f n) (#:if (eq n 1) 1 (times n (f (minus n 1))))))
+                     ((y fac) 10))";
+        assert!(check_str(fac).is_ok());
+        assert!(check(&parse_mir(fac).unwrap().desugar()).is_ok());
+    }
+
+    #[test]
+    fn errors() {
+        assert_error("(plus x 1)", "reference to undefined name x");
+        assert_error(
+            "(#:lambda (x) (#:let (y x) z))",
+            "reference to undefined name z",
+        );
+        assert_error("(plus 1 #t)", "argument 2 of plus should be int, not bool");
+        assert_error(
+            "(#:if 1 2 3)",
+            "the condition of an if should be bool, not int",
+        );
+        assert_error("(car ())", "argument 1 of car should be a pair, not null");
+        assert_error(
+            "(#:let (f (#:lambda (x) (eq x 0))) (plus (f 2) 1))",
+            "argument 1 of plus should be int, not bool",
+        );
+        assert_error("(3 4)", "cannot apply a value of type int");
+        // lists are null or pairs, so they can't be checked any further
+        assert!(check_str("(#:lambda (l) (car (#:if (is-null l) (cons 1 ()) l)))").is_ok());
+        // every error is reported
+        let errors = check_str("(cons (neg 1) (is-null z))").unwrap_err();
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn context() {
+        assert_error(
+            r#"(#:comment "function main" (#:lambda (x) (#:comment "loop" (and x 1))))"#,
+            "function main: loop: argument 2 of and should be bool, not int",
+        );
+        let errors =
+            check_str(r#"(cons (#:comment "first" a) (#:comment "second" b))"#).unwrap_err();
+        assert_eq!(errors[0].context, vec!["first".to_string()]);
+        assert_eq!(errors[1].context, vec!["second".to_string()]);
+    }
+}
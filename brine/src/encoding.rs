@@ -0,0 +1,605 @@
+//! ## Combinator encodings
+//!
+//! How MIR values and primitives are represented once compiled to combinators.
+//!
+//! - Booleans are Church booleans: `true` is `k` (`λa b. a`) and `false` is `` `ki ``
+//!   (`λa b. b`). `if` applies the condition to two thunks and runs the one it picks.
+//! - Pairs are `λf. f car cdr`, so `car` applies a pair to `true` and `cdr` to `false`.
+//! - `null` is `` `kk ``, which answers `true` when asked whether it is null, where
+//!   a pair would answer `false`. It also terminates lists.
+//! - Integers are two's complement, as a null-terminated list of 64 booleans with
+//!   the least significant bit first.
+//!
+//! Primitives are written in MIR in [`DEFINITIONS`], compiled once, and inlined
+//! everywhere they are used.
+//!
+//! ### Input and output
+//!
+//! Definitions can use the Unlambda combinators directly with [raw names](raw_combinator)
+//! like `$dot-65` for `.A`. `put-char` walks a binary tree of the 256 `.x` combinators
+//! using the low 8 bits of its argument, and applies the one it finds. `get-char` reads
+//! with `@`, then tries `?x` for each byte in turn until one matches. `?x` and `@` answer
+//! by applying their argument to `i` or `v`; since `v` swallows everything it is applied
+//! to, telling them apart needs `c` to escape when the answer is `i`. Bytes are mapped
+//! to the characters U+0000 to U+00FF.
+//!
+//! ### Cost model
+//!
+//! Costs are counted in applications of combinators. They were measured on
+//! 64-bit integers and are rounded:
+//!
+//! | Operation                          | Applications                            |
+//! |------------------------------------|-----------------------------------------|
+//! | integer literal                    | 400                                     |
+//! | boolean primitives, `if`           | 10                                      |
+//! | `cons`, `car`, `cdr`, `is-null`    | 50                                      |
+//! | `eq`                               | 300 per matching low bit, up to 20,000  |
+//! | `plus`, `minus`                    | 50,000                                  |
+//! | `truncate`, `sign-extend`          | 20,000                                  |
+//! | `bit-and`, `bit-or`, `bit-xor`     | 25,000                                  |
+//! | `bit-not`                          | 10,000                                  |
+//! | `shl`, `shr`, `shr-unsigned`       | 15,000 per bit shifted, up to 1,200,000 |
+//! | `lt`, `le`, `gt`, `ge`             | 15,000 if the signs differ, else 75,000 |
+//! | `times`                            | 50,000 per set bit of the right operand |
+//! | `div`, `mod` and unsigned versions | 3,000,000                               |
+//! | `put-char`                         | 1,000                                   |
+//! | `get-char`                         | 200 per byte value below the one read   |
+//!
+//! Everything is linear in the number of bits, except `times`, `div`, `mod` and
+//! the shifts, which are quadratic. Since the program text has no sharing, every
+//! use of a primitive also adds a copy of its definition to the output.
+
+use crate::ast::Combinator;
+use crate::mir::{parse_mir, MirLiteral, Primitive};
+use crate::ski::{compile_definition, Term};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Number of bits in an integer.
+pub const INT_BITS: u32 = 64;
+
+/// Definitions of the primitives, in order. Each one can use those before it.
+///
+/// Names start with `%` so they can't clash with variables from C. References
+/// to definitions are treated as values, so each must evaluate to a function
+/// without side effects.
+const DEFINITIONS: &[(&str, &str)] = &[
+    // booleans and pairs
+    ("%not", "(#:lambda (x) (x #f #t))"),
+    ("%and", "(#:lambda (x z) (x z #f))"),
+    ("%or", "(#:lambda (x z) (x #t z))"),
+    ("%xor", "(#:lambda (x z) (x (%not z) z))"),
+    ("%pair", "(#:lambda (a b f) (f a b))"),
+    ("%fst", "(#:lambda (p) (p #t))"),
+    ("%snd", "(#:lambda (p) (p #f))"),
+    ("%nil?", "(#:lambda (l) (l (#:lambda (a b) #f)))"),
+    (
+        "%fix",
+        "(#:lambda (f)
+           ((#:lambda (g) (g g))
+            (#:lambda (g) (f (#:lambda (v) ((g g) v))))))",
+    ),
+    // lists of bits
+    (
+        "%map-not",
+        "(%fix (#:lambda (rec l)
+           (#:if (%nil? l) ()
+             (%pair (%not (%fst l)) (rec (%snd l))))))",
+    ),
+    (
+        "%last",
+        "(%fix (#:lambda (rec l)
+           (#:if (%nil? (%snd l)) (%fst l) (rec (%snd l)))))",
+    ),
+    (
+        "%init",
+        "(%fix (#:lambda (rec l)
+           (#:if (%nil? (%snd l)) () (%pair (%fst l) (rec (%snd l))))))",
+    ),
+    (
+        "%reverse",
+        "(#:lambda (l)
+           ((%fix (#:lambda (rec acc l)
+              (#:if (%nil? l) acc (rec (%pair (%fst l) acc) (%snd l)))))
+            () l))",
+    ),
+    // arithmetic; `z` may be longer than `x`, the result has the length of `x`
+    (
+        "%carry",
+        "(#:lambda (c a b) (%or (%and a b) (%and c (%xor a b))))",
+    ),
+    (
+        "%add-carry",
+        "(%fix (#:lambda (rec c x z)
+           (#:if (%nil? x) ()
+             (#:let (a (%fst x))
+               (#:let (b (%fst z))
+                 (%pair (%xor (%xor a b) c)
+                        (rec (%carry c a b) (%snd x) (%snd z))))))))",
+    ),
+    (
+        "%carry-out",
+        "(%fix (#:lambda (rec c x z)
+           (#:if (%nil? x) c
+             (rec (%carry c (%fst x) (%fst z)) (%snd x) (%snd z)))))",
+    ),
+    ("%plus", "(#:lambda (x z) (%add-carry #f x z))"),
+    ("%minus", "(#:lambda (x z) (%add-carry #t x (%map-not z)))"),
+    ("%negate", "(#:lambda (x) (%minus 0 x))"),
+    (
+        "%times",
+        "(#:lambda (x z)
+           ((%fix (#:lambda (rec acc x z)
+              (#:if (%nil? z) acc
+                (rec (#:if (%fst z) (%plus acc x) acc) (%pair #f x) (%snd z)))))
+            0 x z))",
+    ),
+    // restoring division, going through the bits of `n` from the most significant
+    (
+        "%divmod-unsigned",
+        "(#:lambda (n d)
+           ((%fix (#:lambda (rec q r nd bits)
+              (#:if (%nil? bits) (%pair q r)
+                (#:let (r (%pair (%fst bits) (%init r)))
+                  (#:let (fits (%carry-out #t r nd))
+                    (rec (%pair fits q)
+                         (#:if fits (%add-carry #t r nd) r)
+                         nd
+                         (%snd bits)))))))
+            () 0 (%map-not d) (%reverse n)))",
+    ),
+    (
+        "%div-unsigned",
+        "(#:lambda (x z) (%fst (%divmod-unsigned x z)))",
+    ),
+    (
+        "%mod-unsigned",
+        "(#:lambda (x z) (%snd (%divmod-unsigned x z)))",
+    ),
+    ("%abs", "(#:lambda (x) (#:if (%last x) (%negate x) x))"),
+    // C rounds towards zero, and the remainder has the sign of the dividend
+    (
+        "%div",
+        "(#:lambda (x z)
+           (#:let (q (%fst (%divmod-unsigned (%abs x) (%abs z))))
+             (#:if (%xor (%last x) (%last z)) (%negate q) q)))",
+    ),
+    (
+        "%mod",
+        "(#:lambda (x z)
+           (#:let (r (%snd (%divmod-unsigned (%abs x) (%abs z))))
+             (#:if (%last x) (%negate r) r)))",
+    ),
+    // narrowing, where the mask `m` has ones in the bits to keep
+    (
+        "%truncate",
+        "(%fix (#:lambda (rec m x)
+           (#:if (%nil? x) ()
+             (%pair (%and (%fst m) (%fst x)) (rec (%snd m) (%snd x))))))",
+    ),
+    (
+        "%sign-extend",
+        "(#:lambda (m x)
+           ((%fix (#:lambda (rec sign m x)
+              (#:if (%nil? x) ()
+                (#:let (b (#:if (%fst m) (%fst x) sign))
+                  (%pair b (rec b (%snd m) (%snd x)))))))
+            #f m x))",
+    ),
+    // bitwise operations
+    (
+        "%zip-with",
+        "(%fix (#:lambda (rec f x z)
+           (#:if (%nil? x) ()
+             (%pair (f (%fst x) (%fst z)) (rec f (%snd x) (%snd z))))))",
+    ),
+    ("%bit-and", "(%zip-with %and)"),
+    ("%bit-or", "(%zip-with %or)"),
+    ("%bit-xor", "(%zip-with %xor)"),
+    (
+        "%snoc",
+        "(%fix (#:lambda (rec l v)
+           (#:if (%nil? l) (%pair v ()) (%pair (%fst l) (rec (%snd l) v)))))",
+    ),
+    // apply `shift`, which shifts by one bit, as many times as the low 6 bits of `n`
+    // say, by shifting by 1, 2, 4... bits for each bit of `n` that is set
+    (
+        "%shift-by",
+        "(#:lambda (shift x n)
+           ((%fix (#:lambda (rec shift x n count)
+              (#:if (%nil? count) x
+                (rec (#:lambda (v) (shift (shift v)))
+                     (#:if (%fst n) (shift x) x)
+                     (%snd n)
+                     (%snd count)))))
+            shift x n (%pair () (%pair () (%pair () (%pair () (%pair () (%pair () ()))))))))",
+    ),
+    ("%shl", "(%shift-by (#:lambda (x) (%init (%pair #f x))))"),
+    (
+        "%shr",
+        "(%shift-by (#:lambda (x) (%snoc (%snd x) (%last x))))",
+    ),
+    (
+        "%shr-unsigned",
+        "(%shift-by (#:lambda (x) (%snoc (%snd x) #f)))",
+    ),
+    // comparisons
+    (
+        "%eq",
+        "(%fix (#:lambda (rec x z)
+           (#:if (%nil? x) #t
+             (#:if (%xor (%fst x) (%fst z)) #f (rec (%snd x) (%snd z))))))",
+    ),
+    // when the signs are the same, the subtraction can't overflow
+    (
+        "%lt",
+        "(#:lambda (x z)
+           (#:let (sx (%last x))
+             (#:if (%xor sx (%last z)) sx (%last (%minus x z)))))",
+    ),
+    ("%gt", "(#:lambda (x z) (%lt z x))"),
+    ("%le", "(#:lambda (x z) (%not (%lt z x)))"),
+    ("%ge", "(#:lambda (x z) (%not (%lt x z)))"),
+    ("%bool-to-int", "(#:lambda (b) (%pair b (%snd 0)))"),
+];
+
+thread_local! {
+    /// The compiled definitions, by name.
+    pub(crate) static PRELUDE: HashMap<String, Rc<Term>> = compile_prelude();
+}
+
+fn compile_prelude() -> HashMap<String, Rc<Term>> {
+    let mut prelude = HashMap::new();
+    let definitions = DEFINITIONS
+        .iter()
+        .map(|&(name, code)| (name, code.to_string()))
+        .chain(io_definitions());
+    for (name, code) in definitions {
+        let term = parse_mir(&code)
+            .and_then(|expr| compile_definition(&expr, &prelude))
+            .unwrap_or_else(|err| panic!("invalid definition of {}: {}", name, err));
+        prelude.insert(name.to_string(), term);
+    }
+    prelude
+}
+
+/// Definitions of the I/O primitives, which are too repetitive to write by hand.
+fn io_definitions() -> Vec<(&'static str, String)> {
+    // a complete binary tree, where going to the `cdr` at depth `n` sets bit `n`
+    fn byte_tree(depth: u32, byte: u32) -> String {
+        if depth == 8 {
+            format!("$dot-{}", byte)
+        } else {
+            format!(
+                "(%pair {} {})",
+                byte_tree(depth + 1, byte),
+                byte_tree(depth + 1, byte | 1 << depth)
+            )
+        }
+    }
+    // the reader limits nesting, so build the list 16 elements at a time
+    let formals: Vec<_> = (0..16).map(|i| format!("x{}", i)).collect();
+    let prepend = formals.iter().rev().fold("rest".to_string(), |rest, x| {
+        format!("(%pair {} {})", x, rest)
+    });
+    let prepend = format!("(#:lambda ({} rest) {})", formals.join(" "), prepend);
+    let compare_list = (0..16).rev().fold("()".to_string(), |rest, chunk| {
+        let compares: Vec<_> = (0..16)
+            .map(|i| format!("$compare-{}", chunk * 16 + i))
+            .collect();
+        format!("(%prepend-16 {} {})", compares.join(" "), rest)
+    });
+    // follow the low 8 bits `b0` to `b7` down the tree, print, then return the bits
+    let mut put_char = "((#:lambda (ignored) low-bits) (t8 ()))".to_string();
+    let mut low_bits = "(%snd (%snd (%snd (%snd (%snd (%snd (%snd (%snd 0))))))))".to_string();
+    for bit in (0..8).rev() {
+        low_bits = format!("(%pair b{} {})", bit, low_bits);
+        if bit < 7 {
+            put_char = format!("(#:let (l{} (%snd l{})) {})", bit + 1, bit, put_char);
+        }
+        put_char = format!(
+            "(#:let (b{bit} (%fst l{bit})) (#:let (t{next} (%pick t{bit} b{bit})) {rest}))",
+            bit = bit,
+            next = bit + 1,
+            rest = put_char
+        );
+    }
+    let put_char = format!(
+        "(#:lambda (l0) (#:let (t0 %byte-tree) {}))",
+        put_char.replace("low-bits", &low_bits)
+    );
+    vec![
+        ("%byte-tree", byte_tree(0, 0)),
+        ("%pick", "(#:lambda (t b) ((b %snd %fst) t))".into()),
+        ("%put-char", put_char),
+        // `i` or `v` to a boolean
+        (
+            "%io-test",
+            "(#:lambda (r)
+               ($c (#:lambda (k)
+                 ((#:lambda (ignored) #f) ((r (#:lambda (ignored) (k #t))) $i)))))"
+                .into(),
+        ),
+        ("%prepend-16", prepend),
+        ("%compare-list", compare_list),
+        (
+            "%inc",
+            "(%fix (#:lambda (rec l)
+               (#:if (%nil? l) ()
+                 (#:if (%fst l) (%pair #f (rec (%snd l))) (%pair #t (%snd l))))))"
+                .into(),
+        ),
+        (
+            "%find-char",
+            "(%fix (#:lambda (rec cs n)
+               (#:if (%nil? cs) -1
+                 (#:if (%io-test ((%fst cs) $i)) n (rec (%snd cs) (%inc n))))))"
+                .into(),
+        ),
+        (
+            "%get-char",
+            "(#:lambda (ignored)
+               ($read (#:lambda (ok)
+                 (#:if (%io-test ok) (%find-char %compare-list 0) -1))))"
+                .into(),
+        ),
+    ]
+}
+
+/// The combinator a name starting with `$` stands for, in prelude definitions.
+///
+/// These are the lowercase names of the combinators, `$read` and `$reprint` for
+/// `@` and `|`, and `$dot-N` and `$compare-N` for `.x` and `?x`, where `N` is the
+/// code of `x`.
+pub fn raw_combinator(name: &str) -> Option<Combinator> {
+    let byte = |code: &str| code.parse::<u8>().ok().map(char::from);
+    Some(match name.strip_prefix('$')? {
+        "i" => Combinator::I,
+        "k" => Combinator::K,
+        "s" => Combinator::S,
+        "v" => Combinator::V,
+        "d" => Combinator::D,
+        "c" => Combinator::C,
+        "e" => Combinator::E,
+        "read" => Combinator::Read,
+        "reprint" => Combinator::Reprint,
+        other => {
+            if let Some(code) = other.strip_prefix("dot-") {
+                Combinator::Dot(byte(code)?)
+            } else {
+                Combinator::Compare(byte(other.strip_prefix("compare-")?)?)
+            }
+        }
+    })
+}
+
+/// The name of the prelude definition implementing `primitive`, or `None` if
+/// it should have been desugared.
+pub fn primitive_name(primitive: Primitive) -> Option<&'static str> {
+    Some(match primitive {
+        Primitive::Plus => "%plus",
+        Primitive::Minus => "%minus",
+        Primitive::Times => "%times",
+        Primitive::Div => "%div",
+        Primitive::Mod => "%mod",
+        Primitive::DivUnsigned => "%div-unsigned",
+        Primitive::ModUnsigned => "%mod-unsigned",
+        Primitive::Truncate => "%truncate",
+        Primitive::SignExtend => "%sign-extend",
+        Primitive::Neg => "%not",
+        Primitive::And => "%and",
+        Primitive::Or => "%or",
+        Primitive::Xor => "%xor",
+        Primitive::BitAnd => "%bit-and",
+        Primitive::BitOr => "%bit-or",
+        Primitive::BitXor => "%bit-xor",
+        Primitive::BitNot => "%map-not",
+        Primitive::Shl => "%shl",
+        Primitive::Shr => "%shr",
+        Primitive::ShrUnsigned => "%shr-unsigned",
+        Primitive::Cons => "%pair",
+        Primitive::Car => "%fst",
+        Primitive::Cdr => "%snd",
+        Primitive::Eq => "%eq",
+        Primitive::Lt => "%lt",
+        Primitive::Le => "%le",
+        Primitive::Gt => "%gt",
+        Primitive::Ge => "%ge",
+        Primitive::BoolToInt => "%bool-to-int",
+        Primitive::PutChar => "%put-char",
+        Primitive::GetChar => "%get-char",
+        Primitive::IsNull => "%nil?",
+        Primitive::Get(_)
+        | Primitive::Set(_)
+        | Primitive::Load
+        | Primitive::Store
+        | Primitive::Frame(_)
+        | Primitive::Pure
+        | Primitive::Lift
+        | Primitive::Then
+        | Primitive::Y => return None,
+    })
+}
+
+/// The encoding of a literal, as a value.
+pub(crate) fn literal(literal: &MirLiteral) -> Rc<Term> {
+    match literal {
+        MirLiteral::Bool(b) => boolean(*b),
+        MirLiteral::Null => null(),
+        MirLiteral::Int(i) => (0..INT_BITS)
+            .rev()
+            .fold(null(), |rest, bit| pair(boolean(i >> bit & 1 == 1), rest)),
+    }
+}
+
+fn combinator(c: Combinator) -> Rc<Term> {
+    Term::combinator(c)
+}
+
+fn boolean(b: bool) -> Rc<Term> {
+    if b {
+        combinator(Combinator::K)
+    } else {
+        Term::application(combinator(Combinator::K), combinator(Combinator::I))
+    }
+}
+
+fn null() -> Rc<Term> {
+    Term::application(combinator(Combinator::K), combinator(Combinator::K))
+}
+
+/// `λf. f car cdr`, as `` ``s``si`kcar`kcdr ``
+fn pair(car: Rc<Term>, cdr: Rc<Term>) -> Rc<Term> {
+    let s = || combinator(Combinator::S);
+    let k = |x| Term::application(combinator(Combinator::K), x);
+    let apply_car = Term::application(Term::application(s(), combinator(Combinator::I)), k(car));
+    Term::application(Term::application(s(), apply_car), k(cdr))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mir::parse_mir;
+    use crate::miri::{run_with_io, Obj};
+    use crate::ski::compile;
+    use crate::unlambda::Interpreter;
+
+    /// Check that the program gives the same result when compiled to combinators
+    /// as when run by miri.
+    fn assert_agrees(program: &str) {
+        assert_agrees_with_input(program, b"")
+    }
+
+    /// Check that the program gives the same result and output when compiled to
+    /// combinators as when run by miri.
+    fn assert_agrees_with_input(program: &str, input: &[u8]) {
+        let mir = parse_mir(program).unwrap().desugar();
+        let mut expected_output = Vec::new();
+        let expected = run_with_io(&mir, &mut &*input, &mut expected_output).unwrap();
+        let combinators = compile(&mir).unwrap();
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(input, &mut output);
+        let value = interpreter.eval(&combinators).unwrap();
+        let actual = match expected {
+            Obj::Int(_) => interpreter.decode_int(value).unwrap().map(Obj::Int),
+            Obj::Bool(_) => interpreter.decode_bool(value).unwrap().map(Obj::Bool),
+            _ => panic!("can only compare integers and booleans"),
+        };
+        let actual = actual.unwrap_or_else(|| panic!("could not decode {:?}", expected));
+        assert_eq!(actual, expected, "{}", program);
+        assert_eq!(output, expected_output, "{}", program);
+    }
+
+    const OPERANDS: &[i64] = &[0, 1, -1, 6, -7, 1000, -123_456_789, i64::MAX];
+
+    #[test]
+    fn literals() {
+        for i in OPERANDS.iter().chain(&[i64::MIN]) {
+            assert_agrees(&i.to_string());
+        }
+        assert_agrees("#t");
+        assert_agrees("#f");
+    }
+
+    #[test]
+    fn booleans() {
+        for op in &["and", "or", "xor"] {
+            for a in &["#t", "#f"] {
+                for b in &["#t", "#f"] {
+                    assert_agrees(&format!("({} {} {})", op, a, b));
+                }
+                assert_agrees(&format!("(neg {})", a));
+                assert_agrees(&format!("(bool-to-int {})", a));
+            }
+        }
+        assert_agrees("(#:if (eq 1 2) 3 4)");
+    }
+
+    #[test]
+    fn pairs() {
+        assert_agrees("(car (cons 1 2))");
+        assert_agrees("(car (cdr (cons 1 (cons #t ()))))");
+        assert_agrees("(is-null ())");
+        assert_agrees("(is-null (cons 1 ()))");
+    }
+
+    #[test]
+    fn arithmetic() {
+        for &x in OPERANDS {
+            for &y in OPERANDS {
+                for op in &["eq", "lt", "le", "gt", "ge"] {
+                    assert_agrees(&format!("({} {} {})", op, x, y));
+                }
+                // including when they wrap around
+                assert_agrees(&format!("(plus {} {})", x, y));
+                assert_agrees(&format!("(minus {} {})", x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn multiplication() {
+        // these are slow, so only use a few operands
+        for &(x, y) in &[(0, 5), (6, -7), (-7, 6), (i64::MAX, -1)] {
+            assert_agrees(&format!("(times {} {})", x, y));
+        }
+        for &(x, y) in &[(7, 2), (-7, 2), (7, -2), (-7, -2), (0, 5), (i64::MAX, 1000)] {
+            assert_agrees(&format!("(div {} {})", x, y));
+            assert_agrees(&format!("(mod {} {})", x, y));
+        }
+        for &(x, y) in &[(7, 2), (-7, 2), (-7, -2), (i64::MIN, 3)] {
+            assert_agrees(&format!("(div-unsigned {} {})", x, y));
+            assert_agrees(&format!("(mod-unsigned {} {})", x, y));
+        }
+    }
+
+    #[test]
+    fn bitwise() {
+        for &(x, y) in &[
+            (0, 0),
+            (12, 10),
+            (-1, 1000),
+            (-123_456, 987),
+            (i64::MIN, i64::MAX),
+        ] {
+            for op in &["bit-and", "bit-or", "bit-xor"] {
+                assert_agrees(&format!("({} {} {})", op, x, y));
+            }
+            assert_agrees(&format!("(bit-not {})", x));
+        }
+        for &x in &[1, -8, i64::MIN, 0x1234_5678] {
+            for &n in &[0, 1, 5, 63, 64, -1] {
+                for op in &["shl", "shr", "shr-unsigned"] {
+                    assert_agrees(&format!("({} {} {})", op, x, n));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn narrowing() {
+        for &x in &[0, 1, 127, 128, 255, 256, -1, -128, -129, 70000, i64::MIN] {
+            for &mask in &[0xff_i64, 0xffff, 0xffff_ffff] {
+                assert_agrees(&format!("(truncate {} {})", mask, x));
+                assert_agrees(&format!("(sign-extend {} {})", mask, x));
+            }
+        }
+    }
+
+    #[test]
+    fn recursion() {
+        assert_agrees("((y (#:lambda (fac n) (#:if (le n 1) 1 (times n (fac (minus n 1)))))) 10)");
+    }
+
+    #[test]
+    fn io() {
+        for &c in &[0, 65, 255, -191, 1000] {
+            assert_agrees(&format!("(put-char {})", c));
+        }
+        assert_agrees("(plus (put-char 104) (put-char 105))");
+        for input in &[&b""[..], b"a", b"\0", b"\xff", b"xy"] {
+            assert_agrees_with_input("(get-char ())", input);
+            assert_agrees_with_input("(minus (get-char ()) (get-char ()))", input);
+        }
+        assert_agrees_with_input("(put-char (plus (get-char ()) 1))", b"m");
+    }
+}
@@ -12,43 +12,495 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::encoding::INT_BITS;
+use crate::memory::offset_address;
 use crate::mir::{MirExpr, MirLiteral, Primitive};
-use crate::Compiler;
-use saltwater_parser::hir::{Expr, ExprType};
+use crate::{lift, unsupported, Compiler};
+use saltwater_parser::data::lex::ComparisonToken;
+use saltwater_parser::hir::{BinaryOp, Expr, ExprType, Qualifiers};
 use saltwater_parser::{CompileResult, LiteralValue, Location, Type};
 
+/// A compiled expression.
+///
+/// If `pure` is set, `val` is the value of the expression itself. Otherwise, `val`
+/// is a stateful computation returning that value, and must be sequenced with
+/// `Then` before its result can be used.
 pub struct Value {
     pub val: MirExpr,
     pub ctype: Type,
     pub pure: bool,
 }
 
+/// Where an lvalue lives.
+#[derive(Debug, Clone)]
+pub enum Place {
+    /// A slot in the current stack frame, for locals whose address isn't taken
+    Stack(usize),
+    /// A pure expression giving an address in memory
+    Memory(MirExpr),
+}
+
 impl Compiler {
     pub fn compile_expr(&mut self, expr: Expr) -> CompileResult<Value> {
-        todo!()
-        /*
         let expr = expr.const_fold()?;
-        match expr.expr {
-            ExprType::Literal(token) => self.compile_literal(expr.ctype, token),
-            ExprType::Id(var) => {
-                let md = var.get();
-                Ok(Value {
-                    val: MirExpr::Primitive(Primitive::Get(*self.stack_positions.get(&md.id.into()).unwrap())),
-                    ctype: md.ctype.clone(),
+        // `lval` is only set on expressions that are themselves places,
+        // in which case the value of the expression is their address
+        if expr.lval && !expr.ctype.is_function() {
+            let ctype = Type::Pointer(Box::new(expr.ctype.clone()), Qualifiers::default());
+            return self.address(expr, ctype);
+        }
+        let Expr {
+            expr,
+            ctype,
+            lval,
+            location,
+        } = expr;
+        match expr {
+            ExprType::Literal(token) => self.compile_literal(ctype, token, location),
+            ExprType::Deref(inner) => {
+                self.with_place(*inner, |this, place| Ok(this.load(place, ctype)))
+            }
+            // functions are represented by their index in the function table
+            ExprType::Id(symbol) if symbol.get().ctype.is_function() => {
+                match self.functions.get(&symbol.get().id) {
+                    Some(func) => Ok(Value {
+                        val: int_literal(func.index as i64),
+                        ctype,
+                        pure: true,
+                    }),
+                    None => unsupported("pointers to external functions", location),
+                }
+            }
+            // `&x`, an array decaying to a pointer, or an aggregate,
+            // which is represented by its address
+            expr @ ExprType::Id(_) | expr @ ExprType::Member(_, _) => self.address(
+                Expr {
+                    expr,
+                    ctype: ctype.clone(),
+                    lval,
+                    location,
+                },
+                ctype,
+            ),
+            ExprType::Noop(inner) => {
+                let mut val = self.compile_expr(*inner)?;
+                val.ctype = ctype;
+                Ok(val)
+            }
+            ExprType::Cast(inner) => self.cast(*inner, ctype, location),
+            ExprType::Sizeof(inner_type) => match inner_type.sizeof() {
+                Ok(size) => Ok(Value {
+                    val: MirExpr::literal(MirLiteral::Int(size as i64)),
+                    ctype,
+                    pure: true,
+                }),
+                Err(err) => Err(location.with(err.to_string()).into()),
+            },
+            ExprType::Negate(inner) => {
+                let inner = self.compile_expr(*inner)?;
+                let wrapped = ctype.clone();
+                self.map(inner, ctype, |x| wrap(negate(x), &wrapped))
+            }
+            ExprType::BitwiseNot(inner) => {
+                let inner = self.compile_expr(*inner)?;
+                let wrapped = ctype.clone();
+                self.map(inner, ctype, |x| {
+                    wrap(
+                        MirExpr::apply(MirExpr::Primitive(Primitive::BitNot), x),
+                        &wrapped,
+                    )
+                })
+            }
+            ExprType::Binary(BinaryOp::Assign, target, value) => {
+                let value = self.compile_expr(*value)?;
+                self.with_place(*target, |this, place| {
+                    this.bind(value, |this, val| match place {
+                        // aggregates are represented by their address
+                        Place::Memory(dest) if !ctype.is_scalar() => this.copy(dest, val, &ctype),
+                        _ => {
+                            let value = Value {
+                                val,
+                                ctype,
+                                pure: true,
+                            };
+                            Ok(this.store(place, value))
+                        }
+                    })
+                })
+            }
+            ExprType::Binary(BinaryOp::LogicalAnd, left, right) => {
+                self.logical_expr(*left, *right, true)
+            }
+            ExprType::Binary(BinaryOp::LogicalOr, left, right) => {
+                self.logical_expr(*left, *right, false)
+            }
+            ExprType::Binary(op, left, right) => self.binary_op(op, *left, *right, ctype),
+            ExprType::PostIncrement(target, increase) => {
+                // the frontend doesn't scale the step for pointers
+                let step = match &target.ctype {
+                    Type::Pointer(pointee, _) => match pointee.sizeof() {
+                        Ok(size) => size as i64,
+                        Err(err) => return Err(location.with(err.to_string()).into()),
+                    },
+                    _ => 1,
+                };
+                self.with_place(*target, |this, place| {
+                    let previous = this.load(place.clone(), ctype.clone());
+                    this.bind(previous, |this, prev| {
+                        let op = if increase {
+                            Primitive::Plus
+                        } else {
+                            Primitive::Minus
+                        };
+                        let new = Value {
+                            val: wrap(int_op(op, prev.clone(), int_literal(step)), &ctype),
+                            ctype: ctype.clone(),
+                            pure: true,
+                        };
+                        let result = Value {
+                            val: prev,
+                            ctype,
+                            pure: true,
+                        };
+                        let store = this.store(place, new);
+                        Ok(this.seq(store, result))
+                    })
+                })
+            }
+            ExprType::Ternary(condition, then, otherwise) => {
+                let condition = self.compile_expr(*condition)?;
+                let (then, otherwise) = (self.compile_expr(*then)?, self.compile_expr(*otherwise)?);
+                self.bind(condition, |_, condition| {
+                    Ok(if_value(condition, then, otherwise))
+                })
+            }
+            ExprType::Comma(left, right) => {
+                let left = self.compile_discarded(*left)?;
+                let right = self.compile_expr(*right)?;
+                Ok(self.seq(left, right))
+            }
+            ExprType::FuncCall(func, args) => self.call(*func, args, ctype, location),
+            // only appears in static initializers
+            // the address of a function is its index, like the function itself
+            ExprType::StaticRef(inner) if is_function_designator(&inner) => {
+                self.compile_expr(*inner)
+            }
+            ExprType::StaticRef(inner) => self.address(*inner, ctype),
+        }
+    }
+
+    /// Compile an expression which is only evaluated for its side effects.
+    ///
+    /// Unlike `compile_expr`, this accepts lvalues, which the frontend doesn't convert
+    /// to rvalues when the result is unused.
+    pub fn compile_discarded(&mut self, expr: Expr) -> CompileResult<Value> {
+        // a function designator is an lvalue, but not an object
+        if !expr.lval || expr.ctype.is_function() {
+            return self.compile_expr(expr);
+        }
+        let ctype = expr.ctype.clone();
+        self.with_place(expr, |_, _| {
+            Ok(Value {
+                val: MirExpr::literal(MirLiteral::Null),
+                ctype,
+                pure: true,
+            })
+        })
+    }
+
+    /// Load a value of type `ctype` from `place`.
+    ///
+    /// Memory holds a byte at each address, so a scalar in memory is put back together
+    /// from its bytes, least significant first.
+    pub fn load(&mut self, place: Place, ctype: Type) -> Value {
+        let address = match place {
+            Place::Stack(slot) => {
+                return Value {
+                    val: MirExpr::Primitive(Primitive::Get(slot)),
+                    ctype,
                     pure: false,
+                }
+            }
+            Place::Memory(address) => address,
+        };
+        let size = scalar_size(&ctype);
+        let bytes: Vec<_> = (0..size).map(|_| self.gensym("byte")).collect();
+        let mut whole = MirExpr::Ref(bytes[0]);
+        for (i, &byte) in bytes.iter().enumerate().skip(1) {
+            let shifted = int_op(
+                Primitive::Shl,
+                MirExpr::Ref(byte),
+                int_literal(8 * i as i64),
+            );
+            whole = int_op(Primitive::BitOr, whole, shifted);
+        }
+        let whole = if ctype == Type::Bool {
+            MirExpr::apply(
+                MirExpr::Primitive(Primitive::Neg),
+                int_op(Primitive::Eq, whole, int_literal(0)),
+            )
+        } else if ctype.is_integral() && ctype.is_signed() {
+            wrap(whole, &ctype)
+        } else {
+            whole
+        };
+        let load_byte = |i: usize| {
+            let address = offset_address(address.clone(), i as u64);
+            MirExpr::apply(MirExpr::Primitive(Primitive::Load), address)
+        };
+        // `(then (load a) (lambda (b0) ... (lift (lambda (bn) whole) (load a+n))))`
+        let last = size - 1;
+        let mut val = match whole {
+            MirExpr::Ref(name) if name == bytes[last] => load_byte(last),
+            whole => MirExpr::apply(
+                MirExpr::apply(
+                    MirExpr::Primitive(Primitive::Lift),
+                    MirExpr::lambda(bytes[last], whole),
+                ),
+                load_byte(last),
+            ),
+        };
+        for i in (0..last).rev() {
+            val = MirExpr::apply(
+                MirExpr::apply(MirExpr::Primitive(Primitive::Then), load_byte(i)),
+                MirExpr::lambda(bytes[i], val),
+            );
+        }
+        Value {
+            val,
+            ctype,
+            pure: false,
+        }
+    }
+
+    /// Store a pure value, returning the value stored.
+    pub fn store(&mut self, place: Place, value: Value) -> Value {
+        debug_assert!(value.pure);
+        let address = match place {
+            Place::Stack(slot) => {
+                let write = Value {
+                    val: MirExpr::apply(
+                        MirExpr::Primitive(Primitive::Set(slot)),
+                        value.val.clone(),
+                    ),
+                    ctype: Type::Void,
+                    pure: false,
+                };
+                return self.seq(write, value);
+            }
+            Place::Memory(address) => address,
+        };
+        let int = if value.ctype == Type::Bool {
+            match &value.val {
+                MirExpr::Literal(literal) if **literal == MirLiteral::Bool(true) => int_literal(1),
+                MirExpr::Literal(literal) if **literal == MirLiteral::Bool(false) => int_literal(0),
+                val => bool_to_int(val.clone()),
+            }
+        } else {
+            value.val.clone()
+        };
+        let mut writes = Value {
+            val: MirExpr::literal(MirLiteral::Null),
+            ctype: Type::Void,
+            pure: true,
+        };
+        let size = scalar_size(&value.ctype);
+        // an unsigned char (or a `_Bool`) already fits in a byte
+        let bytes = if size == 1 && (value.ctype == Type::Bool || !value.ctype.is_signed()) {
+            vec![int]
+        } else {
+            bytes(int, size)
+        };
+        for (i, byte) in bytes.into_iter().enumerate() {
+            let address = offset_address(address.clone(), i as u64);
+            let write = Value {
+                val: MirExpr::apply(
+                    MirExpr::apply(MirExpr::Primitive(Primitive::Store), address),
+                    byte,
+                ),
+                ctype: Type::Void,
+                pure: false,
+            };
+            writes = self.seq(writes, write);
+        }
+        self.seq(writes, value)
+    }
+
+    /// Make the result of `value` available as a pure expression to `rest`.
+    ///
+    /// Pure values are bound with a `let`, unless they are atomic;
+    /// stateful values are sequenced with `Then`.
+    pub fn bind<F>(&mut self, value: Value, rest: F) -> CompileResult<Value>
+    where
+        F: FnOnce(&mut Self, MirExpr) -> CompileResult<Value>,
+    {
+        if value.pure {
+            if let MirExpr::Ref(_) | MirExpr::Literal(_) = value.val {
+                return rest(self, value.val);
+            }
+        }
+        let name = self.gensym("tmp");
+        let body = rest(self, MirExpr::Ref(name))?;
+        Ok(if value.pure {
+            Value {
+                val: MirExpr::let_(name, value.val, body.val),
+                ctype: body.ctype,
+                pure: body.pure,
+            }
+        } else {
+            let ctype = body.ctype.clone();
+            Value {
+                val: MirExpr::apply(
+                    MirExpr::apply(MirExpr::Primitive(Primitive::Then), value.val),
+                    MirExpr::lambda(name, lift(body).val),
+                ),
+                ctype,
+                pure: false,
+            }
+        })
+    }
+
+    /// Apply a pure function to the result of `value`.
+    pub(crate) fn map<F>(&mut self, value: Value, ctype: Type, func: F) -> CompileResult<Value>
+    where
+        F: FnOnce(MirExpr) -> MirExpr,
+    {
+        self.bind(value, |_, val| {
+            Ok(Value {
+                val: func(val),
+                ctype,
+                pure: true,
+            })
+        })
+    }
+
+    fn binary_op(
+        &mut self,
+        op: BinaryOp,
+        left: Expr,
+        right: Expr,
+        ctype: Type,
+    ) -> CompileResult<Value> {
+        // pointers are addresses, and the frontend has already scaled any offsets
+        let left_is_bool = left.ctype == Type::Bool;
+        // unsigned integers as wide as the representation compare as two's complement
+        // once their sign bits are flipped
+        let flip_sign = is_unsigned_long(&left.ctype);
+        let signed = ctype.is_signed();
+        let left = self.compile_expr(left)?;
+        let right = self.compile_expr(right)?;
+        let wrapped = ctype.clone();
+        let func: Box<dyn FnOnce(MirExpr, MirExpr) -> MirExpr> = match op {
+            BinaryOp::Add => Box::new(move |l, r| wrap(int_op(Primitive::Plus, l, r), &wrapped)),
+            BinaryOp::Sub => Box::new(move |l, r| wrap(int_op(Primitive::Minus, l, r), &wrapped)),
+            BinaryOp::Mul => Box::new(move |l, r| wrap(int_op(Primitive::Times, l, r), &wrapped)),
+            // `INT_MIN / -1` overflows
+            BinaryOp::Div if signed => {
+                Box::new(move |l, r| wrap(int_op(Primitive::Div, l, r), &wrapped))
+            }
+            BinaryOp::Mod if signed => Box::new(|l, r| int_op(Primitive::Mod, l, r)),
+            BinaryOp::Div => Box::new(|l, r| int_op(Primitive::DivUnsigned, l, r)),
+            BinaryOp::Mod => Box::new(|l, r| int_op(Primitive::ModUnsigned, l, r)),
+            BinaryOp::Compare(token) => Box::new(move |l, r| {
+                let (l, r) = if left_is_bool {
+                    (bool_to_int(l), bool_to_int(r))
+                } else if flip_sign {
+                    let flip = |x| int_op(Primitive::Plus, x, int_literal(i64::MIN));
+                    (flip(l), flip(r))
+                } else {
+                    (l, r)
+                };
+                compare(token, l, r)
+            }),
+            BinaryOp::BitwiseAnd if ctype == Type::Bool => {
+                Box::new(|l, r| int_op(Primitive::And, l, r))
+            }
+            BinaryOp::BitwiseOr if ctype == Type::Bool => {
+                Box::new(|l, r| int_op(Primitive::Or, l, r))
+            }
+            BinaryOp::Xor if ctype == Type::Bool => Box::new(|l, r| int_op(Primitive::Xor, l, r)),
+            // the bits outside the type are all copies of the sign bit or all zero for
+            // both operands, and these keep them that way
+            BinaryOp::BitwiseAnd => Box::new(|l, r| int_op(Primitive::BitAnd, l, r)),
+            BinaryOp::BitwiseOr => Box::new(|l, r| int_op(Primitive::BitOr, l, r)),
+            BinaryOp::Xor => Box::new(|l, r| int_op(Primitive::BitXor, l, r)),
+            // shifting by the width of the type or more is undefined
+            BinaryOp::Shl => Box::new(move |l, r| wrap(int_op(Primitive::Shl, l, r), &wrapped)),
+            BinaryOp::Shr if signed => Box::new(|l, r| int_op(Primitive::Shr, l, r)),
+            BinaryOp::Shr => Box::new(|l, r| int_op(Primitive::ShrUnsigned, l, r)),
+            BinaryOp::Assign | BinaryOp::LogicalAnd | BinaryOp::LogicalOr => {
+                unreachable!("should be handled earlier")
+            }
+        };
+        self.bind(left, |this, left| {
+            this.bind(right, |_, right| {
+                Ok(Value {
+                    val: func(left, right),
+                    ctype,
+                    pure: true,
                 })
+            })
+        })
+    }
+
+    /// `left && right` if `and` is set, otherwise `left || right`.
+    /// Only evaluates `right` if needed.
+    fn logical_expr(&mut self, left: Expr, right: Expr, and: bool) -> CompileResult<Value> {
+        let left = self.compile_expr(left)?;
+        let right = self.compile_expr(right)?;
+        let short_circuit = Value {
+            val: MirExpr::literal(MirLiteral::Bool(!and)),
+            ctype: Type::Bool,
+            pure: true,
+        };
+        self.bind(left, |_, left| {
+            Ok(if and {
+                if_value(left, right, short_circuit)
+            } else {
+                if_value(left, short_circuit, right)
+            })
+        })
+    }
+
+    fn cast(&mut self, expr: Expr, ctype: Type, location: Location) -> CompileResult<Value> {
+        if expr.ctype.is_floating() || ctype.is_floating() {
+            return unsupported("floating-point numbers", location);
+        }
+        let from_bool = expr.ctype == Type::Bool;
+        let narrowing = ctype.is_integral() && !ctype.can_represent(&expr.ctype);
+        let original = self.compile_expr(expr)?;
+        match (from_bool, &ctype) {
+            (true, Type::Bool) | (_, Type::Void) => Ok(Value { ctype, ..original }),
+            (true, _) => self.map(original, ctype, bool_to_int),
+            (false, Type::Bool) => self.map(original, ctype, |x| {
+                compare(ComparisonToken::NotEqual, x, int_literal(0))
+            }),
+            (false, _) if narrowing => {
+                let wrapped = ctype.clone();
+                self.map(original, ctype, |x| wrap(x, &wrapped))
             }
-            _ => todo!("expression type not yet supported: {:?}", expr.expr),
+            // all integers and pointers are represented the same way
+            (false, _) => Ok(Value { ctype, ..original }),
         }
-        */
     }
 
-    fn compile_literal(&mut self, ctype: Type, token: LiteralValue) -> CompileResult<Value> {
+    fn compile_literal(
+        &mut self,
+        ctype: Type,
+        token: LiteralValue,
+        location: Location,
+    ) -> CompileResult<Value> {
         let val = match (token, &ctype) {
             (LiteralValue::Int(i), Type::Bool) => MirExpr::literal(MirLiteral::Bool(i != 0)),
-            (LiteralValue::Int(i), _) => MirExpr::literal(MirLiteral::Int(i)),
-            (LiteralValue::Char(i), _) => MirExpr::literal(MirLiteral::Int(i64::from(i))),
-            _ => unimplemented!("only ints and bools are supported"),
+            (LiteralValue::UnsignedInt(u), Type::Bool) => {
+                MirExpr::literal(MirLiteral::Bool(u != 0))
+            }
+            // the frontend folds casts of constants without narrowing them
+            (LiteralValue::Int(i), _) => wrap(int_literal(i), &ctype),
+            (LiteralValue::UnsignedInt(u), _) => wrap(int_literal(u as i64), &ctype),
+            (LiteralValue::Char(c), _) => wrap(int_literal(i64::from(c)), &ctype),
+            (LiteralValue::Float(_), _) => return unsupported("floating-point numbers", location),
+            (LiteralValue::Str(s), _) => int_literal(self.string_literal(s, location)?),
         };
         Ok(Value {
             val,
@@ -57,3 +509,126 @@ impl Compiler {
         })
     }
 }
+
+/// `if condition then consequent else alternative`, where `condition` is pure.
+fn if_value(condition: MirExpr, consequent: Value, alternative: Value) -> Value {
+    let ctype = consequent.ctype.clone();
+    if consequent.pure && alternative.pure {
+        Value {
+            val: MirExpr::if_(condition, consequent.val, alternative.val),
+            ctype,
+            pure: true,
+        }
+    } else {
+        Value {
+            val: MirExpr::if_(condition, lift(consequent).val, lift(alternative).val),
+            ctype,
+            pure: false,
+        }
+    }
+}
+
+pub(crate) fn int_literal(i: i64) -> MirExpr {
+    MirExpr::literal(MirLiteral::Int(i))
+}
+
+fn int_op(op: Primitive, left: MirExpr, right: MirExpr) -> MirExpr {
+    MirExpr::apply(MirExpr::apply(MirExpr::Primitive(op), left), right)
+}
+
+/// Wrap the result of a 64-bit operation to the range of `ctype`, by sign-extending
+/// signed integers and truncating unsigned ones. Constants are wrapped right away.
+fn wrap(x: MirExpr, ctype: &Type) -> MirExpr {
+    if !ctype.is_integral() || *ctype == Type::Bool {
+        return x;
+    }
+    let bits = ctype.sizeof().expect("integers have a size") * 8;
+    if bits >= u64::from(INT_BITS) {
+        return x;
+    }
+    let mask = (1 << bits) - 1;
+    let signed = ctype.is_signed();
+    if let MirExpr::Literal(literal) = &x {
+        if let MirLiteral::Int(i) = **literal {
+            let sign = 1 << (bits - 1);
+            let wrapped = if signed && i & sign != 0 {
+                i | !mask
+            } else {
+                i & mask
+            };
+            return int_literal(wrapped);
+        }
+    }
+    let prim = if signed {
+        Primitive::SignExtend
+    } else {
+        Primitive::Truncate
+    };
+    int_op(prim, int_literal(mask), x)
+}
+
+/// The number of bytes a scalar of type `ctype` takes in memory.
+pub(crate) fn scalar_size(ctype: &Type) -> usize {
+    ctype.sizeof().expect("scalars should have a size") as usize
+}
+
+/// The `size` bytes of the integer `x`, least significant first. The bytes of
+/// constants are computed right away.
+pub(crate) fn bytes(x: MirExpr, size: usize) -> Vec<MirExpr> {
+    (0..size)
+        .map(|i| {
+            let shift = 8 * i as i64;
+            if let MirExpr::Literal(literal) = &x {
+                if let MirLiteral::Int(n) = **literal {
+                    return int_literal((n >> shift) & 0xff);
+                }
+            }
+            let shifted = if i == 0 {
+                x.clone()
+            } else {
+                int_op(Primitive::ShrUnsigned, x.clone(), int_literal(shift))
+            };
+            int_op(Primitive::Truncate, int_literal(0xff), shifted)
+        })
+        .collect()
+}
+
+/// Whether `ctype` is an unsigned integer with as many bits as the representation.
+fn is_unsigned_long(ctype: &Type) -> bool {
+    ctype.is_integral()
+        && !ctype.is_signed()
+        && *ctype != Type::Bool
+        && ctype.sizeof() == Ok(u64::from(INT_BITS) / 8)
+}
+
+fn negate(x: MirExpr) -> MirExpr {
+    int_op(Primitive::Minus, int_literal(0), x)
+}
+
+fn bool_to_int(x: MirExpr) -> MirExpr {
+    MirExpr::apply(MirExpr::Primitive(Primitive::BoolToInt), x)
+}
+
+fn compare(token: ComparisonToken, left: MirExpr, right: MirExpr) -> MirExpr {
+    let prim = match token {
+        ComparisonToken::Less => Primitive::Lt,
+        ComparisonToken::LessEqual => Primitive::Le,
+        ComparisonToken::Greater => Primitive::Gt,
+        ComparisonToken::GreaterEqual => Primitive::Ge,
+        ComparisonToken::EqualEqual | ComparisonToken::NotEqual => Primitive::Eq,
+    };
+    let cmp = int_op(prim, left, right);
+    if token == ComparisonToken::NotEqual {
+        MirExpr::apply(MirExpr::Primitive(Primitive::Neg), cmp)
+    } else {
+        cmp
+    }
+}
+
+/// Whether `expr` names a function, possibly after decaying to a pointer.
+fn is_function_designator(expr: &Expr) -> bool {
+    match &expr.expr {
+        ExprType::Id(symbol) => symbol.get().ctype.is_function(),
+        _ => false,
+    }
+}
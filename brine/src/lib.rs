@@ -1,46 +1,90 @@
 #[macro_use]
 extern crate lazy_static;
 
-mod ast;
+pub mod ast;
+mod call;
 mod cfg;
+pub mod check;
+pub mod encoding;
 mod expr;
+mod memory;
 pub mod mir;
 pub mod miri;
+pub mod opt;
+pub mod ski;
 mod stmt;
+pub mod unlambda;
 
-use crate::ast::SyntaxNode;
-use crate::cfg::{BlockId, Cfg};
-use crate::expr::Value;
-use crate::mir::{Lambda, MirExpr, MirInternedStr, Primitive};
-use saltwater_parser::get_str;
-use saltwater_parser::hir::{Declaration, Initializer, Stmt, Symbol};
+use crate::cfg::{switch, BlockId, Cfg, Jump};
+use crate::expr::{Place, Value};
+use crate::memory::{
+    addressed_variables, align_to, complete_type, is_complete, needs_memory, offset_address,
+    STATIC_BASE,
+};
+use crate::mir::{run_stateful, Lambda, MirExpr, MirInternedStr, MirLiteral, Primitive};
+use crate::stmt::SwitchCases;
+use saltwater_parser::data::Error;
+use saltwater_parser::hir::{Declaration, ExprType, Initializer, Stmt, Symbol};
 use saltwater_parser::types::FunctionType;
 use saltwater_parser::{
-    CompileError, CompileResult, ErrorHandler, InternedStr, Locatable, Location, Opt, Program,
+    CompileError, CompileResult, Files, InternedStr, Location, Opt, Program, SemanticError,
     StorageClass, Type,
 };
-use std::collections::HashMap;
-use std::convert::TryFrom;
+use std::collections::{HashMap, HashSet};
 
 /// Compile and return the declarations and warnings.
 pub fn compile(buf: &str, opt: Opt) -> Program<MirExpr> {
+    compile_with_source_map(buf, opt).0
+}
+
+/// Where the code in each location comment of the MIR brine generates comes from.
+///
+/// brine wraps the code for each statement, and for the conditions of control flow
+/// statements, in a comment like `at file.c:3:5`.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    locations: HashMap<String, Location>,
+}
+
+impl SourceMap {
+    /// The location of the innermost location comment in `context`, which lists
+    /// comments outermost first.
+    pub fn locate(&self, context: &[String]) -> Option<Location> {
+        context
+            .iter()
+            .rev()
+            .find_map(|comment| self.locations.get(comment).copied())
+    }
+}
+
+/// Compile and return the declarations and warnings, and the locations of the
+/// location comments in the generated MIR.
+pub fn compile_with_source_map(buf: &str, opt: Opt) -> (Program<MirExpr>, SourceMap) {
     use saltwater_parser::{check_semantics, vec_deque};
 
     let program = check_semantics(buf, opt);
     let hir = match program.result {
         Ok(hir) => hir,
         Err(err) => {
-            return Program {
+            let program = Program {
                 result: Err(err),
                 warnings: program.warnings,
                 files: program.files,
-            }
+            };
+            return (program, SourceMap::default());
         }
     };
     // really we'd like to have all errors but that requires a refactor
     let mut err = None;
     let mut compiler = Compiler::new();
-    let mut func_code = HashMap::<InternedStr, MirExpr>::new();
+    compiler.files = program.files;
+    // number the functions up front, so they can call functions defined after them
+    for decl in &hir {
+        if let Some(Initializer::FunctionBody(_)) = decl.data.init {
+            compiler.declare_function(decl.data.symbol);
+        }
+    }
+    let mut functions = Vec::new();
     for decl in hir {
         let meta = decl.data.symbol.get();
         if let StorageClass::Typedef = meta.storage_class {
@@ -48,16 +92,9 @@ pub fn compile(buf: &str, opt: Opt) -> Program<MirExpr> {
         }
         let current = match &meta.ctype {
             Type::Function(func_type) => match decl.data.init {
-                Some(Initializer::FunctionBody(stmts)) => {
-                    match compiler.compile_func(decl.data.symbol, &func_type, stmts, decl.location)
-                    {
-                        Ok(expr) => {
-                            func_code.insert(decl.data.symbol.get().id, compiler.cfg.to_mir());
-                            Ok(())
-                        }
-                        Err(e) => Err(e),
-                    }
-                }
+                Some(Initializer::FunctionBody(stmts)) => compiler
+                    .compile_func(decl.data.symbol, func_type, stmts, decl.location)
+                    .map(|func| functions.push(func)),
                 None => Ok(()),
                 _ => unreachable!("functions can only be initialized by a FunctionBody"),
             },
@@ -66,7 +103,7 @@ pub fn compile(buf: &str, opt: Opt) -> Program<MirExpr> {
                 if let Some(Initializer::FunctionBody(_)) = &decl.data.init {
                     unreachable!("only functions should have a function body")
                 }
-                todo!("Store static")
+                compiler.declare_global(decl.data, decl.location)
             }
         };
         if let Err(e) = current {
@@ -74,38 +111,80 @@ pub fn compile(buf: &str, opt: Opt) -> Program<MirExpr> {
             break;
         }
     }
-    let result = if let Some(err) = err {
-        Err(err)
-    } else {
-        Ok(func_code
-            .remove(&InternedStr::get_or_intern("main"))
-            .unwrap())
+    let result = match err {
+        Some(err) => Err(err),
+        None => compiler.link(functions),
     };
-    Program {
+    let program = Program {
         result: result.map_err(|errs| vec_deque![errs]),
         warnings: program.warnings,
-        files: program.files,
-    }
+        files: std::mem::take(&mut compiler.files),
+    };
+    (program, compiler.source_map)
 }
 
 #[derive(Default)]
 struct Compiler {
-    pub locals: Vec<Vec<InternedStr>>,
     pub gensym_counter: usize,
     pub cfg: Cfg,
-    pub current_block: BlockId,
     pub return_block: BlockId,
-    pub stack_positions: HashMap<MirInternedStr, usize>,
+    pub stack_positions: HashMap<Symbol, usize>,
+    /// Locals whose address is taken in the current function
+    pub addressed: HashSet<Symbol>,
+    /// The offset in the current stack frame of each local that lives in memory
+    pub frame_offsets: HashMap<Symbol, u64>,
+    /// The size of the current stack frame in memory
+    pub frame_size: u64,
+    /// Pointer variables which are known to point to a given place
+    pub aliases: HashMap<Symbol, Place>,
     pub next_stack_slot: usize,
+    /// Where `break` jumps to, for each enclosing loop or switch
+    pub break_targets: Vec<BlockId>,
+    /// Where `continue` jumps to, for each enclosing loop
+    pub continue_targets: Vec<BlockId>,
+    pub switches: Vec<SwitchCases>,
+    pub labels: HashMap<InternedStr, BlockId>,
+    /// Labels which have been jumped to, but not yet declared
+    pub pending_labels: HashMap<InternedStr, Location>,
+    /// Every function defined in the program, by name
+    pub functions: HashMap<InternedStr, Function>,
+    /// The address of each variable declared at file scope, by name
+    pub globals: HashMap<InternedStr, i64>,
+    /// The address of each `static` local variable
+    pub static_locals: HashMap<Symbol, i64>,
+    /// The size of static storage, from address 0
+    pub static_size: u64,
+    /// The initial contents of static storage, as `(address, value)` pairs.
+    /// Any other address starts out as zero.
+    pub static_memory: Vec<(i64, MirExpr)>,
+    /// The source files of the program, to describe locations in comments
+    pub files: Files,
+    pub source_map: SourceMap,
+}
+
+/// A function definition, as seen by its callers.
+#[derive(Debug, Clone, Copy)]
+pub struct Function {
+    /// The position of the function in the function table
+    pub index: usize,
+    /// How many arguments the function takes
+    pub arity: usize,
 }
 
 lazy_static! {
     pub static ref RESULT_NAME: MirInternedStr = MirInternedStr::get_or_intern("_res");
+    pub static ref FUNCTIONS_NAME: MirInternedStr = MirInternedStr::get_or_intern("_functions");
+    static ref INDEX_NAME: MirInternedStr = MirInternedStr::get_or_intern("_index");
+    pub static ref FRAME_POINTER: MirInternedStr = MirInternedStr::get_or_intern("_fp");
+    pub static ref STACK_POINTER: MirInternedStr = MirInternedStr::get_or_intern("_sp");
 }
 
 impl Compiler {
     pub fn new() -> Compiler {
-        Compiler::default()
+        Compiler {
+            static_size: STATIC_BASE,
+            ..Compiler::default()
+        }
     }
 
     pub fn gensym(&mut self, prefix: &str) -> MirInternedStr {
@@ -114,47 +193,392 @@ impl Compiler {
         MirInternedStr::get_or_intern(format!("__{}_{}", prefix, i))
     }
 
+    fn declare_function(&mut self, symbol: Symbol) {
+        let meta = symbol.get();
+        let arity = match &meta.ctype {
+            Type::Function(func_type) if func_type.has_params() => func_type.params.len(),
+            Type::Function(_) => 0,
+            _ => unreachable!("only functions should have a function body"),
+        };
+        let index = self.functions.len();
+        self.functions.insert(meta.id, Function { index, arity });
+    }
+
+    /// Compile a function definition to a curried function of its frame pointer and
+    /// arguments, which returns a stateful computation running the body in a new
+    /// stack frame:
+    ///
+    /// ```text
+    /// (lambda (_fp arg_0 ... arg_n) (let (_sp (plus _fp frame_size)) (frame slots body)))
+    /// ```
+    ///
+    /// Functions without parameters take a single, ignored argument instead. The
+    /// result is wrapped in a comment with the function's name, so errors in it can
+    /// be traced back to it.
     fn compile_func(
         &mut self,
         symbol: Symbol,
         func_type: &FunctionType,
         stmts: Vec<Stmt>,
         location: Location,
-    ) -> CompileResult<()> {
+    ) -> CompileResult<MirExpr> {
         self.cfg = Cfg::default();
-        self.current_block = self.cfg.add_block();
+        self.stack_positions.clear();
+        self.aliases.clear();
+        self.next_stack_slot = 0;
+        self.addressed = addressed_variables(&stmts);
+        self.frame_offsets.clear();
+        self.frame_size = 0;
+        self.labels.clear();
+        self.pending_labels.clear();
         self.return_block = self.cfg.add_block();
         self.cfg.set_return_block(self.return_block);
-        self.compile_all(todo!(), stmts).map(|_| ())
+        if func_type.varargs {
+            return unsupported("variadic functions", location);
+        }
+        if !func_type.return_type.is_scalar() && *func_type.return_type != Type::Void {
+            return unsupported("functions returning structs and unions", location);
+        }
+        let mut args = Vec::new();
+        let mut stores = Vec::new();
+        if func_type.has_params() {
+            for &param in &func_type.params {
+                let ctype = param.get().ctype.clone();
+                let arg = self.gensym("arg");
+                let place = self.declare_local(param, &ctype, location)?;
+                // aggregates are passed by address, and copied by the callee
+                let store = match place {
+                    Place::Memory(address) if !ctype.is_scalar() => {
+                        self.copy(address, MirExpr::Ref(arg), &ctype)?
+                    }
+                    _ => {
+                        let value = Value {
+                            val: MirExpr::Ref(arg),
+                            ctype,
+                            pure: true,
+                        };
+                        self.store(place, value)
+                    }
+                };
+                stores.push(store);
+                args.push(arg);
+            }
+        }
+        if args.is_empty() {
+            args.push(self.gensym("unused"));
+        }
+        let start = stores
+            .into_iter()
+            .rev()
+            .fold(self.block_start(), |rest, store| self.seq(store, rest));
+        let end = self.compile_all(start, stmts)?;
+        // falling off the end of `main` is the same as `return 0;`
+        let retval = if symbol.get().id == InternedStr::get_or_intern("main") {
+            MirLiteral::Int(0)
+        } else {
+            MirLiteral::Null
+        };
+        let retval = Value {
+            val: MirExpr::literal(retval),
+            ctype: (*func_type.return_type).clone(),
+            pure: true,
+        };
+        self.end_block(self.seq(end, retval), Jump::Jmp(self.return_block));
+        if let Some((&name, &location)) = self.pending_labels.iter().next() {
+            return Err(location.error(SemanticError::UndeclaredLabel(name)));
+        }
+        let mut body = self.cfg.to_mir();
+        if self.next_stack_slot > 0 {
+            body = MirExpr::apply(
+                MirExpr::Primitive(Primitive::Frame(self.next_stack_slot)),
+                body,
+            );
+        }
+        let body = MirExpr::let_(
+            *STACK_POINTER,
+            offset_address(MirExpr::Ref(*FRAME_POINTER), self.frame_size),
+            body,
+        );
+        let func = std::iter::once(*FRAME_POINTER)
+            .chain(args)
+            .rev()
+            .fold(body, |body, arg| MirExpr::lambda(arg, body));
+        Ok(MirExpr::Comment(
+            format!("function {}", symbol.get().id),
+            Box::new(func),
+        ))
     }
 
-    fn declare_stack(&mut self, decl: Declaration, location: Location) -> CompileResult<()> {
-        let meta = decl.symbol.get();
-        if let StorageClass::Typedef = meta.storage_class {
-            return Ok(());
+    /// Put the compiled functions in a table, and run `main`.
+    ///
+    /// The table is a recursive function from indices to functions, so that
+    /// functions can call each other (and themselves) through it:
+    ///
+    /// ```text
+    /// (let (_functions (y (lambda (_functions _index) (switch _index f_0 ... f_n))))
+    ///   (run (((_functions main) stack_start) null) static_memory))
+    /// ```
+    ///
+    /// `main` is called with zero for any parameters it has, and its stack frame
+    /// starts just after static storage.
+    fn link(&self, functions: Vec<MirExpr>) -> CompileResult<MirExpr> {
+        let main = match self.functions.get(&InternedStr::get_or_intern("main")) {
+            Some(&main) => main,
+            None => {
+                return Err(Location::default()
+                    .with("brine programs must define a main function".to_string())
+                    .into())
+            }
+        };
+        let table = MirExpr::lambda(
+            *FUNCTIONS_NAME,
+            MirExpr::lambda(*INDEX_NAME, switch(MirExpr::Ref(*INDEX_NAME), functions)),
+        );
+        let arg = if main.arity == 0 {
+            MirExpr::literal(MirLiteral::Null)
+        } else {
+            MirExpr::literal(MirLiteral::Int(0))
+        };
+        let stack_start = align_to(self.static_size, 8) as i64;
+        let call_main = MirExpr::apply(
+            function_ref(main.index),
+            MirExpr::literal(MirLiteral::Int(stack_start)),
+        );
+        let call_main =
+            (0..main.arity.max(1)).fold(call_main, |func, _| MirExpr::apply(func, arg.clone()));
+        Ok(MirExpr::let_(
+            *FUNCTIONS_NAME,
+            MirExpr::apply(MirExpr::Primitive(Primitive::Y), table),
+            run_stateful(call_main, self.static_memory.clone()),
+        ))
+    }
+
+    /// The value a basic block starts with: the result of the previous block.
+    fn block_start(&self) -> Value {
+        Value {
+            val: MirExpr::Ref(*RESULT_NAME),
+            ctype: Type::Void,
+            pure: true,
+        }
+    }
+
+    /// Make `value` the instruction of the current block, and end it with `jump`.
+    fn end_block(&mut self, value: Value, jump: Jump) {
+        self.cfg.add_instr(create_res_lambda(lift(value).val));
+        self.cfg.set_jump(jump);
+    }
+
+    /// Wrap `value` in a comment with its location, so that errors while running it
+    /// can be traced back to the source.
+    fn locate(&mut self, value: Value, location: Location) -> Value {
+        // looking up a variable or a literal can't go wrong
+        if let MirExpr::Ref(_) | MirExpr::Literal(_) = value.val {
+            return value;
         }
-        if let Type::Function(_) = &meta.ctype {
-            todo!("function declaration")
+        let start = match self.files.location(location.file, location.span.start) {
+            Ok(start) => start,
+            Err(_) => return value,
+        };
+        let comment = format!(
+            "at {}:{}:{}",
+            self.files.name(location.file).to_string_lossy(),
+            start.line.number(),
+            start.column.number()
+        );
+        self.source_map.locations.insert(comment.clone(), location);
+        Value {
+            val: MirExpr::Comment(comment, Box::new(value.val)),
+            ..value
         }
-        self.declare_stack_slot(meta.id.into());
-        if let Some(init) = decl.init {
-            todo!("stack slot initializer")
+    }
+
+    /// Run `first` for its side effects only, then `second`.
+    fn seq(&self, first: Value, second: Value) -> Value {
+        if first.pure {
+            return second;
+        }
+        let ignored = MirInternedStr::get_or_intern("_");
+        let ctype = second.ctype.clone();
+        Value {
+            val: MirExpr::apply(
+                MirExpr::apply(MirExpr::Primitive(Primitive::Then), first.val),
+                MirExpr::lambda(ignored, lift(second).val),
+            ),
+            ctype,
+            pure: false,
         }
-        Ok(())
     }
 
-    fn declare_stack_slot(&mut self, identifier: MirInternedStr) -> usize {
+    fn declare_stack(
+        &mut self,
+        prev: Value,
+        decl: Declaration,
+        location: Location,
+    ) -> CompileResult<Value> {
+        let meta = decl.symbol.get();
+        let ctype = complete_type(&meta.ctype, decl.init.as_ref());
+        match meta.storage_class {
+            StorageClass::Typedef => return Ok(prev),
+            // calls and global variables are looked up by name
+            _ if meta.ctype.is_function() => return Ok(prev),
+            // `extern struct s x;` is allocated once `struct s` is complete
+            StorageClass::Extern => {
+                if is_complete(&meta.ctype) {
+                    self.global_address(meta.id, &meta.ctype, location)?;
+                }
+                return Ok(prev);
+            }
+            StorageClass::Static => {
+                let address = self.allocate_static(&ctype, location)?;
+                self.static_locals.insert(decl.symbol, address);
+                self.initialize_static(address as u64, &ctype, decl.init, location)?;
+                return Ok(prev);
+            }
+            StorageClass::Auto | StorageClass::Register => {}
+        }
+        let init = match decl.init {
+            // the frontend declares a pointer to an lvalue to implement compound assignment;
+            // since a variable in a stack slot can't move, remember its place instead
+            Some(Initializer::Scalar(init)) if init.lval => match init.expr {
+                ExprType::Id(symbol) if self.stack_positions.contains_key(&symbol) => {
+                    let place = self.variable_place(symbol, location)?;
+                    self.aliases.insert(decl.symbol, place);
+                    return Ok(prev);
+                }
+                _ => Some(Initializer::Scalar(init)),
+            },
+            init => init,
+        };
+        let place = self.declare_local(decl.symbol, &ctype, location)?;
+        let init = match (init, place) {
+            (None, _) => return Ok(prev),
+            (Some(Initializer::Scalar(init)), Place::Stack(slot)) => {
+                let init = self.compile_expr(*init)?;
+                self.bind(init, |this, val| {
+                    let value = Value {
+                        val,
+                        ctype: ctype.clone(),
+                        pure: true,
+                    };
+                    Ok(this.store(Place::Stack(slot), value))
+                })?
+            }
+            (Some(Initializer::InitializerList(_)), Place::Stack(_)) => {
+                return unsupported("initializer lists", location)
+            }
+            (Some(init), Place::Memory(address)) => {
+                self.initialize_local(address, &ctype, init, location)?
+            }
+            (Some(Initializer::FunctionBody(_)), _) => {
+                unreachable!("only functions should have a function body")
+            }
+        };
+        let init = self.locate(init, location);
+        Ok(self.seq(prev, init))
+    }
+
+    /// Allocate a local variable, in a stack slot if possible or in memory otherwise.
+    fn declare_local(
+        &mut self,
+        symbol: Symbol,
+        ctype: &Type,
+        location: Location,
+    ) -> CompileResult<Place> {
+        if needs_memory(symbol, &self.addressed) {
+            let offset = self.allocate_local(ctype, location)?;
+            self.frame_offsets.insert(symbol, offset);
+            self.variable_place(symbol, location)
+        } else {
+            Ok(Place::Stack(self.declare_stack_slot(symbol)))
+        }
+    }
+
+    fn declare_stack_slot(&mut self, symbol: Symbol) -> usize {
         let slot = self.next_stack_slot;
         self.next_stack_slot += 1;
-        self.stack_positions.insert(identifier, slot);
-        return slot;
+        self.stack_positions.insert(symbol, slot);
+        slot
     }
 
-    fn get_stack(&self, identifier: MirInternedStr) -> CompileResult<usize> {
-        todo!()
+    /// Declare a variable with static storage at file scope.
+    fn declare_global(&mut self, decl: Declaration, location: Location) -> CompileResult<()> {
+        let meta = decl.symbol.get();
+        let ctype = complete_type(&meta.ctype, decl.init.as_ref());
+        // `extern int a[];` and `extern struct s x;` don't have a size until they're
+        // defined
+        if decl.init.is_none() && !is_complete(&ctype) {
+            return Ok(());
+        }
+        let address = self.global_address(meta.id, &ctype, location)?;
+        if decl.init.is_some() {
+            self.initialize_static(address as u64, &ctype, decl.init, location)?;
+        }
+        Ok(())
+    }
+
+    /// The address of the global variable `name`, allocating it if it hasn't been
+    /// declared yet.
+    fn global_address(
+        &mut self,
+        name: InternedStr,
+        ctype: &Type,
+        location: Location,
+    ) -> CompileResult<i64> {
+        if let Some(&address) = self.globals.get(&name) {
+            return Ok(address);
+        }
+        let address = self.allocate_static(ctype, location)?;
+        self.globals.insert(name, address);
+        Ok(address)
+    }
+
+    /// The place where a variable is stored.
+    fn variable_place(&self, symbol: Symbol, location: Location) -> CompileResult<Place> {
+        let address = if let Some(&slot) = self.stack_positions.get(&symbol) {
+            return Ok(Place::Stack(slot));
+        } else if let Some(&offset) = self.frame_offsets.get(&symbol) {
+            let address = offset_address(MirExpr::Ref(*FRAME_POINTER), offset);
+            return Ok(Place::Memory(address));
+        } else if let Some(&address) = self.static_locals.get(&symbol) {
+            address
+        } else {
+            match self.globals.get(&symbol.get().id) {
+                Some(&address) => address,
+                // only declarations of incomplete types aren't allocated right away
+                None => {
+                    return unsupported(
+                        "objects of incomplete type used before their definition",
+                        location,
+                    )
+                }
+            }
+        };
+        Ok(Place::Memory(MirExpr::literal(MirLiteral::Int(address))))
     }
 }
 
+/// Return an error for a C feature brine doesn't know how to compile yet.
+pub fn unsupported<T>(feature: &str, location: Location) -> CompileResult<T> {
+    Err(location.error(SemanticError::Unsupported {
+        feature: feature.to_string(),
+        backend: "brine",
+    }))
+}
+
+/// Whether `err` is about a feature brine doesn't support yet, rather than a problem
+/// with the program.
+pub fn is_unsupported(err: &CompileError) -> bool {
+    matches!(err.data, Error::Semantic(SemanticError::Unsupported { .. }))
+}
+
+/// The function at `index` in the function table.
+pub fn function_ref(index: usize) -> MirExpr {
+    MirExpr::apply(
+        MirExpr::Ref(*FUNCTIONS_NAME),
+        MirExpr::literal(MirLiteral::Int(index as i64)),
+    )
+}
+
 pub fn create_res_lambda(e: MirExpr) -> Lambda {
     Lambda {
         arg: *RESULT_NAME,
@@ -163,9 +587,523 @@ pub fn create_res_lambda(e: MirExpr) -> Lambda {
 }
 
 pub fn lift(v: Value) -> Value {
+    if !v.pure {
+        return v;
+    }
     Value {
         val: MirExpr::apply(MirExpr::Primitive(Primitive::Pure), v.val),
         ctype: v.ctype,
         pure: false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{compile, compile_with_source_map, is_unsupported};
+    use crate::check::check;
+    use crate::mir::MirExpr;
+    use crate::miri::{run_with_io, MiriError, Obj};
+    use crate::opt::{optimize, Passes};
+    use crate::ski;
+    use crate::unlambda::Interpreter;
+    use saltwater_parser::Opt;
+
+    /// Check the types of `main`, then run it in miri, both before and after optimizing,
+    /// and once compiled to combinators, check they all agree, and return its return
+    /// value and output.
+    fn run_both(program: &str, input: &[u8]) -> (i64, String) {
+        let mir = compile(program, Opt::default())
+            .result
+            .unwrap_or_else(|err| panic!("failed to compile {}: {:?}", program, err));
+        if let Err(errors) = check(&mir) {
+            panic!("{} does not check: {:?}", program, errors);
+        }
+        let mir = mir.desugar();
+        if let Err(errors) = check(&mir) {
+            panic!("desugared {} does not check: {:?}", program, errors);
+        }
+        let mut miri_output = Vec::new();
+        let returned = match run_with_io(&mir, &mut &*input, &mut miri_output) {
+            Ok(Obj::Int(i)) => i,
+            other => panic!("{} returned {:?}", program, other),
+        };
+
+        let mir = optimize(mir, Passes::default());
+        let mut optimized_output = Vec::new();
+        match run_with_io(&mir, &mut &*input, &mut optimized_output) {
+            Ok(Obj::Int(i)) => assert_eq!(i, returned, "optimized {}", program),
+            other => panic!("optimized {} returned {:?}", program, other),
+        };
+        assert_eq!(optimized_output, miri_output, "optimized {}", program);
+
+        let combinators = ski::compile(&mir).unwrap();
+        let mut output = Vec::new();
+        let mut interpreter = Interpreter::new(input, &mut output);
+        let value = interpreter.eval(&combinators).unwrap();
+        let ski_returned = interpreter.decode_int(value).unwrap();
+        assert_eq!(ski_returned, Some(returned), "{}", program);
+        assert_eq!(output, miri_output, "{}", program);
+        (returned, String::from_utf8(output).unwrap())
+    }
+
+    fn assert_returns(program: &str, expected: i64) {
+        assert_eq!(run_both(program, b"").0, expected, "{}", program);
+    }
+
+    fn assert_output(program: &str, input: &str, expected: &str) {
+        assert_eq!(
+            run_both(program, input.as_bytes()).1,
+            expected,
+            "{}",
+            program
+        );
+    }
+
+    #[test]
+    fn optimize_program() {
+        let program = "int fib(int n) { return n < 2 ? n : fib(n - 1) + fib(n - 2); }
+            int main() { int a[2]; int i; for (i = 0; i < 2; i++) a[i] = fib(i + 2); return a[1]; }";
+        let mir = compile(program, Opt::default()).result.unwrap().desugar();
+        let size = |mir: &MirExpr| ski::compile(mir).unwrap().output(80).len();
+        let original = size(&mir);
+        let optimized = size(&optimize(mir, Passes::default()));
+        assert!(
+            optimized < original * 9 / 10,
+            "{} vs {}",
+            optimized,
+            original
+        );
+    }
+
+    #[test]
+    fn compile_return() {
+        assert!(compile("int main() { return 0; }", Opt::default())
+            .result
+            .is_ok());
+    }
+
+    #[test]
+    fn compile_expressions() {
+        let program = "int main() {
+            int x = 1, y = 2;
+            _Bool b = x < y && !(y == 3);
+            x += y * 3 - -x / 2;
+            y = x++ + --y;
+            x = b ? ~x : (long)y % 4;
+            return sizeof(x) + (x, y);
+        }";
+        let r = compile(program, Opt::default()).result;
+        assert!(r.is_ok(), "{:?}", r.err());
+    }
+
+    #[test]
+    fn unsupported_is_error() {
+        let program = "int main() { double d = 1.5; return 0; }";
+        let errors = compile(program, Opt::default()).result.unwrap_err();
+        assert!(is_unsupported(errors.front().unwrap()));
+        let program = "int main() { return x; }";
+        let errors = compile(program, Opt::default()).result.unwrap_err();
+        assert!(!is_unsupported(errors.front().unwrap()));
+    }
+
+    #[test]
+    fn compile_control_flow() {
+        let program = "int main() {
+            int i, total = 0;
+            for (i = 0; i < 10; i++) {
+                if (i == 3) continue;
+                else if (i > 7) break;
+                total += i;
+            }
+            while (total) total--;
+            do { total++; } while (total < 5);
+            switch (total) {
+                case 1: total = 2;
+                case -5: break;
+                default: goto end;
+            }
+            for (;;) { break; }
+        end:
+            return total;
+        }";
+        let r = compile(program, Opt::default()).result;
+        assert!(r.is_ok(), "{:?}", r.err());
+    }
+
+    #[test]
+    fn invalid_control_flow() {
+        for program in &[
+            "int main() { goto missing; }",
+            "int main() { a: a: return 0; }",
+            "int main() { break; }",
+            "int main() { switch (1) { continue; } }",
+            "int main() { case 1: return 0; }",
+            "int main() { switch (1) { case 1: case 1: return 0; } }",
+            "int f(int x) { return x; }",
+            "int f(); int main() { return f(1); } int f() { return 0; }",
+        ] {
+            assert!(
+                compile(program, Opt::default()).result.is_err(),
+                "{}",
+                program
+            );
+        }
+    }
+
+    #[test]
+    fn run_locals() {
+        assert_returns(
+            "int main() { int x = 1, y = 2; y = x; x = 5; return x + y; }",
+            6,
+        );
+        assert_returns(
+            "int main() { int x = 3; x *= x + 1; x -= 2; return x; }",
+            10,
+        );
+        assert_returns(
+            "int main() { int i = 0; int j = i++ + ++i; return i * 10 + j; }",
+            22,
+        );
+        assert_returns("int main() { int x; x = 4; { int x = 7; } return x; }", 4);
+        assert_returns(
+            "int main() { int x = 5; _Bool b = x > 3 && x != 4; return b ? ~x : -x; }",
+            -6,
+        );
+    }
+
+    #[test]
+    fn run_integer_wrapping() {
+        // each condition sets a bit of the result, as with the Cranelift backend
+        assert_returns(
+            "int main() {
+                unsigned char a = 255, b = 1, c = a + b;
+                signed char d = 127; d++;
+                short s = (short)70000;
+                int big = 2147483647; big = big + 1;
+                unsigned u = 0; u--;
+                unsigned long ul = -1;
+                return (c == 0) + (d == -128) * 2 + (s == 4464) * 4
+                    + (big == -2147483647 - 1) * 8 + (u == 4294967295u) * 16 + (ul > 1) * 32
+                    + (ul / 2 == 9223372036854775807ul) * 64 + ((char)300 == 44) * 128;
+            }",
+            255,
+        );
+        assert_returns(
+            "int main() {
+                unsigned u = 3000000000u; int i = u;
+                unsigned short us = -1;
+                long l = -5; unsigned long m = l;
+                int x = 65536; x = x * x;
+                return (i < 0) + (us == 65535) * 2 + (m % 10 == 1) * 4 + (x == 0) * 8
+                    + (-u == 1294967296) * 16 + ((unsigned char)~0u == 255) * 32 + (m > 5ul) * 64;
+            }",
+            127,
+        );
+        assert_returns("unsigned char c = 511; int main() { return c; }", 255);
+        assert_returns(
+            "int main() { char c = 200; signed char d = '\\xff'; return (c < 0) + (d == -1) * 2; }",
+            3,
+        );
+    }
+
+    #[test]
+    fn run_bitwise() {
+        assert_returns(
+            "int main() {
+                int x = -20; unsigned u = 0x80000000u; unsigned char c = 0xf0;
+                long l = -1; unsigned long ul = -1;
+                return ((x >> 2) == -5) + ((u >> 31) == 1) * 2 + ((x << 3) == -160) * 4
+                    + ((x & 0xff) == 0xec) * 8 + ((unsigned char)(c << 1) == 0xe0) * 16
+                    + ((l >> 63) == -1) * 32 + ((ul >> 63) == 1) * 64
+                    + ((0x5a & 0x0f | 0x30 ^ 0x11) == 0x2b) * 128;
+            }",
+            255,
+        );
+        // FNV-1a
+        assert_returns(
+            "unsigned hash(char *s) {
+                unsigned h = 2166136261u;
+                while (*s) {
+                    h ^= (unsigned char)*s++;
+                    h *= 16777619u;
+                }
+                return h;
+            }
+            int popcount(unsigned x) { int n = 0; while (x) { n += x & 1; x >>= 1; } return n; }
+            int main() { return (hash(\"brine\") & 0xff) + popcount(~0u << 4); }",
+            205,
+        );
+    }
+
+    #[test]
+    fn run_control_flow() {
+        assert_returns(
+            "int main() { int i, total = 0; for (i = 0; i < 5; i++) total += i; return total; }",
+            10,
+        );
+        assert_returns(
+            "int main() { int n = 10, a = 0, b = 1; while (n--) { int t = a + b; a = b; b = t; } return a; }",
+            55,
+        );
+        assert_returns(
+            "int main() { int x = 3, r = 0; switch (x) { case 1: r = 1; case 3: r += 3; case 4: r += 4; break; default: r = 100; } return r; }",
+            7,
+        );
+        assert_returns(
+            "int main() { int i = 0; again: i++; if (i < 3) goto again; do i *= 2; while (i < 20); return i; }",
+            24,
+        );
+    }
+
+    #[test]
+    fn run_io() {
+        assert_output(
+            "int putchar(int); int main() { putchar('h'); putchar(105); return putchar(-246); }",
+            "",
+            "hi\n",
+        );
+        assert_output(
+            "int getchar(void); int putchar(int);
+            int main() { int c; while ((c = getchar()) != -1) putchar(c + 1); }",
+            "HAL",
+            "IBM",
+        );
+        assert_returns("int getchar(void); int main() { return getchar(); }", -1);
+        assert_output(
+            "int puts(const char *); int main() { puts(\"one\"); puts(\"two\"); }",
+            "",
+            "one\ntwo\n",
+        );
+        // arguments are evaluated left to right
+        assert_output(
+            "int putchar(int); int main() { return putchar('a') - putchar('b'); }",
+            "",
+            "ab",
+        );
+    }
+
+    #[test]
+    fn run_functions() {
+        assert_returns(
+            "int square(int x) { return x * x; }
+            int add(int a, int b) { return a + b; }
+            int main() { int x = 3; return add(square(x), x); }",
+            12,
+        );
+        // locals of one call don't clobber those of another
+        assert_returns(
+            "int fac(int n) { int r = 1; if (n > 1) r = n * fac(n - 1); return r; }
+            int main() { return fac(5); }",
+            120,
+        );
+        assert_returns(
+            "int is_odd(int);
+            int is_even(int n) { return n == 0 ? 1 : is_odd(n - 1); }
+            int main() { return is_even(6) * 10 + is_even(3); }
+            int is_odd(int n) { return n == 0 ? 0 : is_even(n - 1); }",
+            10,
+        );
+        assert_output(
+            "int putchar(int);
+            void twice(int c) { putchar(c); putchar(c); }
+            int main(void) { twice('a'); twice('b'); }",
+            "",
+            "aabb",
+        );
+    }
+
+    #[test]
+    fn run_function_pointers() {
+        assert_returns(
+            "int inc(int x) { return x + 1; }
+            int dec(int x) { return x - 1; }
+            int apply(int (*f)(int), int x) { return f(x); }
+            int main() {
+                int (*f)(int) = inc;
+                int y = apply(f, 5);
+                f = &dec;
+                return y * 10 + (*f)(apply(dec, 3));
+            }",
+            61,
+        );
+    }
+
+    #[test]
+    fn run_globals() {
+        assert_returns(
+            "int counter; long start = 40, *unused; _Bool flag = 1;
+            void bump(void) { counter++; }
+            int main() { bump(); bump(); return flag ? counter + start : 0; }",
+            42,
+        );
+        // tentative definitions, and `extern` declarations before the definition
+        assert_returns(
+            "extern int x; int x; int get(void) { extern int y; return x + y; }
+            int x = { 5 }; int y = 2;
+            int main() { x *= 10; return get(); }",
+            52,
+        );
+        // declarations of incomplete type are only allocated once they're defined
+        assert_returns(
+            "extern struct s a; extern int b[]; int main() { extern struct s c; return 0; }",
+            0,
+        );
+        assert_returns(
+            "extern struct s a; extern int b[];
+            struct s { int x; } a = { 4 }; int b[] = { 1, 2 };
+            int main() { return a.x + b[1]; }",
+            6,
+        );
+        assert_returns(
+            "int next(void) { static int n = 10; return n++; }
+            int other(void) { static int n; return --n; }
+            int main() { next(); next(); return next() * other(); }",
+            -12,
+        );
+        // a local shadows a global of the same name
+        assert_returns(
+            "int x = 3; int f(void) { return x; } int main() { int x = 4; return f() * x; }",
+            12,
+        );
+    }
+
+    #[test]
+    fn run_pointers() {
+        assert_returns(
+            "int main() { int x = 1; int *p = &x; *p = 5; x += *p; return x; }",
+            10,
+        );
+        assert_returns(
+            "void swap(int *a, int *b) { int t = *a; *a = *b; *b = t; }
+            int main() { int x = 1, y = 2; swap(&x, &y); return x * 10 + y; }",
+            21,
+        );
+        // parameters whose address is taken
+        assert_returns(
+            "void set(int *p, int v) { *p = v; }
+            int f(int n) { set(&n, n * 2); return n; }
+            int main() { return f(f(3)); }",
+            12,
+        );
+        assert_returns(
+            "int main() { int x = 3, *p = &x, **pp = &p; **pp *= 2; (*pp)++; return x + (p == &x); }",
+            6,
+        );
+    }
+
+    #[test]
+    fn run_arrays() {
+        assert_returns(
+            "int main() {
+                int a[5], i, total = 0;
+                for (i = 0; i < 5; i++) a[i] = i * i;
+                for (i = 0; i < 5; i++) total += a[i];
+                return total;
+            }",
+            30,
+        );
+        assert_returns(
+            "int sum(int *p, int n) { int total = 0; while (n--) total += *p++; return total; }
+            int main() { int a[4] = { 1, 2, 3 }; long b[2][2] = { { 1, 2 }, { 3, 4 } };
+                return sum(a, 4) * 10 + b[1][0]; }",
+            63,
+        );
+        assert_returns(
+            "int main() { char s[] = \"abc\"; char *end = s; while (*end) end++;
+                return (end > s) + (end - 3 == s) * 10 + *(end - 1); }",
+            110,
+        );
+        assert_output(
+            "int putchar(int);
+            void print(char *s) { while (*s) putchar(*s++); }
+            int main() { char *s = \"hi \"; print(s); print(\"there\"); }",
+            "",
+            "hi there",
+        );
+        assert_returns(
+            "int table[3] = { 4, 5 }; char *name = \"xy\"; int *start = table;
+            int main() { table[2] = start[1] + 1; return table[0] + table[2] + name[1]; }",
+            4 + 6 + 'y' as i64,
+        );
+    }
+
+    #[test]
+    fn run_structs() {
+        assert_returns(
+            "struct point { char tag; int x, y; };
+            int main() { struct point p; p.tag = 1; p.x = 3; p.y = 4;
+                struct point *q = &p; q->y *= 10; return p.tag + p.x * 100 + q->y; }",
+            341,
+        );
+        // copies are independent of the original
+        assert_returns(
+            "struct pair { int a, b; };
+            int main() { struct pair p = { 1, 2 }, q = p, r; r = q; q.a = 10; r.b += p.b;
+                return p.a * 1000 + q.a * 100 + r.a * 10 + r.b; }",
+            2014,
+        );
+        // nested aggregates, and passing structs by value
+        assert_returns(
+            "struct inner { int v[2]; }; struct outer { int first; struct inner in; } g = { 5, { { 6 } } };
+            int sum(struct outer o) { o.first = 0; return o.in.v[0] + o.in.v[1]; }
+            int main() { struct outer o[2]; o[1] = g; o[1].in.v[1] = 7;
+                return sum(o[1]) * 100 + g.first * 10 + g.in.v[1]; }",
+            1350,
+        );
+        // all the members of a union share the same bytes
+        assert_returns(
+            "union u { int i; char c[4]; struct { short lo, hi; } s; };
+            int main() { union u x, y; x.i = 0x01020304; y = x; y.c[3] = 0;
+                return x.c[0] + x.c[1] * 10 + x.s.hi * 100 + (y.i == 0x020304) * 100000; }",
+            4 + 30 + 0x0102 * 100 + 100000,
+        );
+    }
+
+    #[test]
+    fn runtime_error_location() {
+        let program = "int div(int a, int b) {\n    return a / b;\n}\nint main() {\n    int zero = 0;\n    return div(1, zero);\n}\n";
+        let opt = Opt {
+            filename: "div.c".into(),
+            ..Opt::default()
+        };
+        let (program, source_map) = compile_with_source_map(program, opt);
+        let mir = program.result.unwrap().desugar();
+        let err = run_with_io(&mir, &mut std::io::empty(), &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind, MiriError::DivisionByZero);
+        assert_eq!(err.context, vec!["function div", "at div.c:2:12"]);
+        let location = source_map.locate(&err.context).unwrap();
+        let rendered = saltwater_parser::pretty_print("error", err.kind, location, &program.files);
+        assert_eq!(
+            rendered,
+            "div.c:2:12 error: division by zero\n    return a / b;\n           ^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn runner_function_tests() {
+        let fibonacci = format!(
+            "{}\nint main() {{ return fibonacci(6); }}",
+            include_str!("../../tests/runner-tests/fibonacci.c")
+        );
+        assert_returns(&fibonacci, 13);
+        let factorial = format!(
+            "{}\nint main() {{ return factorial_recursive(5) + factorial_iterative(4); }}",
+            include_str!("../../tests/runner-tests/factorial.c")
+        );
+        assert_returns(&factorial, 121);
+    }
+
+    #[test]
+    fn runner_output_tests() {
+        assert_output(
+            include_str!("../../tests/runner-tests/stmt/for_loop/0.c"),
+            "",
+            "abcdefghij",
+        );
+        assert_output(
+            include_str!("../../tests/runner-tests/hello_world.c"),
+            "",
+            "Hello, world!\n",
+        );
+    }
+}
@@ -0,0 +1,569 @@
+// Copyright 2020 Matthieu Felix
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! ## Memory
+//!
+//! Objects whose address can be observed live in memory, a map from byte addresses
+//! to bytes (see [`Primitive::Load`] and [`Primitive::Store`]). Scalars are split into
+//! their bytes, least significant first, when they're stored, and put back together
+//! when they're loaded, so union members and `char` pointers see the bytes of wider
+//! objects.
+//!
+//! - Null is address 0, and objects with static storage (globals, `static` locals and
+//!   string literals) are laid out from [`STATIC_BASE`] at compile time.
+//! - The stack starts where static storage ends. Every function receives its frame
+//!   pointer as a hidden first argument, and its callees get the address just past
+//!   its frame. Frames don't need to be freed: they're reused by the next call.
+//!
+//! Structs, unions and arrays always live in memory. The value of an aggregate is its
+//! address, and assigning one copies it byte by byte.
+//!
+//! Locals whose address is never taken stay in stack slots (see [`Primitive::Get`]),
+//! which are much cheaper to access.
+
+use crate::call::string_literal;
+use crate::expr::{bytes, int_literal, scalar_size, Place, Value};
+use crate::mir::{MirExpr, MirLiteral, Primitive};
+use crate::{unsupported, Compiler};
+use saltwater_parser::hir::{Expr, ExprType, Initializer, Stmt, StmtType, Symbol};
+use saltwater_parser::types::ArrayType;
+use saltwater_parser::{CompileResult, LiteralValue, Location, StorageClass, Type};
+use std::collections::HashSet;
+
+/// The address of the first object with static storage.
+pub const STATIC_BASE: u64 = 16;
+
+/// The initial value of a scalar inside an object.
+enum Leaf {
+    Zero,
+    Byte(u8),
+    /// An expression of the same type, which may be an aggregate to copy
+    Expr(Expr),
+}
+
+impl Compiler {
+    /// Compile an expression whose value is an address to the place it designates,
+    /// and pass that place to `rest`.
+    ///
+    /// This is either an lvalue, or a pointer being dereferenced. The address is
+    /// computed (with its side effects) once, before `rest` runs.
+    pub fn with_place<F>(&mut self, expr: Expr, rest: F) -> CompileResult<Value>
+    where
+        F: FnOnce(&mut Self, Place) -> CompileResult<Value>,
+    {
+        match expr.expr {
+            // `x`, or `&x` if it isn't an lvalue
+            ExprType::Id(symbol) => {
+                let place = self.variable_place(symbol, expr.location)?;
+                rest(self, place)
+            }
+            // `*p`, whose address is the value of `p`
+            ExprType::Noop(pointer) if expr.lval => self.with_place(*pointer, rest),
+            // aggregates are always in memory
+            ExprType::Member(inner, member) => {
+                let offset = inner
+                    .ctype
+                    .member_offset(member)
+                    .expect("only structs and unions can have members");
+                let ctype = Type::Pointer(Box::new(inner.ctype.clone()), Default::default());
+                let base = self.address(*inner, ctype)?;
+                self.bind(base, |this, base| {
+                    rest(this, Place::Memory(offset_address(base, offset)))
+                })
+            }
+            // the value of a pointer variable that aliases another place,
+            // e.g. the temporary the frontend introduces for `x += 1`
+            ExprType::Deref(ref inner) if !expr.lval => match inner.expr {
+                ExprType::Id(symbol) if self.aliases.contains_key(&symbol) => {
+                    let place = self.aliases[&symbol].clone();
+                    rest(self, place)
+                }
+                _ => self.with_address(expr, rest),
+            },
+            // this includes `a[i]`, which is `a + i` marked as an lvalue
+            _ => self.with_address(expr, rest),
+        }
+    }
+
+    /// Compute the value of `expr` as an address in memory, and pass it to `rest`.
+    fn with_address<F>(&mut self, expr: Expr, rest: F) -> CompileResult<Value>
+    where
+        F: FnOnce(&mut Self, Place) -> CompileResult<Value>,
+    {
+        let address = self.compile_expr(Expr {
+            lval: false,
+            ..expr
+        })?;
+        self.bind(address, |this, address| rest(this, Place::Memory(address)))
+    }
+
+    /// The address of the place designated by `expr`, as a value of type `ctype`.
+    pub fn address(&mut self, expr: Expr, ctype: Type) -> CompileResult<Value> {
+        let location = expr.location;
+        self.with_place(expr, |_, place| match place {
+            Place::Memory(address) => Ok(Value {
+                val: address,
+                ctype,
+                pure: true,
+            }),
+            // the frontend only makes non-lvalue `Id`s from `&x` and arrays,
+            // so these variables are never in stack slots
+            Place::Stack(_) => unsupported("taking the address of this variable", location),
+        })
+    }
+
+    /// Allocate space for `ctype` in the current stack frame, and return its offset
+    /// from the frame pointer.
+    pub fn allocate_local(&mut self, ctype: &Type, location: Location) -> CompileResult<u64> {
+        let (size, align) = size_and_align(ctype, location)?;
+        let offset = align_to(self.frame_size, align);
+        self.frame_size = offset + size;
+        Ok(offset)
+    }
+
+    /// Allocate space for `ctype` in static storage, and return its address.
+    pub fn allocate_static(&mut self, ctype: &Type, location: Location) -> CompileResult<i64> {
+        let (size, align) = size_and_align(ctype, location)?;
+        let address = align_to(self.static_size, align);
+        self.static_size = address + size;
+        self.initialize_static(address, ctype, None, location)?;
+        Ok(address as i64)
+    }
+
+    /// Set the initial value of the object of type `ctype` at `address` in static storage.
+    ///
+    /// Static storage starts out as zero, but `_Bool`s still need an initial value of
+    /// the right type.
+    pub fn initialize_static(
+        &mut self,
+        address: u64,
+        ctype: &Type,
+        init: Option<Initializer>,
+        location: Location,
+    ) -> CompileResult<()> {
+        let mut leaves = Vec::new();
+        flatten(ctype, init, 0, &mut leaves, location)?;
+        for (offset, ctype, leaf) in leaves {
+            let value = match leaf {
+                Leaf::Zero => 0,
+                Leaf::Byte(b) => i64::from(b),
+                Leaf::Expr(expr) if !ctype.is_scalar() => {
+                    return unsupported("non-constant static initializers", expr.location)
+                }
+                Leaf::Expr(expr) => {
+                    let value = self.compile_expr(expr)?;
+                    match value.val {
+                        MirExpr::Literal(literal) if value.pure => match *literal {
+                            MirLiteral::Int(i) => i,
+                            MirLiteral::Bool(b) => i64::from(b),
+                            _ => return unsupported("non-constant static initializers", location),
+                        },
+                        _ => return unsupported("non-constant static initializers", location),
+                    }
+                }
+            };
+            let bytes = bytes(int_literal(value), scalar_size(&ctype));
+            for (i, byte) in bytes.into_iter().enumerate() {
+                let address = (address + offset) as i64 + i as i64;
+                self.static_memory
+                    .retain(|&(existing, _)| existing != address);
+                // memory reads 0 at addresses which were never written
+                if byte != int_literal(0) {
+                    self.static_memory.push((address, byte));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Store the initial value of an object of type `ctype` in memory at `address`,
+    /// which must be pure. Any elements without an initializer are set to zero.
+    pub fn initialize_local(
+        &mut self,
+        address: MirExpr,
+        ctype: &Type,
+        init: Initializer,
+        location: Location,
+    ) -> CompileResult<Value> {
+        let mut leaves = Vec::new();
+        flatten(ctype, Some(init), 0, &mut leaves, location)?;
+        let mut result = Value {
+            val: MirExpr::literal(MirLiteral::Null),
+            ctype: Type::Void,
+            pure: true,
+        };
+        for (offset, ctype, leaf) in leaves {
+            let address = offset_address(address.clone(), offset);
+            let value = match leaf {
+                // `struct s a = b;`
+                Leaf::Expr(expr) if !ctype.is_scalar() => {
+                    let source = self.compile_expr(expr)?;
+                    let copy =
+                        self.bind(source, |this, source| this.copy(address, source, &ctype))?;
+                    result = self.seq(result, copy);
+                    continue;
+                }
+                Leaf::Zero => Value {
+                    val: MirExpr::literal(zero(&ctype)),
+                    ctype: ctype.clone(),
+                    pure: true,
+                },
+                Leaf::Byte(b) => Value {
+                    val: MirExpr::literal(MirLiteral::Int(i64::from(b))),
+                    ctype: ctype.clone(),
+                    pure: true,
+                },
+                Leaf::Expr(expr) => self.compile_expr(expr)?,
+            };
+            let place = Place::Memory(address);
+            let store = self.bind(value, |this, val| {
+                let value = Value {
+                    val,
+                    ctype,
+                    pure: true,
+                };
+                Ok(this.store(place, value))
+            })?;
+            result = self.seq(result, store);
+        }
+        Ok(result)
+    }
+
+    /// Copy an object of type `ctype` from `source` to `dest`, which are pure addresses.
+    /// The result is `dest`, which is how aggregate values are represented.
+    pub fn copy(&mut self, dest: MirExpr, source: MirExpr, ctype: &Type) -> CompileResult<Value> {
+        let size = ctype
+            .sizeof()
+            .expect("copied objects should have a valid size");
+        let byte = Type::Char(false);
+        let mut result = Value {
+            val: MirExpr::literal(MirLiteral::Null),
+            ctype: Type::Void,
+            pure: true,
+        };
+        for offset in 0..size {
+            let value = self.load(
+                Place::Memory(offset_address(source.clone(), offset)),
+                byte.clone(),
+            );
+            let place = Place::Memory(offset_address(dest.clone(), offset));
+            let store = self.bind(value, |this, val| {
+                let value = Value {
+                    val,
+                    ctype: byte.clone(),
+                    pure: true,
+                };
+                Ok(this.store(place, value))
+            })?;
+            result = self.seq(result, store);
+        }
+        let dest = Value {
+            val: dest,
+            ctype: ctype.clone(),
+            pure: true,
+        };
+        Ok(self.seq(result, dest))
+    }
+
+    /// The address of a string literal, which is stored in static memory.
+    pub fn string_literal(&mut self, bytes: Vec<u8>, location: Location) -> CompileResult<i64> {
+        let ctype = Type::Array(
+            Box::new(Type::Char(true)),
+            ArrayType::Fixed(bytes.len() as u64),
+        );
+        let address = self.allocate_static(&ctype, location)?;
+        let init = Initializer::Scalar(Box::new(Expr {
+            expr: ExprType::Literal(LiteralValue::Str(bytes)),
+            ctype: ctype.clone(),
+            lval: false,
+            location,
+        }));
+        self.initialize_static(address as u64, &ctype, Some(init), location)?;
+        Ok(address)
+    }
+}
+
+/// `address + offset`, which is folded for static addresses so they can be used in
+/// static initializers.
+pub fn offset_address(address: MirExpr, offset: u64) -> MirExpr {
+    if offset == 0 {
+        return address;
+    }
+    if let MirExpr::Literal(literal) = &address {
+        if let MirLiteral::Int(base) = **literal {
+            return MirExpr::literal(MirLiteral::Int(base + offset as i64));
+        }
+    }
+    MirExpr::apply(
+        MirExpr::apply(MirExpr::Primitive(Primitive::Plus), address),
+        MirExpr::literal(MirLiteral::Int(offset as i64)),
+    )
+}
+
+/// Whether an object of type `ctype` has a size, so it can be allocated.
+///
+/// This is false for declarations like `extern struct s x;` or `extern int a[];`.
+pub fn is_complete(ctype: &Type) -> bool {
+    match ctype {
+        Type::Struct(struct_type) | Type::Union(struct_type) => !struct_type.is_empty(),
+        Type::Array(_, ArrayType::Unbounded) => false,
+        _ => true,
+    }
+}
+
+/// The type of an object declared with `ctype` and initialized by `init`.
+///
+/// This is only different from `ctype` for arrays of unknown size like `int a[] = { 1, 2 }`,
+/// whose length comes from the initializer.
+pub fn complete_type(ctype: &Type, init: Option<&Initializer>) -> Type {
+    match (ctype, init) {
+        (Type::Array(element, ArrayType::Unbounded), Some(init)) => {
+            let len = match init {
+                Initializer::InitializerList(inits) => inits.len(),
+                Initializer::Scalar(expr) => match string_literal(expr) {
+                    Some(bytes) => bytes.len(),
+                    None => return ctype.clone(),
+                },
+                Initializer::FunctionBody(_) => {
+                    unreachable!("only functions should have a function body")
+                }
+            };
+            Type::Array(element.clone(), ArrayType::Fixed(len as u64))
+        }
+        _ => ctype.clone(),
+    }
+}
+
+/// The value of a scalar of type `ctype` which is zero.
+pub fn zero(ctype: &Type) -> MirLiteral {
+    if *ctype == Type::Bool {
+        MirLiteral::Bool(false)
+    } else {
+        MirLiteral::Int(0)
+    }
+}
+
+pub fn align_to(offset: u64, align: u64) -> u64 {
+    offset.div_ceil(align) * align
+}
+
+fn size_and_align(ctype: &Type, location: Location) -> CompileResult<(u64, u64)> {
+    match (ctype.sizeof(), ctype.alignof()) {
+        (Ok(size), Ok(align)) => Ok((size, align)),
+        (Err(err), _) | (_, Err(err)) => Err(location.with(err.to_string()).into()),
+    }
+}
+
+/// Find the initial value of every scalar in an object of type `ctype` at `offset`,
+/// given its initializer.
+fn flatten(
+    ctype: &Type,
+    init: Option<Initializer>,
+    offset: u64,
+    leaves: &mut Vec<(u64, Type, Leaf)>,
+    location: Location,
+) -> CompileResult<()> {
+    match (ctype, init) {
+        // `int i = { 1 };`
+        (_, Some(Initializer::InitializerList(mut inits)))
+            if ctype.is_scalar() && inits.len() == 1 =>
+        {
+            flatten(ctype, Some(inits.remove(0)), offset, leaves, location)
+        }
+        (_, Some(Initializer::Scalar(expr))) if ctype.is_scalar() => {
+            leaves.push((offset, ctype.clone(), Leaf::Expr(*expr)));
+            Ok(())
+        }
+        (_, None) if ctype.is_scalar() => {
+            leaves.push((offset, ctype.clone(), Leaf::Zero));
+            Ok(())
+        }
+        (Type::Array(element, ArrayType::Fixed(len)), init) => {
+            let (size, _) = size_and_align(element, location)?;
+            let mut inits: Vec<Option<Initializer>> = match init {
+                None => vec![],
+                Some(Initializer::InitializerList(inits)) => inits.into_iter().map(Some).collect(),
+                // `char s[] = "hi";`
+                Some(Initializer::Scalar(expr)) => match string_literal(&expr) {
+                    Some(bytes) => {
+                        for (i, &byte) in bytes.iter().take(*len as usize).enumerate() {
+                            let offset = offset + i as u64 * size;
+                            leaves.push((offset, (**element).clone(), Leaf::Byte(byte)));
+                        }
+                        return Ok(());
+                    }
+                    None => return unsupported("arrays initialized by expressions", location),
+                },
+                Some(Initializer::FunctionBody(_)) => {
+                    unreachable!("only functions should have a function body")
+                }
+            };
+            inits.resize_with(*len as usize, || None);
+            for (i, init) in inits.into_iter().enumerate() {
+                flatten(element, init, offset + i as u64 * size, leaves, location)?;
+            }
+            Ok(())
+        }
+        (Type::Array(_, ArrayType::Unbounded), _) => {
+            unsupported("arrays of unknown size", location)
+        }
+        // `struct s a = b;`
+        (Type::Struct(_), Some(Initializer::Scalar(expr)))
+        | (Type::Union(_), Some(Initializer::Scalar(expr))) => {
+            leaves.push((offset, ctype.clone(), Leaf::Expr(*expr)));
+            Ok(())
+        }
+        (Type::Struct(stype), init) | (Type::Union(stype), init) => {
+            let mut inits: Vec<Option<Initializer>> = match init {
+                None => vec![],
+                Some(Initializer::InitializerList(inits)) => inits.into_iter().map(Some).collect(),
+                Some(_) => unreachable!("handled above"),
+            };
+            let mut members = stype.members().to_vec();
+            // only the first member of a union is initialized
+            if let Type::Union(_) = ctype {
+                members.truncate(1);
+            }
+            inits.resize_with(members.len(), || None);
+            for (member, init) in members.into_iter().zip(inits) {
+                let member_offset = ctype
+                    .member_offset(member.id)
+                    .expect("only structs and unions can have members");
+                flatten(
+                    &member.ctype,
+                    init,
+                    offset + member_offset,
+                    leaves,
+                    location,
+                )?;
+            }
+            Ok(())
+        }
+        _ => unsupported("this initializer", location),
+    }
+}
+
+/// Whether a variable needs to live in memory: either it's an aggregate, or its address
+/// is taken somewhere in `stmts`.
+pub fn needs_memory(symbol: Symbol, addressed: &HashSet<Symbol>) -> bool {
+    !symbol.get().ctype.is_scalar() || addressed.contains(&symbol)
+}
+
+/// The local variables whose address is taken in a function body.
+///
+/// In HIR, using a variable as an rvalue loads from it, with `Deref(Id(x))`. A bare
+/// `Id(x)` which isn't an lvalue is the address of `x` itself, from `&x` or an array
+/// decaying to a pointer.
+pub fn addressed_variables(stmts: &[Stmt]) -> HashSet<Symbol> {
+    let mut found = HashSet::new();
+    for stmt in stmts {
+        stmt_addresses(stmt, &mut found);
+    }
+    found
+}
+
+fn stmt_addresses(stmt: &Stmt, found: &mut HashSet<Symbol>) {
+    match &stmt.data {
+        StmtType::Compound(stmts) => {
+            for stmt in stmts {
+                stmt_addresses(stmt, found);
+            }
+        }
+        StmtType::If(condition, body, otherwise) => {
+            expr_addresses(condition, found);
+            stmt_addresses(body, found);
+            if let Some(otherwise) = otherwise {
+                stmt_addresses(otherwise, found);
+            }
+        }
+        StmtType::Do(body, condition) | StmtType::While(condition, body) => {
+            expr_addresses(condition, found);
+            stmt_addresses(body, found);
+        }
+        StmtType::For(init, condition, post_loop, body) => {
+            stmt_addresses(init, found);
+            for expr in condition.iter().chain(post_loop) {
+                expr_addresses(expr, found);
+            }
+            stmt_addresses(body, found);
+        }
+        StmtType::Switch(condition, body) => {
+            expr_addresses(condition, found);
+            stmt_addresses(body, found);
+        }
+        StmtType::Label(_, inner) | StmtType::Case(_, inner) | StmtType::Default(inner) => {
+            stmt_addresses(inner, found)
+        }
+        StmtType::Expr(expr) | StmtType::Return(Some(expr)) => expr_addresses(expr, found),
+        StmtType::Decl(decls) => {
+            for decl in decls {
+                if let Some(init) = &decl.data.init {
+                    initializer_addresses(init, found);
+                }
+            }
+        }
+        StmtType::Return(None) | StmtType::Goto(_) | StmtType::Continue | StmtType::Break => {}
+    }
+}
+
+fn initializer_addresses(init: &Initializer, found: &mut HashSet<Symbol>) {
+    match init {
+        Initializer::Scalar(expr) => expr_addresses(expr, found),
+        Initializer::InitializerList(inits) => {
+            for init in inits {
+                initializer_addresses(init, found);
+            }
+        }
+        Initializer::FunctionBody(_) => {}
+    }
+}
+
+fn expr_addresses(expr: &Expr, found: &mut HashSet<Symbol>) {
+    match &expr.expr {
+        ExprType::Id(symbol) => {
+            let meta = symbol.get();
+            let local = matches!(
+                meta.storage_class,
+                StorageClass::Auto | StorageClass::Register
+            );
+            if !expr.lval && local && !meta.ctype.is_function() {
+                found.insert(*symbol);
+            }
+        }
+        ExprType::Literal(_) | ExprType::Sizeof(_) => {}
+        ExprType::FuncCall(func, args) => {
+            expr_addresses(func, found);
+            for arg in args {
+                expr_addresses(arg, found);
+            }
+        }
+        ExprType::Member(inner, _)
+        | ExprType::PostIncrement(inner, _)
+        | ExprType::Cast(inner)
+        | ExprType::Deref(inner)
+        | ExprType::Negate(inner)
+        | ExprType::BitwiseNot(inner)
+        | ExprType::StaticRef(inner)
+        | ExprType::Noop(inner) => expr_addresses(inner, found),
+        ExprType::Binary(_, left, right) | ExprType::Comma(left, right) => {
+            expr_addresses(left, found);
+            expr_addresses(right, found);
+        }
+        ExprType::Ternary(condition, then, otherwise) => {
+            expr_addresses(condition, found);
+            expr_addresses(then, found);
+            expr_addresses(otherwise, found);
+        }
+    }
+}
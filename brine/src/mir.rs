@@ -15,18 +15,38 @@
 //! ## Mid-level intermediate representation
 //! Describes a purely-functional language higher-level than Relambda, serving as an intermediate
 //! compilation step.
+//!
+//! ### Text format
+//!
+//! MIR is written as s-expressions, which [`mir_to_lexpr`] and [`lexpr_to_mir`] convert
+//! exactly to and from MIR:
+//!
+//! ```text
+//! expr := integer | #t | #f | ()          literals; () is null
+//!       | name                            a primitive if one has that name, else a reference
+//!       | (#:ref "name")                  a reference to any name
+//!       | (#:get n) | (#:set n) | (#:frame n)
+//!       | (#:let (name expr) expr)
+//!       | (#:lambda (name ...) expr)      a lambda for each name, outermost first
+//!       | (#:if expr expr expr)
+//!       | (#:comment "text" expr)
+//!       | (expr expr ...)                 applies the first expression to each of the others
+//!                                         in turn
+//! ```
+//!
+//! Names bound by `let` and `lambda` can also be strings, for names which aren't valid
+//! symbols. A `.mir` file holds MIR expressions, and may contain `;` comments.
 
 use itertools::Itertools;
 use lexpr::{Number, Value};
 use saltwater_parser::get_str;
 use saltwater_parser::InternedStr;
-use serde::de::Visitor;
-use serde::export::Formatter;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_lexpr::{from_str, to_string};
-use std::convert::TryFrom;
+use std::fmt::Formatter;
+use std::io::Write;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum MirExpr {
     Let(Box<Let>),
@@ -79,17 +99,17 @@ impl MirExpr {
         match self {
             MirExpr::Let(let_) => {
                 let Let { ident, value, body } = &**let_;
-                MirExpr::apply(
-                    MirExpr::lambda(ident.clone(), body.desugar()),
-                    value.desugar(),
-                )
+                MirExpr::apply(MirExpr::lambda(*ident, body.desugar()), value.desugar())
             }
             MirExpr::Primitive(Primitive::Y) => Y_CODE.clone(),
-            MirExpr::Primitive(Primitive::Pure) => todo!(),
-            MirExpr::Primitive(Primitive::Lift) => todo!(),
-            MirExpr::Primitive(Primitive::Then) => todo!(),
-            MirExpr::Primitive(Primitive::Get(_)) => todo!(),
-            MirExpr::Primitive(Primitive::Set(_)) => todo!(),
+            MirExpr::Primitive(Primitive::Pure) => PURE_CODE.clone(),
+            MirExpr::Primitive(Primitive::Lift) => LIFT_CODE.clone(),
+            MirExpr::Primitive(Primitive::Then) => THEN_CODE.clone(),
+            MirExpr::Primitive(Primitive::Get(n)) => get_code(*n),
+            MirExpr::Primitive(Primitive::Set(n)) => set_code(*n),
+            MirExpr::Primitive(Primitive::Load) => LOAD_CODE.clone(),
+            MirExpr::Primitive(Primitive::Store) => STORE_CODE.clone(),
+            MirExpr::Primitive(Primitive::Frame(n)) => frame_code(*n),
             MirExpr::Primitive(_) | MirExpr::Literal(_) | MirExpr::Ref(_) => self.clone(),
             MirExpr::Lambda(l) => {
                 let Lambda { arg, body } = &**l;
@@ -114,6 +134,15 @@ impl MirExpr {
             MirExpr::Comment(cmt, body) => MirExpr::Comment(cmt.clone(), Box::new(body.desugar())),
         }
     }
+
+    /// Write the expression in MIR's text format, preceded by `header` in `;` comments,
+    /// and followed by a newline.
+    pub fn write_mir<W: Write>(&self, header: &str, mut out: W) -> std::io::Result<()> {
+        for line in header.lines() {
+            writeln!(out, "; {}", line)?;
+        }
+        writeln!(out, "{}", mir_to_lexpr(self))
+    }
 }
 
 lazy_static! {
@@ -127,18 +156,194 @@ lazy_static! {
     .unwrap();
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+// Stateful computations are desugared to functions from a state to `(cons result new_state)`.
+// The state is `(cons memory stack)`:
+//  - `memory` is a list of `(cons address value)` cells, most recently stored first.
+//    Addresses which were never stored to hold 0.
+//  - `stack` is a list of slots. Function calls push a frame for the callee's locals on top
+//    of it, so stack slots are numbered from the current frame.
+lazy_static! {
+    static ref PURE_CODE: MirExpr = parse_mir("(#:lambda (x s) (cons x s))").unwrap();
+    static ref LIFT_CODE: MirExpr =
+        parse_mir("(#:lambda (f m s) (#:let (r (m s)) (cons (f (car r)) (cdr r))))")
+            .unwrap()
+            .desugar();
+    static ref THEN_CODE: MirExpr =
+        parse_mir("(#:lambda (m f s) (#:let (r (m s)) ((f (car r)) (cdr r))))")
+            .unwrap()
+            .desugar();
+    static ref LOAD_CODE: MirExpr = parse_mir(
+        "\
+    (#:lambda (a s)\
+     (cons ((y (#:lambda (find cells)\
+                (#:if (is-null cells) 0\
+                 (#:if (eq (car (car cells)) a) (cdr (car cells))\
+                  (find (cdr cells))))))\
+            (car s))\
+      s))\
+    "
+    )
+    .unwrap()
+    .desugar();
+    // the stored cell moves to the front of memory, so recently used addresses are quick to find
+    static ref STORE_CODE: MirExpr = parse_mir(
+        "\
+    (#:lambda (a v s)\
+     (cons ()\
+      (cons (cons (cons a v)\
+             ((y (#:lambda (remove cells)\
+                  (#:if (is-null cells) ()\
+                   (#:if (eq (car (car cells)) a) (cdr cells)\
+                    (cons (car cells) (remove (cdr cells)))))))\
+              (car s)))\
+       (cdr s))))\
+    "
+    )
+    .unwrap()
+    .desugar();
+    static ref STATE: MirInternedStr = MirInternedStr::get_or_intern("s");
+    static ref VALUE: MirInternedStr = MirInternedStr::get_or_intern("v");
+    static ref LIST: MirInternedStr = MirInternedStr::get_or_intern("l");
+}
+
+fn state_primitive(p: Primitive, arg: MirExpr) -> MirExpr {
+    MirExpr::apply(MirExpr::Primitive(p), arg)
+}
+
+fn cons(car: MirExpr, cdr: MirExpr) -> MirExpr {
+    MirExpr::apply(state_primitive(Primitive::Cons, car), cdr)
+}
+
+/// The stack of `state`.
+fn stack_of(state: MirExpr) -> MirExpr {
+    state_primitive(Primitive::Cdr, state)
+}
+
+/// `state`, with its stack replaced by `stack`.
+fn with_stack(state: MirExpr, stack: MirExpr) -> MirExpr {
+    cons(state_primitive(Primitive::Car, state), stack)
+}
+
+/// `(lambda (s) (cons (car (cdr ... (cdr stack))) s))`, where `stack` is the stack of `s`.
+fn get_code(slot: usize) -> MirExpr {
+    let state = MirExpr::Ref(*STATE);
+    let rest = (0..slot).fold(stack_of(state.clone()), |list, _| {
+        state_primitive(Primitive::Cdr, list)
+    });
+    let value = state_primitive(Primitive::Car, rest);
+    MirExpr::lambda(*STATE, cons(value, state))
+}
+
+/// `(lambda (v s) (cons null new_state))`, where `new_state` is `s` with stack slot `slot`
+/// replaced by `v`.
+fn set_code(slot: usize) -> MirExpr {
+    let list = || MirExpr::Ref(*LIST);
+    let replaced = cons(
+        MirExpr::Ref(*VALUE),
+        state_primitive(Primitive::Cdr, list()),
+    );
+    // rebuild the list up to the slot, shadowing `l` with its tail at each step
+    let new_stack = (0..slot).fold(replaced, |inner, _| {
+        cons(
+            state_primitive(Primitive::Car, list()),
+            MirExpr::let_(*LIST, state_primitive(Primitive::Cdr, list()), inner),
+        )
+    });
+    let new_stack = MirExpr::let_(*LIST, stack_of(MirExpr::Ref(*STATE)), new_stack);
+    let new_state = with_stack(MirExpr::Ref(*STATE), new_stack);
+    MirExpr::lambda(
+        *VALUE,
+        MirExpr::lambda(*STATE, cons(MirExpr::literal(MirLiteral::Null), new_state)),
+    )
+    .desugar()
+}
+
+/// `(lambda (m s) (let (r (m new_state)) (cons (car r) old_state)))`, where `new_state`
+/// is `s` with `slots` null slots pushed on top of its stack, and `old_state` is the
+/// state `m` returned with them popped again.
+fn frame_code(slots: usize) -> MirExpr {
+    let computation = MirInternedStr::get_or_intern("m");
+    let result = MirInternedStr::get_or_intern("r");
+    let state = MirExpr::Ref(*STATE);
+    let pushed = (0..slots).fold(stack_of(state.clone()), |list, _| {
+        cons(MirExpr::literal(MirLiteral::Null), list)
+    });
+    let new_state = with_stack(state, pushed);
+    let returned_state = || state_primitive(Primitive::Cdr, MirExpr::Ref(result));
+    let popped = (0..slots).fold(stack_of(returned_state()), |list, _| {
+        state_primitive(Primitive::Cdr, list)
+    });
+    let old_state = with_stack(returned_state(), popped);
+    MirExpr::lambda(
+        computation,
+        MirExpr::lambda(
+            *STATE,
+            MirExpr::let_(
+                result,
+                MirExpr::apply(MirExpr::Ref(computation), new_state),
+                cons(
+                    state_primitive(Primitive::Car, MirExpr::Ref(result)),
+                    old_state,
+                ),
+            ),
+        ),
+    )
+    .desugar()
+}
+
+/// Run the stateful computation `expr` with an empty stack and the given `(address, value)`
+/// cells in memory, and return its result.
+pub fn run_stateful(expr: MirExpr, memory: Vec<(i64, MirExpr)>) -> MirExpr {
+    let memory = memory.into_iter().rev().fold(
+        MirExpr::literal(MirLiteral::Null),
+        |list, (address, value)| {
+            cons(
+                cons(MirExpr::literal(MirLiteral::Int(address)), value),
+                list,
+            )
+        },
+    );
+    let initial_state = cons(memory, MirExpr::literal(MirLiteral::Null));
+    state_primitive(Primitive::Car, MirExpr::apply(expr, initial_state))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum Primitive {
     Plus,
     Minus,
     Times,
+    /// Division, rounding towards zero
     Div,
+    /// The remainder of `Div`, which has the sign of the dividend
     Mod,
+    /// Division of the two's complement bit patterns as unsigned integers
+    DivUnsigned,
+    /// The remainder of `DivUnsigned`
+    ModUnsigned,
+    /// Keep the bits of an integer (the second argument) which are set in a mask of
+    /// its low bits (the first argument), and clear the others
+    Truncate,
+    /// Keep the bits of an integer (the second argument) which are set in a mask of
+    /// its low bits (the first argument), and set the others to the highest bit kept
+    SignExtend,
     Neg,
     And,
     Or,
     Xor,
+    /// Bitwise operations on integers
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    /// Shift an integer left by the low 6 bits of the second argument
+    Shl,
+    /// Shift an integer right by the low 6 bits of the second argument, copying
+    /// the sign bit
+    Shr,
+    /// Shift an integer right by the low 6 bits of the second argument, filling
+    /// with zeros
+    ShrUnsigned,
     Cons,
     Car,
     Cdr,
@@ -148,17 +353,31 @@ pub enum Primitive {
     Gt,
     Ge,
     BoolToInt,
+    /// Write the low 8 bits of an integer to the output, and return them
+    PutChar,
+    /// Read a byte from the input, or return -1 at the end of input. Takes a
+    /// dummy argument.
+    GetChar,
+    /// Whether a value is null
+    IsNull,
 
     // Higher level primitives -- get rewritten during desugaring
     Get(usize),
     Set(usize),
+    /// Read the value at an address in memory
+    Load,
+    /// Write a value (the second argument) to an address in memory, and return null
+    Store,
+    /// Run a stateful computation with the given number of new slots on top of
+    /// the state, numbered from 0, and remove them when it's done.
+    Frame(usize),
     Pure, // x -> S[x]
     Lift, // (x -> y) -> S[x] -> S[y]
     Then,
     Y,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum MirLiteral {
     Bool(bool),
@@ -166,7 +385,7 @@ pub enum MirLiteral {
     Null,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Let {
     pub ident: MirInternedStr,
@@ -174,14 +393,14 @@ pub struct Let {
     pub body: MirExpr,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Lambda {
     pub arg: MirInternedStr,
     pub body: MirExpr,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct If {
     pub condition: MirExpr,
@@ -189,7 +408,7 @@ pub struct If {
     pub alternative: MirExpr,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Apply {
     pub func: MirExpr,
@@ -313,7 +532,7 @@ fn cons_to_mir(cons: lexpr::Cons) -> Result<MirExpr, String> {
         _ => {
             let first_val = lexpr_to_mir(first)?;
             let mut others = elems.into_iter().map(lexpr_to_mir);
-            others.fold_results(first_val, |func, arg| MirExpr::apply(func, arg))
+            others.fold_results(first_val, MirExpr::apply)
         }
     }
 }
@@ -349,15 +568,7 @@ fn parse_kw(kw: &str, mut elems: Vec<lexpr::Value>) -> Result<MirExpr, String> {
                 ));
             }
             let val = lexpr_to_mir(bind.pop().unwrap())?;
-            let ident = match bind.pop().unwrap() {
-                Value::Symbol(s) => MirInternedStr::get_or_intern(s),
-                e => {
-                    return Err(format!(
-                        "lambda first argument must be a symbol, not {:?}",
-                        e
-                    ))
-                }
-            };
+            let ident = parse_name(bind.pop().unwrap(), "let binding name")?;
             MirExpr::let_(ident, val, body)
         }
         "lambda" => {
@@ -372,13 +583,10 @@ fn parse_kw(kw: &str, mut elems: Vec<lexpr::Value>) -> Result<MirExpr, String> {
                 Value::Cons(s) => into_vec_proper(s)?,
                 e => return Err(format!("lambda first argument must be a list, not {:?}", e)),
             };
-            let mut formal_symbols = Vec::new();
-            for formal in formals {
-                match formal {
-                    Value::Symbol(s) => formal_symbols.push(MirInternedStr::get_or_intern(s)),
-                    _ => return Err(format!("lambda formals must be symbols, not {:?}", formal)),
-                }
-            }
+            let formal_symbols = formals
+                .into_iter()
+                .map(|formal| parse_name(formal, "lambda formal"))
+                .collect::<Result<Vec<_>, _>>()?;
             formal_symbols
                 .into_iter()
                 .rfold(body, |inner, ext_formal| MirExpr::lambda(ext_formal, inner))
@@ -414,42 +622,135 @@ fn parse_kw(kw: &str, mut elems: Vec<lexpr::Value>) -> Result<MirExpr, String> {
             };
             MirExpr::Comment(comment, Box::new(body))
         }
+        "ref" => match elems.as_slice() {
+            [Value::String(s)] => MirExpr::Ref(MirInternedStr::get_or_intern(&**s)),
+            _ => {
+                return Err(format!(
+                    "ref must have a single string argument, found {:?}",
+                    elems
+                ))
+            }
+        },
+        "get" | "set" | "frame" => {
+            let n = match elems.as_slice() {
+                [Value::Number(n)] => n.as_u64(),
+                _ => None,
+            };
+            let n = match n {
+                Some(n) => n as usize,
+                None => {
+                    return Err(format!(
+                        "{} must have a single non-negative integer argument, found {:?}",
+                        kw, elems
+                    ))
+                }
+            };
+            MirExpr::Primitive(match kw {
+                "get" => Primitive::Get(n),
+                "set" => Primitive::Set(n),
+                _ => Primitive::Frame(n),
+            })
+        }
         _ => return Err(format!("unknown keyword: {}", kw)),
     })
 }
 
+/// A name bound by `let` or `lambda`: a symbol, or a string for names which aren't symbols.
+fn parse_name(v: Value, what: &str) -> Result<MirInternedStr, String> {
+    match v {
+        Value::Symbol(s) => Ok(MirInternedStr::get_or_intern(s)),
+        Value::String(s) => Ok(MirInternedStr::get_or_intern(s)),
+        e => Err(format!(
+            "{} must be a symbol or a string, not {:?}",
+            what, e
+        )),
+    }
+}
+
 pub fn mir_to_lexpr(expr: &MirExpr) -> lexpr::Value {
     match expr {
         MirExpr::Let(l) => Value::list(vec![
             Value::keyword("let".to_string()),
-            Value::list(vec![
-                Value::symbol(l.ident.to_string()),
-                mir_to_lexpr(&l.value),
-            ]),
-            mir_to_lexpr(&l.body),
-        ]),
-        MirExpr::Lambda(l) => Value::list(vec![
-            Value::keyword("lambda".to_string()),
-            Value::symbol(l.arg.to_string()),
+            Value::list(vec![name_to_lexpr(l.ident), mir_to_lexpr(&l.value)]),
             mir_to_lexpr(&l.body),
         ]),
+        MirExpr::Lambda(l) => {
+            // `(lambda (x) (lambda (y) body))` is written `(lambda (x y) body)`
+            let mut formals = vec![name_to_lexpr(l.arg)];
+            let mut body = &l.body;
+            while let MirExpr::Lambda(l) = body {
+                formals.push(name_to_lexpr(l.arg));
+                body = &l.body;
+            }
+            Value::list(vec![
+                Value::keyword("lambda".to_string()),
+                Value::list(formals),
+                mir_to_lexpr(body),
+            ])
+        }
         MirExpr::If(if_) => Value::list(vec![
             Value::keyword("if".to_string()),
             mir_to_lexpr(&if_.condition),
             mir_to_lexpr(&if_.consequent),
             mir_to_lexpr(&if_.alternative),
         ]),
-        MirExpr::Apply(ap) => Value::list(vec![mir_to_lexpr(&ap.func), mir_to_lexpr(&ap.arg)]),
-        MirExpr::Primitive(p) => Value::symbol(to_string(p).unwrap()),
+        MirExpr::Apply(_) => {
+            // `((f x) y)` is written `(f x y)`
+            let mut args = Vec::new();
+            let mut func = expr;
+            while let MirExpr::Apply(ap) = func {
+                args.push(mir_to_lexpr(&ap.arg));
+                func = &ap.func;
+            }
+            args.push(mir_to_lexpr(func));
+            args.reverse();
+            Value::list(args)
+        }
+        MirExpr::Primitive(p) => {
+            let (kw, n) = match p {
+                Primitive::Get(n) => ("get", n),
+                Primitive::Set(n) => ("set", n),
+                Primitive::Frame(n) => ("frame", n),
+                _ => return Value::symbol(to_string(p).unwrap()),
+            };
+            Value::list(vec![
+                Value::keyword(kw.to_string()),
+                Value::Number(Number::from(*n as u64)),
+            ])
+        }
         MirExpr::Literal(b) => match &**b {
             MirLiteral::Null => Value::Null,
             MirLiteral::Int(i) => Value::Number(Number::from(*i)),
             MirLiteral::Bool(b) => Value::Bool(*b),
         },
-        MirExpr::Ref(r) => Value::symbol(r.to_string()),
-        MirExpr::Comment(comment, body) => {
-            Value::list(vec![Value::string(comment.clone()), mir_to_lexpr(body)])
+        MirExpr::Ref(r) => {
+            let name = r.resolve_and_clone();
+            if is_symbol(&name) && from_str::<Primitive>(&name).is_err() {
+                Value::symbol(name)
+            } else {
+                Value::list(vec![Value::keyword("ref".to_string()), Value::string(name)])
+            }
         }
+        MirExpr::Comment(comment, body) => Value::list(vec![
+            Value::keyword("comment".to_string()),
+            Value::string(comment.clone()),
+            mir_to_lexpr(body),
+        ]),
+    }
+}
+
+/// Whether `name` reads back as a symbol with the same name, when it is followed by
+/// something else.
+fn is_symbol(name: &str) -> bool {
+    lexpr::from_str(&format!("({})", name)).ok() == Some(Value::list(vec![Value::symbol(name)]))
+}
+
+fn name_to_lexpr(name: MirInternedStr) -> Value {
+    let name = name.resolve_and_clone();
+    if is_symbol(&name) {
+        Value::symbol(name)
+    } else {
+        Value::string(name)
     }
 }
 
@@ -457,3 +758,142 @@ pub fn mir_to_lexpr(expr: &MirExpr) -> lexpr::Value {
 pub fn parse_mir(s: &str) -> Result<MirExpr, String> {
     lexpr_to_mir(lexpr::from_str(s).map_err(|e| format!("syntax error: {:?}", e))?)
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        mir_to_lexpr, parse_mir, MirExpr, MirInternedStr, MirLiteral, Primitive, LOAD_CODE, Y_CODE,
+    };
+    use proptest::prelude::*;
+
+    fn round_trip(expr: &MirExpr) -> String {
+        let text = mir_to_lexpr(expr).to_string();
+        assert_eq!(parse_mir(&text).as_ref(), Ok(expr), "{}", text);
+        text
+    }
+
+    #[test]
+    fn text_format() {
+        let assert_prints = |program: &str, expected: &str| {
+            assert_eq!(round_trip(&parse_mir(program).unwrap()), expected);
+        };
+        assert_prints(
+            "(#:lambda (x y) ((plus x) y))",
+            "(#:lambda (x y) (plus x y))",
+        );
+        assert_prints(
+            r#"(#:comment "a \"loop\"" (#:let (i 0) i))"#,
+            r#"(#:comment "a \"loop\"" (#:let (i 0) i))"#,
+        );
+        assert_prints(
+            "((#:frame 2) (then (#:get 0) (#:set 1)))",
+            "((#:frame 2) (then (#:get 0) (#:set 1)))",
+        );
+        // references which would read back as something else
+        assert_prints(
+            r#"(#:lambda ("y") (#:ref "y"))"#,
+            r#"(#:lambda (y) (#:ref "y"))"#,
+        );
+        assert_prints(
+            r#"(#:let ("a b" 1) (#:ref "a b"))"#,
+            r#"(#:let ("a b" 1) (#:ref "a b"))"#,
+        );
+        assert_prints("(get set)", "(get set)");
+        round_trip(&Y_CODE);
+        round_trip(&LOAD_CODE);
+        assert!(parse_mir("(#:get -1)").is_err());
+        assert!(parse_mir("(#:ref x)").is_err());
+    }
+
+    fn arb_name() -> impl Strategy<Value = MirInternedStr> {
+        prop_oneof![
+            prop::sample::select(vec![
+                "x", "y", "plus", "get", "_fp", "a b", "", "#t", "12", "-3", "(", "|x|", "#:let",
+                "\"", ";", ".",
+            ])
+            .prop_map(MirInternedStr::from),
+            "[a-z_][a-z0-9_-]{0,6}".prop_map(MirInternedStr::from),
+            any::<String>().prop_map(MirInternedStr::from),
+        ]
+    }
+
+    fn arb_primitive() -> impl Strategy<Value = Primitive> {
+        use Primitive::*;
+        prop_oneof![
+            prop::sample::select(vec![
+                Plus,
+                Minus,
+                Times,
+                Div,
+                Mod,
+                DivUnsigned,
+                ModUnsigned,
+                Truncate,
+                SignExtend,
+                Neg,
+                And,
+                Or,
+                Xor,
+                BitAnd,
+                BitOr,
+                BitXor,
+                BitNot,
+                Shl,
+                Shr,
+                ShrUnsigned,
+                Cons,
+                Car,
+                Cdr,
+                Eq,
+                Lt,
+                Le,
+                Gt,
+                Ge,
+                BoolToInt,
+                PutChar,
+                GetChar,
+                IsNull,
+                Load,
+                Store,
+                Pure,
+                Lift,
+                Then,
+                Y,
+            ]),
+            any::<u32>().prop_map(|n| Get(n as usize)),
+            any::<u32>().prop_map(|n| Set(n as usize)),
+            any::<u32>().prop_map(|n| Frame(n as usize)),
+        ]
+    }
+
+    /// Arbitrary MIR expressions, with names that are hard to print.
+    pub(crate) fn arb_mir() -> impl Strategy<Value = MirExpr> {
+        let leaf = prop_oneof![
+            arb_primitive().prop_map(MirExpr::Primitive),
+            any::<i64>().prop_map(|i| MirExpr::literal(MirLiteral::Int(i))),
+            any::<bool>().prop_map(|b| MirExpr::literal(MirLiteral::Bool(b))),
+            Just(MirExpr::literal(MirLiteral::Null)),
+            arb_name().prop_map(MirExpr::Ref),
+        ];
+        leaf.prop_recursive(6, 64, 3, |inner| {
+            prop_oneof![
+                (arb_name(), inner.clone(), inner.clone())
+                    .prop_map(|(name, value, body)| MirExpr::let_(name, value, body)),
+                (arb_name(), inner.clone()).prop_map(|(arg, body)| MirExpr::lambda(arg, body)),
+                (inner.clone(), inner.clone(), inner.clone())
+                    .prop_map(|(c, t, e)| MirExpr::if_(c, t, e)),
+                (inner.clone(), inner.clone()).prop_map(|(func, arg)| MirExpr::apply(func, arg)),
+                (any::<String>(), inner)
+                    .prop_map(|(comment, body)| MirExpr::Comment(comment, Box::new(body))),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_round_trip(expr in arb_mir()) {
+            let text = mir_to_lexpr(&expr).to_string();
+            prop_assert_eq!(parse_mir(&text), Ok(expr), "{}", text);
+        }
+    }
+}
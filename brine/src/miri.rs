@@ -13,296 +13,839 @@
 // limitations under the License.
 
 //! ## Miri -- an explicit-CPS interpreter for MIR
+//!
+//! Before running an expression, miri resolves its names: every lambda lists the
+//! variables it captures from its surroundings, and every reference becomes either
+//! the argument of the innermost lambda or an index into its captures. A closure is
+//! then just its lambda and a vector of captured values, and looking up a variable
+//! is a single index.
+//!
+//! The interpreter keeps its continuations on the heap, so a program can recurse
+//! as deeply as it likes, and values like long lists are dropped in a loop. Resolving
+//! names still recurses over the expression, like brine's other passes over MIR, so
+//! the nesting of the program text itself is limited by the Rust stack.
+//!
+//! Running a program never panics: type errors, division by zero and programs which
+//! run for too long, recurse too deeply or hold on to too much memory stop with a
+//! [`MiriError`]. Errors come with
+//! the comments around the code which failed; as comments are resolved like names,
+//! code in a closure is in the comments around the lambda, wherever it is called from.
 
-use crate::mir::{Apply, If, MirExpr, MirInternedStr, MirLiteral, Primitive};
-use itertools::Itertools;
-use saltwater_parser::InternedStr;
+use crate::mir::{MirExpr, MirInternedStr, MirLiteral, Primitive};
+use std::cell::Cell;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io::{Read, Write};
 use std::rc::Rc;
 
 /// A Mir runtime object
 #[derive(Debug, Clone)]
-pub enum Obj<'a> {
+pub enum Obj {
     Bool(bool),
     Int(i64),
     Null,
-    Lambda(Box<Lambda<'a>>),
-    CurriedPrimitive(CurriedPrimitive<'a>),
-    Cons(Rc<Obj<'a>>, Rc<Obj<'a>>),
+    Lambda(Lambda),
+    CurriedPrimitive(CurriedPrimitive),
+    Cons(Rc<Obj>, Rc<Obj>),
 }
 
-impl<'a> PartialEq for Obj<'a> {
+impl PartialEq for Obj {
     fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Obj::Bool(a), Obj::Bool(b)) => a == b,
-            (Obj::Int(a), Obj::Int(b)) => a == b,
-            (Obj::Null, Obj::Null) => true,
-            (Obj::Cons(a_car, a_cdr), Obj::Cons(b_car, b_cdr)) => a_car == b_car && a_cdr == b_cdr,
-            _ => false,
+        let (mut a, mut b) = (self, other);
+        // follow the tails of lists in a loop, so long lists don't overflow the stack
+        loop {
+            return match (a, b) {
+                (Obj::Bool(x), Obj::Bool(y)) => x == y,
+                (Obj::Int(x), Obj::Int(y)) => x == y,
+                (Obj::Null, Obj::Null) => true,
+                (Obj::Cons(a_car, a_cdr), Obj::Cons(b_car, b_cdr)) if a_car == b_car => {
+                    a = a_cdr;
+                    b = b_cdr;
+                    continue;
+                }
+                _ => false,
+            };
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Lambda<'a> {
-    env: RcEnv<'a>,
-    arg: MirInternedStr,
-    body: &'a MirExpr,
+thread_local! {
+    /// The number of pairs and closures made by running programs on this thread
+    /// which are still alive, for [`Limits::memory`].
+    static LIVE_OBJECTS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn live_objects() -> usize {
+    LIVE_OBJECTS.with(Cell::get)
+}
+
+/// Allocate an object made by a running program.
+fn allocate(obj: Obj) -> Rc<Obj> {
+    if let Obj::Cons(..) | Obj::Lambda(_) = obj {
+        LIVE_OBJECTS.with(|live| live.set(live.get() + 1));
+    }
+    Rc::new(obj)
+}
+
+// Lists and chains of closures can be very long, so don't drop them recursively.
+impl Drop for Obj {
+    fn drop(&mut self) {
+        if let Obj::Cons(..) | Obj::Lambda(_) = self {
+            // copies of objects weren't counted when they were made
+            LIVE_OBJECTS.with(|live| live.set(live.get().saturating_sub(1)));
+        }
+        let mut placeholder = None;
+        let mut owned = Vec::new();
+        self.take_unique(&mut placeholder, &mut owned);
+        while let Some(mut obj) = owned.pop() {
+            obj.take_unique(&mut placeholder, &mut owned);
+        }
+    }
+}
+
+impl Obj {
+    /// Move the objects which only `self` refers to into `owned`, leaving a shared
+    /// null object in their place.
+    fn take_unique(&mut self, placeholder: &mut Option<Rc<Obj>>, owned: &mut Vec<Obj>) {
+        let mut take = |child: &mut Rc<Obj>| {
+            if Rc::strong_count(child) == 1 {
+                let null = placeholder.get_or_insert_with(|| Rc::new(Obj::Null));
+                let child = std::mem::replace(child, null.clone());
+                if let Ok(obj) = Rc::try_unwrap(child) {
+                    owned.push(obj);
+                }
+            }
+        };
+        match self {
+            Obj::Cons(car, cdr) => {
+                take(car);
+                take(cdr);
+            }
+            Obj::Lambda(l) => {
+                if let Some(captured) = Rc::get_mut(&mut l.captured) {
+                    captured.iter_mut().for_each(take);
+                }
+            }
+            Obj::CurriedPrimitive(p) => p.args.iter_mut().for_each(take),
+            Obj::Bool(_) | Obj::Int(_) | Obj::Null => {}
+        }
+    }
+}
+
+/// Prints objects like MIR literals, with lists in Lisp style.
+impl Display for Obj {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Obj::Bool(true) => write!(f, "#t"),
+            Obj::Bool(false) => write!(f, "#f"),
+            Obj::Int(i) => write!(f, "{}", i),
+            Obj::Null => write!(f, "()"),
+            Obj::Lambda(l) => write!(f, "#<lambda {}>", l.code.arg),
+            Obj::CurriedPrimitive(p) => write!(f, "#<primitive {}>", primitive_name(p.primitive)),
+            Obj::Cons(car, cdr) => {
+                write!(f, "({}", car)?;
+                let mut rest = cdr;
+                while let Obj::Cons(car, cdr) = &**rest {
+                    write!(f, " {}", car)?;
+                    rest = cdr;
+                }
+                match &**rest {
+                    Obj::Null => write!(f, ")"),
+                    last => write!(f, " . {})", last),
+                }
+            }
+        }
+    }
+}
+
+/// A closure: a lambda, and the values of the variables it captures.
+#[derive(Clone)]
+pub struct Lambda {
+    code: Rc<LambdaCode>,
+    captured: Rc<[Rc<Obj>]>,
+}
+
+impl Debug for Lambda {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Lambda({})", self.code.arg)
+    }
 }
 
 #[derive(Debug, Clone)]
-pub struct CurriedPrimitive<'a> {
+pub struct CurriedPrimitive {
     primitive: Primitive,
-    args: Vec<Rc<Obj<'a>>>,
+    args: Vec<Rc<Obj>>,
 }
 
-#[derive(Debug, Clone)]
-enum Continuation<'a> {
-    Eval {
-        expr: &'a MirExpr,
-        environment: RcEnv<'a>,
-    },
+/// A MIR expression whose names have been resolved.
+#[derive(Debug)]
+enum Code {
+    Lambda(Rc<LambdaCode>),
+    If(Rc<Code>, Rc<Code>, Rc<Code>),
+    Apply(Rc<Code>, Rc<Code>),
+    /// A literal or a primitive, which evaluates to the same object every time
+    Const(Rc<Obj>),
+    Var(Var),
+    /// A low-level primitive applied to all of its arguments, which are constants
+    /// or variables
+    Call(Primitive, Vec<Rc<Code>>),
+    /// A reference to a name which isn't bound; evaluating it is an error
+    Undefined(MirInternedStr),
+    /// Code in a comment, which is innermost in the context
+    Comment(Rc<Context>, Rc<Code>),
+}
+
+impl Code {
+    /// Whether evaluating the expression needs no continuation.
+    fn is_immediate(&self) -> bool {
+        !matches!(self, Code::If(..) | Code::Apply(..) | Code::Comment(..))
+    }
+
+    /// Whether evaluating the expression just looks it up.
+    fn is_leaf(&self) -> bool {
+        matches!(self, Code::Const(_) | Code::Var(_) | Code::Undefined(_))
+    }
+}
+
+#[derive(Debug)]
+struct LambdaCode {
+    /// The name of the argument, for debugging
+    arg: MirInternedStr,
+    /// Where to find each captured variable when the closure is created
+    captures: Vec<Var>,
+    body: Rc<Code>,
+    /// The comments around the lambda
+    context: Option<Rc<Context>>,
+}
+
+/// The comments around some code, innermost first.
+#[derive(Debug)]
+struct Context {
+    comment: String,
+    outer: Option<Rc<Context>>,
+}
+
+/// The comments in `context`, outermost first.
+fn comments(mut context: &Option<Rc<Context>>) -> Vec<String> {
+    let mut comments = Vec::new();
+    while let Some(c) = context {
+        comments.push(c.comment.clone());
+        context = &c.outer;
+    }
+    comments.reverse();
+    comments
+}
+
+/// Where to find a variable in a frame.
+#[derive(Debug, Clone, Copy)]
+enum Var {
+    /// The argument of the innermost lambda
+    Arg,
+    /// A variable captured by the innermost lambda
+    Captured(usize),
+}
+
+/// The variables visible while running the body of a lambda.
+struct Frame {
+    arg: Rc<Obj>,
+    captured: Rc<[Rc<Obj>]>,
+}
+
+type Env = Rc<Frame>;
+
+impl Frame {
+    fn get(&self, var: Var) -> Rc<Obj> {
+        match var {
+            Var::Arg => self.arg.clone(),
+            Var::Captured(i) => self.captured[i].clone(),
+        }
+    }
+}
+
+/// A lambda being resolved.
+struct Scope {
+    /// `None` at the top level, which has no argument
+    arg: Option<MirInternedStr>,
+    /// The names of the captured variables, and where to find them in the enclosing frame
+    captured: Vec<(MirInternedStr, Var)>,
+}
+
+#[derive(Default)]
+struct Resolver {
+    /// The enclosing lambdas, innermost last
+    scopes: Vec<Scope>,
+    /// The enclosing comments
+    context: Option<Rc<Context>>,
+}
+
+impl Resolver {
+    fn resolve(&mut self, expr: &MirExpr) -> Rc<Code> {
+        Rc::new(match expr {
+            // `(let (x value) body)` is `((lambda (x) body) value)`
+            MirExpr::Let(l) => Code::Apply(
+                self.resolve_lambda(l.ident, &l.body),
+                self.resolve(&l.value),
+            ),
+            MirExpr::Lambda(l) => return self.resolve_lambda(l.arg, &l.body),
+            MirExpr::If(if_) => Code::If(
+                self.resolve(&if_.condition),
+                self.resolve(&if_.consequent),
+                self.resolve(&if_.alternative),
+            ),
+            MirExpr::Apply(_) => return self.resolve_apply(expr),
+            MirExpr::Primitive(p) => {
+                Code::Const(Rc::new(Obj::CurriedPrimitive(CurriedPrimitive {
+                    primitive: *p,
+                    args: vec![],
+                })))
+            }
+            MirExpr::Literal(l) => Code::Const(Rc::new(match **l {
+                MirLiteral::Bool(b) => Obj::Bool(b),
+                MirLiteral::Int(i) => Obj::Int(i),
+                MirLiteral::Null => Obj::Null,
+            })),
+            MirExpr::Ref(name) => match self.lookup(*name, self.scopes.len()) {
+                Some(var) => Code::Var(var),
+                None => Code::Undefined(*name),
+            },
+            MirExpr::Comment(comment, body) => {
+                let context = Rc::new(Context {
+                    comment: comment.clone(),
+                    outer: self.context.clone(),
+                });
+                let outer = self.context.replace(context.clone());
+                let body = self.resolve(body);
+                self.context = outer;
+                Code::Comment(context, body)
+            }
+        })
+    }
+
+    /// Resolve a chain of applications, as a single call if it applies a primitive to
+    /// all of its arguments.
+    fn resolve_apply(&mut self, expr: &MirExpr) -> Rc<Code> {
+        let mut args = Vec::new();
+        let mut head = expr;
+        while let MirExpr::Apply(ap) = head {
+            args.push(self.resolve(&ap.arg));
+            head = &ap.func;
+        }
+        args.reverse();
+        if let MirExpr::Primitive(p) = head {
+            let arity = parameter_types(*p).map(<[_]>::len);
+            if arity == Some(args.len()) && args.iter().all(|arg| arg.is_leaf()) {
+                return Rc::new(Code::Call(*p, args));
+            }
+        }
+        let head = self.resolve(head);
+        args.into_iter()
+            .fold(head, |func, arg| Rc::new(Code::Apply(func, arg)))
+    }
+
+    fn resolve_lambda(&mut self, arg: MirInternedStr, body: &MirExpr) -> Rc<Code> {
+        self.scopes.push(Scope {
+            arg: Some(arg),
+            captured: Vec::new(),
+        });
+        let body = self.resolve(body);
+        let scope = self.scopes.pop().unwrap();
+        Rc::new(Code::Lambda(Rc::new(LambdaCode {
+            arg,
+            captures: scope.captured.into_iter().map(|(_, var)| var).collect(),
+            body,
+            context: self.context.clone(),
+        })))
+    }
+
+    /// Find `name` in the frame of the `depth`th enclosing lambda, capturing it in that
+    /// lambda and all the ones in between if needed.
+    fn lookup(&mut self, name: MirInternedStr, depth: usize) -> Option<Var> {
+        if depth == 0 {
+            return None;
+        }
+        let scope = &self.scopes[depth - 1];
+        if scope.arg == Some(name) {
+            return Some(Var::Arg);
+        }
+        if let Some(i) = scope.captured.iter().position(|(n, _)| *n == name) {
+            return Some(Var::Captured(i));
+        }
+        let outer = self.lookup(name, depth - 1)?;
+        let captured = &mut self.scopes[depth - 1].captured;
+        captured.push((name, outer));
+        Some(Var::Captured(captured.len() - 1))
+    }
+}
+
+/// What to do with a value, and the comments around the code which does it.
+enum Continuation {
     If {
-        consequent: &'a MirExpr,
-        alternative: &'a MirExpr,
-        environment: RcEnv<'a>,
+        consequent: Rc<Code>,
+        alternative: Rc<Code>,
+        environment: Env,
+        context: Option<Rc<Context>>,
     },
     EvFun {
-        arg: &'a MirExpr,
-        environment: RcEnv<'a>,
+        arg: Rc<Code>,
+        environment: Env,
+        context: Option<Rc<Context>>,
     },
     Apply {
-        func: Rc<Obj<'a>>,
-        environment: RcEnv<'a>,
+        func: Rc<Obj>,
+        context: Option<Rc<Context>>,
     },
 }
 
-#[derive(Debug, Clone, Default)]
-struct Environment<'a> {
-    parent: Option<RcEnv<'a>>,
-    bindings: Vec<(MirInternedStr, Rc<Obj<'a>>)>,
+/// Why a program stopped before returning a value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MiriError {
+    /// A primitive got an argument of the wrong type, an `if` tested something which
+    /// isn't a bool, or something which isn't a function was applied
+    TypeMismatch {
+        /// Where the value was used, like "argument 2 of plus"
+        context: String,
+        expected: &'static str,
+        found: Obj,
+    },
+    /// A reference to a name which isn't bound was evaluated
+    UnboundName(MirInternedStr),
+    DivisionByZero,
+    /// A high-level primitive was applied, in a program which wasn't desugared
+    NotDesugared(Primitive),
+    /// The program ran for more than the allowed number of steps
+    StepLimit(u64),
+    /// The continuation stack grew deeper than allowed
+    DepthLimit(usize),
+    /// The program kept more pairs and closures alive than allowed
+    MemoryLimit(usize),
+    Io(String),
 }
 
-impl<'a> Environment<'a> {
-    fn show_bindings(&self) -> String {
-        let parent_binding = match &self.parent {
-            Some(p) => format!("{} → ", p.show_bindings()),
-            None => "".to_string(),
-        };
-        let self_bindings = self.bindings.iter().map(|(s, o)| s).join(", ");
-        format!("{}[{}]", parent_binding, self_bindings)
+impl Display for MiriError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            MiriError::TypeMismatch {
+                context,
+                expected,
+                found,
+            } => write!(f, "{} should be {}, not {}", context, expected, found),
+            MiriError::UnboundName(name) => write!(f, "reference to undefined name {}", name),
+            MiriError::DivisionByZero => write!(f, "division by zero"),
+            MiriError::NotDesugared(p) => write!(
+                f,
+                "primitive {} should have been desugared",
+                primitive_name(*p)
+            ),
+            MiriError::StepLimit(steps) => {
+                write!(f, "program ran for more than {} steps", steps)
+            }
+            MiriError::DepthLimit(depth) => {
+                write!(f, "program nested more than {} continuations deep", depth)
+            }
+            MiriError::MemoryLimit(objects) => {
+                write!(
+                    f,
+                    "program kept more than {} pairs and closures alive",
+                    objects
+                )
+            }
+            MiriError::Io(err) => write!(f, "i/o error: {}", err),
+        }
     }
 }
 
-#[derive(Debug, Clone)]
-struct RcEnv<'a>(Rc<Environment<'a>>);
-
-impl<'a> RcEnv<'a> {
-    fn find_value(&self, name: MirInternedStr) -> Option<Rc<Obj<'a>>> {
-        if let Some(b) = self.0.bindings.iter().find(|b| b.0 == name) {
-            Some(b.1.clone())
-        } else if let Some(p) = &self.0.parent {
-            p.find_value(name)
-        } else {
-            None
+/// A runtime error, with the comments around the code which caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub kind: MiriError,
+    /// The comments around the code which failed, outermost first
+    pub context: Vec<String>,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for comment in &self.context {
+            write!(f, "{}: ", comment)?;
         }
+        write!(f, "{}", self.kind)
     }
+}
 
-    fn with_value<'b: 'a>(self, name: MirInternedStr, value: Rc<Obj<'b>>) -> RcEnv<'a> {
-        RcEnv(Rc::new(Environment {
-            parent: Some(RcEnv(self.0.clone())),
-            bindings: vec![(name, value)],
-        }))
+impl From<std::io::Error> for MiriError {
+    fn from(err: std::io::Error) -> Self {
+        MiriError::Io(err.to_string())
     }
+}
+
+/// How far a program may run before miri stops it. The default has no limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The number of steps a program may take, where a step evaluates an expression
+    /// or resumes a continuation
+    pub steps: Option<u64>,
+    /// How deep the continuation stack may grow, which bounds how deeply a program
+    /// can recurse
+    pub depth: Option<usize>,
+    /// How many pairs and closures a program may keep alive at once, which bounds the
+    /// memory it uses for its values
+    pub memory: Option<usize>,
+}
+
+/// Where `put-char` writes and `get-char` reads.
+struct Io<'b> {
+    input: &'b mut dyn Read,
+    output: &'b mut dyn Write,
+}
 
-    fn show_bindings(&self) -> String {
-        self.0.show_bindings()
+/// Run `expr`, with stdin and stdout as its input and output.
+pub fn run(expr: &MirExpr) -> Result<Obj, Error> {
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let result = run_with_io(expr, &mut std::io::stdin(), &mut stdout);
+    stdout.flush().map_err(|err| Error {
+        kind: err.into(),
+        context: Vec::new(),
+    })?;
+    result
+}
+
+pub fn run_with_io(
+    expr: &MirExpr,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<Obj, Error> {
+    run_with_globals(expr, &[], input, output)
+}
+
+/// Run `expr` with each of `globals` bound to its value.
+pub fn run_with_globals(
+    expr: &MirExpr,
+    globals: &[(MirInternedStr, Obj)],
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<Obj, Error> {
+    run_with_limits(expr, globals, Limits::default(), input, output)
+}
+
+/// Run `expr` with each of `globals` bound to its value, stopping it if it goes over
+/// `limits`.
+pub fn run_with_limits(
+    expr: &MirExpr,
+    globals: &[(MirInternedStr, Obj)],
+    limits: Limits,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<Obj, Error> {
+    let mut io = Io { input, output };
+    // the top level captures the globals; there is no frame around it, so the
+    // `Var`s saying where to capture them from are never used
+    let mut resolver = Resolver::default();
+    resolver.scopes.push(Scope {
+        arg: None,
+        captured: globals.iter().map(|(name, _)| (*name, Var::Arg)).collect(),
+    });
+    let code = resolver.resolve(expr);
+    let captured: Vec<_> = globals
+        .iter()
+        .map(|(_, obj)| Rc::new(obj.clone()))
+        .collect();
+    let top_level = Rc::new(Frame {
+        arg: Rc::new(Obj::Null),
+        captured: captured.into(),
+    });
+    let mut context = None;
+    match eval(code, top_level, limits, &mut io, &mut context) {
+        Ok(value) => Ok(Rc::try_unwrap(value).unwrap_or_else(|value| (*value).clone())),
+        Err(kind) => Err(Error {
+            kind,
+            context: comments(&context),
+        }),
     }
 }
 
-pub fn run(expr: &MirExpr) -> Result<Obj, String> {
-    let top_level = RcEnv(Rc::new(Environment::default()));
+/// Evaluate `code`, keeping `context` up to date with the comments around the code
+/// being evaluated, so that they are known if it fails.
+fn eval(
+    code: Rc<Code>,
+    top_level: Env,
+    limits: Limits,
+    io: &mut Io,
+    context: &mut Option<Rc<Context>>,
+) -> Result<Rc<Obj>, MiriError> {
     let mut stack = Vec::new();
-    stack.push(Continuation::Eval {
-        expr,
-        environment: top_level,
-    });
+    // the expression to evaluate next, or `None` to return `value` to the top of the stack
+    let mut next = Some((code, top_level));
     let mut value = Rc::new(Obj::Null);
-    while let Some(cont) = stack.pop() {
-        match cont {
-            Continuation::Eval { expr, environment } => {
-                eval(expr, environment, &mut stack, &mut value)?;
+    let mut steps = 0;
+    let live_before = live_objects();
+    loop {
+        if let Some(max) = limits.steps {
+            if steps == max {
+                return Err(MiriError::StepLimit(max));
+            }
+        }
+        steps += 1;
+        if let Some(max) = limits.depth {
+            if stack.len() > max {
+                return Err(MiriError::DepthLimit(max));
             }
-            Continuation::If {
+        }
+        if let Some(max) = limits.memory {
+            if live_objects().saturating_sub(live_before) > max {
+                return Err(MiriError::MemoryLimit(max));
+            }
+        }
+        if let Some((code, environment)) = next.take() {
+            match &*code {
+                Code::If(condition, consequent, alternative) => {
+                    stack.push(Continuation::If {
+                        consequent: consequent.clone(),
+                        alternative: alternative.clone(),
+                        environment: environment.clone(),
+                        context: context.clone(),
+                    });
+                    next = Some((condition.clone(), environment));
+                    continue;
+                }
+                // skip the continuations for whatever can be evaluated right away
+                Code::Apply(func, arg) if func.is_immediate() => {
+                    let func = eval_immediate(func, &environment, io)?;
+                    if arg.is_immediate() {
+                        let arg = eval_immediate(arg, &environment, io)?;
+                        match apply(&func, arg, io)? {
+                            Applied::Value(v) => value = v,
+                            Applied::Eval(lambda, environment) => {
+                                *context = lambda.context.clone();
+                                next = Some((lambda.body.clone(), environment));
+                                continue;
+                            }
+                        }
+                    } else {
+                        stack.push(Continuation::Apply {
+                            func,
+                            context: context.clone(),
+                        });
+                        next = Some((arg.clone(), environment));
+                        continue;
+                    }
+                }
+                Code::Apply(func, arg) => {
+                    stack.push(Continuation::EvFun {
+                        arg: arg.clone(),
+                        environment: environment.clone(),
+                        context: context.clone(),
+                    });
+                    next = Some((func.clone(), environment));
+                    continue;
+                }
+                Code::Comment(inner, body) => {
+                    *context = Some(inner.clone());
+                    next = Some((body.clone(), environment));
+                    continue;
+                }
+                _ => value = eval_immediate(&code, &environment, io)?,
+            }
+        }
+        match stack.pop() {
+            None => break,
+            Some(Continuation::If {
                 consequent,
                 alternative,
                 environment,
-            } => {
+                context: outer,
+            }) => {
+                *context = outer;
                 let condition = match *value {
                     Obj::Bool(t) => t,
-                    _ => return Err(format!("expected a bool value, got {:?}", value.clone())),
+                    _ => {
+                        return Err(MiriError::TypeMismatch {
+                            context: "the condition of an if".to_string(),
+                            expected: ObjType::Bool.name(),
+                            found: Obj::clone(&value),
+                        })
+                    }
                 };
-                stack.push(Continuation::Eval {
-                    expr: if condition { consequent } else { alternative },
+                next = Some((
+                    if condition { consequent } else { alternative },
                     environment,
-                })
+                ));
             }
-            Continuation::EvFun { arg, environment } => {
+            Some(Continuation::EvFun {
+                arg,
+                environment,
+                context: outer,
+            }) => {
+                *context = outer;
                 stack.push(Continuation::Apply {
                     func: value.clone(),
-                    environment: environment.clone(),
-                });
-                stack.push(Continuation::Eval {
-                    expr: arg,
-                    environment,
+                    context: context.clone(),
                 });
+                next = Some((arg, environment));
             }
-            Continuation::Apply { func, environment } => match &*func.clone() {
-                Obj::Lambda(l) => {
-                    let new_env = l.env.clone().with_value(l.arg, value.clone());
-                    stack.push(Continuation::Eval {
-                        expr: l.body,
-                        environment: new_env,
-                    })
+            Some(Continuation::Apply {
+                func,
+                context: outer,
+            }) => {
+                *context = outer;
+                match apply(&func, value.clone(), io)? {
+                    Applied::Value(v) => value = v,
+                    Applied::Eval(lambda, environment) => {
+                        *context = lambda.context.clone();
+                        next = Some((lambda.body.clone(), environment));
+                    }
                 }
-                Obj::CurriedPrimitive(p) => value = apply_primitive(p, value)?,
-                _ => return Err(format!("cannot apply {:?}", func)),
-            },
+            }
         }
     }
-    Ok(Rc::try_unwrap(value).unwrap())
-}
-
-fn eval<'a, 'b>(
-    expr: &'a MirExpr,
-    environment: RcEnv<'a>,
-    stack: &'b mut Vec<Continuation<'a>>,
-    value: &'b mut Rc<Obj<'a>>,
-) -> Result<(), String> {
-    match expr {
-        MirExpr::Lambda(l) => {
-            *value = Rc::new(Obj::Lambda(Box::new(Lambda {
-                env: environment,
-                arg: l.arg,
-                body: &l.body,
-            })));
-        }
-        MirExpr::If(if_) => {
-            let If {
-                condition,
-                consequent,
-                alternative,
-            } = &**if_;
-            stack.push(Continuation::If {
-                consequent,
-                alternative,
-                environment: environment.clone(),
-            });
-            stack.push(Continuation::Eval {
-                expr: condition,
-                environment,
-            });
-        }
-        MirExpr::Apply(ap) => {
-            let Apply { func, arg } = &**ap;
-            stack.push(Continuation::EvFun {
+    Ok(value)
+}
+
+/// What happens after applying a function.
+enum Applied {
+    /// It returned a value
+    Value(Rc<Obj>),
+    /// It evaluates the body of a lambda
+    Eval(Rc<LambdaCode>, Env),
+}
+
+fn apply(func: &Rc<Obj>, arg: Rc<Obj>, io: &mut Io) -> Result<Applied, MiriError> {
+    match &**func {
+        Obj::Lambda(l) => {
+            let frame = Frame {
                 arg,
-                environment: environment.clone(),
-            });
-            stack.push(Continuation::Eval {
-                expr: func,
-                environment,
-            });
+                captured: l.captured.clone(),
+            };
+            Ok(Applied::Eval(l.code.clone(), Rc::new(frame)))
         }
-        MirExpr::Primitive(sp) => {
-            *value = Rc::new(Obj::CurriedPrimitive(CurriedPrimitive {
-                primitive: *sp,
-                args: vec![],
+        Obj::CurriedPrimitive(p) => Ok(Applied::Value(apply_primitive(p, arg, io)?)),
+        _ => Err(MiriError::TypeMismatch {
+            context: "an applied value".to_string(),
+            expected: "a function",
+            found: Obj::clone(func),
+        }),
+    }
+}
+
+/// Evaluate an expression which needs no continuation: anything but an `if` or
+/// an application.
+fn eval_immediate(code: &Code, environment: &Env, io: &mut Io) -> Result<Rc<Obj>, MiriError> {
+    Ok(match code {
+        Code::Lambda(l) => {
+            let captured: Vec<_> = l.captures.iter().map(|&var| environment.get(var)).collect();
+            allocate(Obj::Lambda(Lambda {
+                code: l.clone(),
+                captured: captured.into(),
             }))
         }
-        MirExpr::Literal(l) => {
-            *value = Rc::new(match &**l {
-                MirLiteral::Bool(b) => Obj::Bool(*b),
-                MirLiteral::Int(i) => Obj::Int(*i),
-                MirLiteral::Null => Obj::Null,
-            });
-        }
-        MirExpr::Ref(name) => {
-            if let Some(v) = environment.find_value(*name).clone() {
-                *value = v;
-            } else {
-                return Err(format!(
-                    "reference to undefined name {} in {:?}",
-                    name,
-                    environment.show_bindings()
-                ));
+        Code::Const(obj) => obj.clone(),
+        Code::Var(var) => environment.get(*var),
+        Code::Call(primitive, args) => {
+            let expected_args = parameter_types(*primitive).unwrap();
+            let mut values = Vec::with_capacity(args.len());
+            for (i, (arg, &expected_type)) in args.iter().zip(expected_args).enumerate() {
+                let value = eval_immediate(arg, environment, io)?;
+                check_arg(*primitive, i, expected_type, &value)?;
+                values.push(value);
             }
+            return call_primitive(*primitive, &values, io);
         }
-        _ => unimplemented!("found {:?}, which should be gone after desugaring", expr),
-    }
-    Ok(())
+        Code::Undefined(name) => return Err(MiriError::UnboundName(*name)),
+        Code::If(..) | Code::Apply(..) | Code::Comment(..) => {
+            unreachable!("{:?} needs a continuation", code)
+        }
+    })
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 enum ObjType {
     Bool,
     Int,
-    Null,
-    Lambda,
-    CurriedPrimitive,
     Cons,
     Any,
 }
 
 impl ObjType {
-    fn check_type<'a>(&self, obj: &Obj<'a>) -> bool {
-        match (self, obj) {
-            (ObjType::Any, _) => true,
-            (ObjType::Bool, Obj::Bool(_)) => true,
-            (ObjType::Int, Obj::Int(_)) => true,
-            (ObjType::Null, Obj::Null) => true,
-            (ObjType::Lambda, Obj::Lambda(_)) => true,
-            (ObjType::CurriedPrimitive, Obj::CurriedPrimitive(_)) => true,
-            (ObjType::Cons, Obj::Cons(_, _)) => true,
-            _ => false,
+    fn check_type(&self, obj: &Obj) -> bool {
+        matches!(
+            (self, obj),
+            (ObjType::Any, _)
+                | (ObjType::Bool, Obj::Bool(_))
+                | (ObjType::Int, Obj::Int(_))
+                | (ObjType::Cons, Obj::Cons(_, _))
+        )
+    }
+
+    /// How error messages describe values of this type.
+    fn name(self) -> &'static str {
+        match self {
+            ObjType::Bool => "a bool",
+            ObjType::Int => "an int",
+            ObjType::Cons => "a pair",
+            ObjType::Any => "anything",
         }
     }
 }
 
-fn get_int(obj: &Obj) -> i64 {
-    match obj {
-        Obj::Int(i) => *i,
-        _ => panic!("expected int, got {:?}", obj),
+fn primitive_name(primitive: Primitive) -> String {
+    serde_lexpr::to_string(&primitive).unwrap()
+}
+
+/// The error for argument `index` of `primitive` not having the type it expects.
+fn mismatch(primitive: Primitive, index: usize, expected_type: ObjType, arg: &Obj) -> MiriError {
+    MiriError::TypeMismatch {
+        context: format!("argument {} of {}", index + 1, primitive_name(primitive)),
+        expected: expected_type.name(),
+        found: arg.clone(),
     }
 }
 
-fn get_bool(obj: &Obj) -> bool {
-    match obj {
-        Obj::Bool(b) => *b,
-        _ => panic!("expected bool, got {:?}", obj),
+fn get_int(primitive: Primitive, args: &[Rc<Obj>], index: usize) -> Result<i64, MiriError> {
+    match &*args[index] {
+        Obj::Int(i) => Ok(*i),
+        other => Err(mismatch(primitive, index, ObjType::Int, other)),
     }
 }
 
-fn get_pair<'a, 'b>(obj: &'a Obj<'b>) -> (Rc<Obj<'b>>, Rc<Obj<'b>>) {
-    match obj {
-        Obj::Cons(car, cdr) => (car.clone(), cdr.clone()),
-        _ => panic!("expected pair, got {:?}", obj),
+fn get_bool(primitive: Primitive, args: &[Rc<Obj>], index: usize) -> Result<bool, MiriError> {
+    match &*args[index] {
+        Obj::Bool(b) => Ok(*b),
+        other => Err(mismatch(primitive, index, ObjType::Bool, other)),
     }
 }
 
-fn apply_primitive<'a>(
-    prim: &CurriedPrimitive<'a>,
-    arg: Rc<Obj<'a>>,
-) -> Result<Rc<Obj<'a>>, String> {
-    let expected_args = match prim.primitive {
+fn get_pair(
+    primitive: Primitive,
+    args: &[Rc<Obj>],
+    index: usize,
+) -> Result<(Rc<Obj>, Rc<Obj>), MiriError> {
+    match &*args[index] {
+        Obj::Cons(car, cdr) => Ok((car.clone(), cdr.clone())),
+        other => Err(mismatch(primitive, index, ObjType::Cons, other)),
+    }
+}
+
+/// The types of the arguments of a low-level primitive, or `None` for high-level ones.
+fn parameter_types(primitive: Primitive) -> Option<&'static [ObjType]> {
+    Some(match primitive {
         Primitive::Plus => &[ObjType::Int, ObjType::Int][..],
         Primitive::Minus => &[ObjType::Int, ObjType::Int][..],
         Primitive::Times => &[ObjType::Int, ObjType::Int][..],
         Primitive::Div => &[ObjType::Int, ObjType::Int][..],
         Primitive::Mod => &[ObjType::Int, ObjType::Int][..],
+        Primitive::DivUnsigned => &[ObjType::Int, ObjType::Int][..],
+        Primitive::ModUnsigned => &[ObjType::Int, ObjType::Int][..],
+        Primitive::Truncate => &[ObjType::Int, ObjType::Int][..],
+        Primitive::SignExtend => &[ObjType::Int, ObjType::Int][..],
         Primitive::Neg => &[ObjType::Bool][..],
         Primitive::And => &[ObjType::Bool, ObjType::Bool][..],
         Primitive::Or => &[ObjType::Bool, ObjType::Bool][..],
         Primitive::Xor => &[ObjType::Bool, ObjType::Bool][..],
+        Primitive::BitAnd => &[ObjType::Int, ObjType::Int][..],
+        Primitive::BitOr => &[ObjType::Int, ObjType::Int][..],
+        Primitive::BitXor => &[ObjType::Int, ObjType::Int][..],
+        Primitive::BitNot => &[ObjType::Int][..],
+        Primitive::Shl => &[ObjType::Int, ObjType::Int][..],
+        Primitive::Shr => &[ObjType::Int, ObjType::Int][..],
+        Primitive::ShrUnsigned => &[ObjType::Int, ObjType::Int][..],
         Primitive::Cons => &[ObjType::Any, ObjType::Any][..],
         Primitive::Car => &[ObjType::Cons][..],
         Primitive::Cdr => &[ObjType::Cons][..],
@@ -312,17 +855,36 @@ fn apply_primitive<'a>(
         Primitive::Lt => &[ObjType::Int, ObjType::Int][..],
         Primitive::Le => &[ObjType::Int, ObjType::Int][..],
         Primitive::BoolToInt => &[ObjType::Bool][..],
-        p => panic!("got primitive {:?}, which should have been desugared", p),
-    };
-    let mut args = prim.args.clone();
-    let arg_pos = prim.args.len();
-    let expected_type = expected_args[arg_pos];
-    if !expected_type.check_type(&*arg) {
-        return Err(format!(
-            "primitive {:?}: expected type {:?}, but got {:?}",
-            prim.primitive, expected_type, &arg
-        ));
+        Primitive::PutChar => &[ObjType::Int][..],
+        Primitive::GetChar => &[ObjType::Any][..],
+        Primitive::IsNull => &[ObjType::Any][..],
+        _ => return None,
+    })
+}
+
+fn check_arg(
+    primitive: Primitive,
+    index: usize,
+    expected_type: ObjType,
+    arg: &Rc<Obj>,
+) -> Result<(), MiriError> {
+    if expected_type.check_type(arg) {
+        Ok(())
+    } else {
+        Err(mismatch(primitive, index, expected_type, arg))
     }
+}
+
+fn apply_primitive(
+    prim: &CurriedPrimitive,
+    arg: Rc<Obj>,
+    io: &mut Io,
+) -> Result<Rc<Obj>, MiriError> {
+    let expected_args =
+        parameter_types(prim.primitive).ok_or(MiriError::NotDesugared(prim.primitive))?;
+    let index = prim.args.len();
+    check_arg(prim.primitive, index, expected_args[index], &arg)?;
+    let mut args = prim.args.clone();
     args.push(arg);
     if args.len() < expected_args.len() {
         return Ok(Rc::new(Obj::CurriedPrimitive(CurriedPrimitive {
@@ -330,34 +892,84 @@ fn apply_primitive<'a>(
             args,
         })));
     }
-    let val = match prim.primitive {
-        Primitive::Plus => Obj::Int(get_int(&*args[0]) + get_int(&*args[1])),
-        Primitive::Minus => Obj::Int(get_int(&*args[0]) - get_int(&*args[1])),
-        Primitive::Times => Obj::Int(get_int(&*args[0]) * get_int(&*args[1])),
-        Primitive::Div => Obj::Int(get_int(&*args[0]) / get_int(&*args[1])),
-        Primitive::Mod => Obj::Int(get_int(&*args[0]) % get_int(&*args[1])),
-        Primitive::Neg => Obj::Bool(!get_bool(&*args[0])),
-        Primitive::And => Obj::Bool(get_bool(&*args[0]) && get_bool(&*args[1])),
-        Primitive::Or => Obj::Bool(get_bool(&*args[0]) || get_bool(&*args[1])),
-        Primitive::Xor => Obj::Bool(get_bool(&*args[0]) == get_bool(&*args[1])),
+    call_primitive(prim.primitive, &args, io)
+}
+
+/// Apply a primitive to all of its arguments.
+fn call_primitive(
+    primitive: Primitive,
+    args: &[Rc<Obj>],
+    io: &mut Io,
+) -> Result<Rc<Obj>, MiriError> {
+    let int = |index| get_int(primitive, args, index);
+    let boolean = |index| get_bool(primitive, args, index);
+    let pair = |index| get_pair(primitive, args, index);
+    let val = match primitive {
+        // integers are 64 bits, and wrap around on overflow
+        Primitive::Plus => Obj::Int(int(0)?.wrapping_add(int(1)?)),
+        Primitive::Minus => Obj::Int(int(0)?.wrapping_sub(int(1)?)),
+        Primitive::Times => Obj::Int(int(0)?.wrapping_mul(int(1)?)),
+        Primitive::Div | Primitive::Mod | Primitive::DivUnsigned | Primitive::ModUnsigned
+            if int(1)? == 0 =>
+        {
+            return Err(MiriError::DivisionByZero)
+        }
+        Primitive::Div => Obj::Int(int(0)?.wrapping_div(int(1)?)),
+        Primitive::Mod => Obj::Int(int(0)?.wrapping_rem(int(1)?)),
+        Primitive::DivUnsigned => Obj::Int((int(0)? as u64 / int(1)? as u64) as i64),
+        Primitive::ModUnsigned => Obj::Int((int(0)? as u64 % int(1)? as u64) as i64),
+        Primitive::Truncate => Obj::Int(int(1)? & int(0)?),
+        Primitive::SignExtend => {
+            let (mask, x) = (int(0)?, int(1)?);
+            // the highest bit of the mask
+            let sign = mask & !(mask >> 1);
+            Obj::Int(if x & sign == 0 { x & mask } else { x | !mask })
+        }
+        Primitive::Neg => Obj::Bool(!boolean(0)?),
+        Primitive::And => Obj::Bool(boolean(0)? && boolean(1)?),
+        Primitive::Or => Obj::Bool(boolean(0)? || boolean(1)?),
+        Primitive::Xor => Obj::Bool(boolean(0)? != boolean(1)?),
+        Primitive::BitAnd => Obj::Int(int(0)? & int(1)?),
+        Primitive::BitOr => Obj::Int(int(0)? | int(1)?),
+        Primitive::BitXor => Obj::Int(int(0)? ^ int(1)?),
+        Primitive::BitNot => Obj::Int(!int(0)?),
+        // `wrapping_shl` and `wrapping_shr` only use the low 6 bits of the amount
+        Primitive::Shl => Obj::Int(int(0)?.wrapping_shl(int(1)? as u32)),
+        Primitive::Shr => Obj::Int(int(0)?.wrapping_shr(int(1)? as u32)),
+        Primitive::ShrUnsigned => Obj::Int((int(0)? as u64).wrapping_shr(int(1)? as u32) as i64),
         Primitive::Cons => Obj::Cons(args[0].clone(), args[1].clone()),
-        Primitive::Car => Obj::clone(&*get_pair(&*args[0]).0),
-        Primitive::Cdr => Obj::clone(&*get_pair(&*args[0]).1),
-        Primitive::Eq => Obj::Bool(get_int(&*args[0]) == get_int(&*args[1])),
-        Primitive::Lt => Obj::Bool(get_int(&*args[0]) < get_int(&*args[1])),
-        Primitive::Le => Obj::Bool(get_int(&*args[0]) <= get_int(&*args[1])),
-        Primitive::Gt => Obj::Bool(get_int(&*args[0]) > get_int(&*args[1])),
-        Primitive::Ge => Obj::Bool(get_int(&*args[0]) >= get_int(&*args[1])),
-        Primitive::BoolToInt => Obj::Int(i64::from(get_bool(&*args[0]))),
-        p => panic!("got primitive {:?}, which should have been desugared", p),
+        Primitive::Car => Obj::clone(&pair(0)?.0),
+        Primitive::Cdr => Obj::clone(&pair(0)?.1),
+        Primitive::Eq => Obj::Bool(int(0)? == int(1)?),
+        Primitive::Lt => Obj::Bool(int(0)? < int(1)?),
+        Primitive::Le => Obj::Bool(int(0)? <= int(1)?),
+        Primitive::Gt => Obj::Bool(int(0)? > int(1)?),
+        Primitive::Ge => Obj::Bool(int(0)? >= int(1)?),
+        Primitive::BoolToInt => Obj::Int(i64::from(boolean(0)?)),
+        Primitive::PutChar => {
+            let byte = int(0)? as u8;
+            io.output.write_all(&[byte])?;
+            Obj::Int(i64::from(byte))
+        }
+        Primitive::GetChar => {
+            let mut byte = [0];
+            match io.input.read(&mut byte)? {
+                0 => Obj::Int(-1),
+                _ => Obj::Int(i64::from(byte[0])),
+            }
+        }
+        Primitive::IsNull => Obj::Bool(*args[0] == Obj::Null),
+        p => return Err(MiriError::NotDesugared(p)),
     };
-    Ok(Rc::new(val))
+    Ok(allocate(val))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{run, Obj};
-    use crate::mir::{mir_to_lexpr, parse_mir};
+    use super::{run, run_with_globals, run_with_limits, Limits, MiriError, Obj};
+    use crate::mir::tests::arb_mir;
+    use crate::mir::{parse_mir, Primitive};
+    use proptest::prelude::*;
 
     #[test]
     fn fib() {
@@ -375,4 +987,210 @@ mod tests {
             Obj::Int(120)
         );
     }
+
+    #[test]
+    fn scopes() {
+        let eval = |program| run(&parse_mir(program).unwrap()).unwrap();
+        assert_eq!(eval("((#:lambda (x x) x) 1 2)"), Obj::Int(2));
+        assert_eq!(
+            eval("(#:let (x 1) (#:let (f (#:lambda (z) (plus x z))) (#:let (x 10) (f x))))"),
+            Obj::Int(11)
+        );
+        // `a` is captured through two lambdas which don't use it themselves
+        assert_eq!(
+            eval("(((#:lambda (a b c) (#:lambda (d) (minus a d))) 5 6 7) 1)"),
+            Obj::Int(4)
+        );
+        // undefined names are only an error if they are evaluated
+        assert_eq!(eval("(#:if #t 1 undefined)"), Obj::Int(1));
+        assert!(run(&parse_mir("(plus 1 undefined)").unwrap()).is_err());
+    }
+
+    #[test]
+    fn globals() {
+        let inc = run(&parse_mir("(plus 1)").unwrap()).unwrap();
+        let globals = [("inc".into(), inc), ("x".into(), Obj::Int(2))];
+        let program = parse_mir("((#:lambda (n) (inc (inc n))) x)").unwrap();
+        let result = run_with_globals(&program, &globals, &mut std::io::empty(), &mut Vec::new());
+        assert_eq!(result, Ok(Obj::Int(4)));
+    }
+
+    #[test]
+    fn display() {
+        let eval = |program| run(&parse_mir(program).unwrap()).unwrap().to_string();
+        assert_eq!(eval("(cons 1 (cons #t (cons () ())))"), "(1 #t ())");
+        assert_eq!(eval("(cons (cons 1 2) #f)"), "((1 . 2) . #f)");
+        assert_eq!(eval("(#:lambda (x) x)"), "#<lambda x>");
+        assert_eq!(eval("(div-unsigned 1)"), "#<primitive div-unsigned>");
+    }
+
+    #[test]
+    fn deep_recursion() {
+        // the interpreter keeps its stack on the heap, so deep non-tail recursion is fine
+        assert_eq!(
+            run(&parse_mir(
+                "((y (#:lambda (sum n) (#:if (eq n 0) 0 (plus n (sum (minus n 1)))))) 100000)"
+            )
+            .unwrap()
+            .desugar())
+            .unwrap(),
+            Obj::Int(5_000_050_000)
+        );
+    }
+
+    #[test]
+    fn long_values() {
+        let eval = |program| run(&parse_mir(program).unwrap().desugar()).unwrap();
+        // neither dropping nor comparing a long list recurses
+        let list = eval(
+            "((y (#:lambda (build n acc) (#:if (eq n 0) acc (build (minus n 1) (cons n acc)))))
+              200000 ())",
+        );
+        assert_eq!(list, list.clone());
+        drop(list);
+        // and neither does dropping a long chain of closures
+        eval(
+            "((y (#:lambda (build n acc) (#:if (eq n 0) acc (build (minus n 1) (#:lambda (x) acc)))))
+              200000 ())",
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let eval = |program| run(&parse_mir(program).unwrap()).unwrap_err().kind;
+        let mismatch = |context: &str, expected, found| MiriError::TypeMismatch {
+            context: context.to_string(),
+            expected,
+            found,
+        };
+        assert_eq!(
+            eval("(plus 1 #t)"),
+            mismatch("argument 2 of plus", "an int", Obj::Bool(true))
+        );
+        assert_eq!(
+            eval("((#:lambda (n) (car n)) 1)"),
+            mismatch("argument 1 of car", "a pair", Obj::Int(1))
+        );
+        assert_eq!(
+            eval("(#:if 0 1 2)"),
+            mismatch("the condition of an if", "a bool", Obj::Int(0))
+        );
+        assert_eq!(
+            eval("(1 2)"),
+            mismatch("an applied value", "a function", Obj::Int(1))
+        );
+        assert_eq!(eval("(plus n 1)"), MiriError::UnboundName("n".into()));
+        assert_eq!(
+            eval("((#:lambda (n) (div n 0)) 1)"),
+            MiriError::DivisionByZero
+        );
+        assert_eq!(
+            eval("((#:get 0) ())"),
+            MiriError::NotDesugared(Primitive::Get(0))
+        );
+        assert_eq!(
+            eval("(neg 1)").to_string(),
+            "argument 1 of neg should be a bool, not 1"
+        );
+    }
+
+    #[test]
+    fn context() {
+        let context = |program| run(&parse_mir(program).unwrap()).unwrap_err().context;
+        let err = run(&parse_mir("(#:comment \"a\" (#:comment \"b\" (car 1)))").unwrap());
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "a: b: argument 1 of car should be a pair, not 1"
+        );
+        // the comments around a lambda apply to its body, wherever it is called
+        assert_eq!(
+            context(
+                "(#:let (f (#:comment \"in f\" (#:lambda (n) (div 1 n))))
+                   (#:comment \"calling f\" (f 0)))"
+            ),
+            vec!["in f"]
+        );
+        // and the comments around a call apply once it returns
+        assert_eq!(
+            context(
+                "(#:let (f (#:comment \"in f\" (#:lambda (n) n)))
+                   (#:comment \"calling f\" (plus (f 1) #t)))"
+            ),
+            vec!["calling f"]
+        );
+        assert_eq!(
+            context("(#:if (#:comment \"a\" 1) 2 3)"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let eval = |program, limits| {
+            let expr = parse_mir(program).unwrap().desugar();
+            run_with_limits(&expr, &[], limits, &mut std::io::empty(), &mut Vec::new())
+                .map_err(|err| err.kind)
+        };
+        let forever = "((y (#:lambda (f n) (f n))) 0)";
+        let steps = Limits {
+            steps: Some(10000),
+            ..Limits::default()
+        };
+        assert_eq!(eval(forever, steps), Err(MiriError::StepLimit(10000)));
+        let sum = "((y (#:lambda (sum n) (#:if (eq n 0) 0 (plus n (sum (minus n 1)))))) 1000)";
+        let shallow = Limits {
+            depth: Some(100),
+            ..Limits::default()
+        };
+        assert_eq!(eval(sum, shallow), Err(MiriError::DepthLimit(100)));
+        let enough = Limits {
+            steps: Some(1_000_000),
+            depth: Some(10000),
+            memory: Some(10000),
+        };
+        assert_eq!(eval(sum, enough), Ok(Obj::Int(500_500)));
+        // the list grows forever, but every step of the loop also makes closures
+        // which are dropped right away
+        let grow = "((y (#:lambda (grow l) (grow (cons 0 l)))) ())";
+        let small = Limits {
+            memory: Some(1000),
+            ..Limits::default()
+        };
+        assert_eq!(eval(grow, small), Err(MiriError::MemoryLimit(1000)));
+        let count = "((y (#:lambda (count n) (#:if (eq n 0) 0 (count (minus n 1))))) 100000)";
+        assert_eq!(eval(count, small), Ok(Obj::Int(0)));
+    }
+
+    #[test]
+    fn overflow() {
+        let eval = |program| match run(&parse_mir(program).unwrap().desugar()) {
+            Ok(Obj::Int(i)) => Ok(i),
+            Ok(other) => panic!("{} returned {:?}", program, other),
+            Err(err) => Err(err),
+        };
+        assert_eq!(eval("(plus 9223372036854775807 1)"), Ok(i64::MIN));
+        assert_eq!(eval("(div -9223372036854775808 -1)"), Ok(i64::MIN));
+        assert_eq!(eval("(div-unsigned -2 2)"), Ok(i64::MAX));
+        assert_eq!(eval("(sign-extend 255 383)"), Ok(127));
+        assert_eq!(eval("(sign-extend 255 128)"), Ok(-128));
+        assert_eq!(eval("(truncate 65535 -1)"), Ok(65535));
+        assert!(eval("(mod 1 0)").is_err());
+        assert_eq!(eval("(shl 1 65)"), Ok(2));
+        assert_eq!(eval("(shr -8 1)"), Ok(-4));
+        assert_eq!(eval("(shr-unsigned -1 60)"), Ok(15));
+    }
+
+    proptest! {
+        #[test]
+        fn proptest_no_panic(expr in arb_mir()) {
+            // arbitrary programs may fail, but only with an error
+            let limits = Limits {
+                steps: Some(10000),
+                depth: Some(1000),
+                memory: Some(10000),
+            };
+            let mut input: &[u8] = b"input";
+            run_with_limits(&expr, &[], limits, &mut input, &mut std::io::sink()).ok();
+        }
+    }
 }
@@ -0,0 +1,692 @@
+// Copyright 2020 Matthieu Felix
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! ## Optimization passes
+//!
+//! Rewrites of MIR which keep its meaning, but make it smaller and faster to run.
+//! They are meant to run after desugaring, where every `let` has become an applied
+//! lambda, but also understand `let`.
+//!
+//! - Beta reduction replaces `((lambda (x) body) arg)` by `body` with `arg` substituted
+//!   for `x`, if that doesn't duplicate work or code: `arg` must be a reference or a
+//!   boolean or null literal, or be [pure](is_pure) and used only once, outside of any
+//!   lambda.
+//! - Constant folding evaluates primitives applied to literals, and `if`s on literals.
+//! - Dead-let elimination removes bindings which are never used, if their value is pure.
+//! - Eta reduction replaces `(lambda (x) (f x))` by `f`, if `f` is a value which doesn't
+//!   use `x`. `f` has to be a value since MIR is strict: the eta-expansion in the Y
+//!   combinator is what keeps it from looping forever.
+//!
+//! The passes assume the program is well-typed, so a primitive never fails because
+//! of the type of its arguments. They run bottom-up, over and over, until the
+//! expression stops changing.
+
+use crate::mir::{Apply, If, Lambda, Let, MirExpr, MirInternedStr, MirLiteral, Primitive};
+use crate::miri::{run_with_io, Obj};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// Stop optimizing after this many rounds, even if the expression still changes.
+const MAX_ROUNDS: usize = 64;
+
+/// Which optimization passes to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Passes {
+    pub beta: bool,
+    pub fold: bool,
+    pub dead_let: bool,
+    pub eta: bool,
+}
+
+impl Passes {
+    pub fn none() -> Passes {
+        Passes {
+            beta: false,
+            fold: false,
+            dead_let: false,
+            eta: false,
+        }
+    }
+}
+
+impl Default for Passes {
+    fn default() -> Passes {
+        Passes {
+            beta: true,
+            fold: true,
+            dead_let: true,
+            eta: true,
+        }
+    }
+}
+
+/// Parse a comma-separated list of passes, like `beta,fold`, or `all` or `none`.
+impl FromStr for Passes {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Passes, String> {
+        match s {
+            "all" => return Ok(Passes::default()),
+            "none" => return Ok(Passes::none()),
+            _ => {}
+        }
+        let mut passes = Passes::none();
+        for name in s.split(',') {
+            match name {
+                "beta" => passes.beta = true,
+                "fold" => passes.fold = true,
+                "dead-let" => passes.dead_let = true,
+                "eta" => passes.eta = true,
+                _ => return Err(format!("unknown optimization pass: {}", name)),
+            }
+        }
+        Ok(passes)
+    }
+}
+
+/// Run the enabled `passes` on `expr`.
+pub fn optimize(expr: MirExpr, passes: Passes) -> MirExpr {
+    let mut used_names = HashSet::new();
+    collect_names(&expr, &mut used_names);
+    let mut optimizer = Optimizer {
+        passes,
+        used_names,
+        fresh_counter: 0,
+        changed: false,
+    };
+    let mut expr = expr;
+    for _ in 0..MAX_ROUNDS {
+        optimizer.changed = false;
+        expr = optimizer.rewrite(expr);
+        if !optimizer.changed {
+            break;
+        }
+    }
+    expr
+}
+
+struct Optimizer {
+    passes: Passes,
+    /// Every name in the original expression, so fresh names can avoid them
+    used_names: HashSet<MirInternedStr>,
+    fresh_counter: usize,
+    /// Whether the current round rewrote anything
+    changed: bool,
+}
+
+impl Optimizer {
+    fn fresh_name(&mut self, base: MirInternedStr) -> MirInternedStr {
+        loop {
+            let name = format!("{}_{}", base, self.fresh_counter);
+            self.fresh_counter += 1;
+            let name = MirInternedStr::get_or_intern(name);
+            if self.used_names.insert(name) {
+                return name;
+            }
+        }
+    }
+
+    /// Rewrite the children of `expr`, then `expr` itself.
+    fn rewrite(&mut self, expr: MirExpr) -> MirExpr {
+        match expr {
+            MirExpr::Let(let_) => {
+                let Let { ident, value, body } = *let_;
+                let value = self.rewrite(value);
+                let body = self.rewrite(body);
+                match self.reduce(ident, value, body) {
+                    Ok(reduced) => reduced,
+                    Err((ident, value, body)) => MirExpr::let_(ident, value, body),
+                }
+            }
+            MirExpr::Lambda(lambda) => {
+                let Lambda { arg, body } = *lambda;
+                let body = self.rewrite(body);
+                self.eta_reduce(arg, body)
+            }
+            MirExpr::If(if_) => {
+                let If {
+                    condition,
+                    consequent,
+                    alternative,
+                } = *if_;
+                let condition = self.rewrite(condition);
+                let consequent = self.rewrite(consequent);
+                let alternative = self.rewrite(alternative);
+                if self.passes.fold {
+                    if let Some(MirLiteral::Bool(b)) = as_literal(&condition) {
+                        self.changed = true;
+                        return if *b { consequent } else { alternative };
+                    }
+                }
+                MirExpr::if_(condition, consequent, alternative)
+            }
+            MirExpr::Apply(ap) => {
+                let Apply { func, arg } = *ap;
+                let func = self.rewrite(func);
+                let arg = self.rewrite(arg);
+                let (func, arg) = match func {
+                    MirExpr::Lambda(lambda) => {
+                        let Lambda { arg: ident, body } = *lambda;
+                        match self.reduce(ident, arg, body) {
+                            Ok(reduced) => return reduced,
+                            Err((ident, arg, body)) => (MirExpr::lambda(ident, body), arg),
+                        }
+                    }
+                    func => (func, arg),
+                };
+                let applied = MirExpr::apply(func, arg);
+                if self.passes.fold {
+                    if let Some(folded) = fold_primitive(&applied) {
+                        self.changed = true;
+                        return folded;
+                    }
+                }
+                applied
+            }
+            MirExpr::Comment(comment, body) => {
+                MirExpr::Comment(comment, Box::new(self.rewrite(*body)))
+            }
+            MirExpr::Primitive(_) | MirExpr::Literal(_) | MirExpr::Ref(_) => expr,
+        }
+    }
+
+    /// Remove the binding of `ident` to `value` in `body`, either by dead-let elimination
+    /// or beta reduction, or give them back if neither applies.
+    fn reduce(
+        &mut self,
+        ident: MirInternedStr,
+        value: MirExpr,
+        body: MirExpr,
+    ) -> Result<MirExpr, (MirInternedStr, MirExpr, MirExpr)> {
+        let uses = count_uses(ident, &body);
+        if uses.count == 0 && self.passes.dead_let && is_pure(&value) {
+            self.changed = true;
+            return Ok(body);
+        }
+        let substitutable = match &value {
+            // integers are large once compiled to combinators, so don't copy them
+            MirExpr::Literal(literal) if !matches!(**literal, MirLiteral::Int(_)) => true,
+            MirExpr::Ref(_) => true,
+            // moving values into lambdas makes bracket abstraction produce more code
+            _ => uses.count == 1 && !uses.under_lambda && is_pure(&value),
+        };
+        if uses.count > 0 && self.passes.beta && substitutable {
+            self.changed = true;
+            let mut free = HashSet::new();
+            free_variables(&value, &mut HashSet::new(), &mut free);
+            return Ok(self.substitute(body, ident, &value, &free));
+        }
+        Err((ident, value, body))
+    }
+
+    /// `(lambda (x) (f x))` is `f`, if `f` is a value that doesn't use `x`.
+    fn eta_reduce(&mut self, arg: MirInternedStr, body: MirExpr) -> MirExpr {
+        if self.passes.eta {
+            if let MirExpr::Apply(ap) = &body {
+                let is_arg = matches!(&ap.arg, MirExpr::Ref(r) if *r == arg);
+                if is_arg && is_value(&ap.func) && count_uses(arg, &ap.func).count == 0 {
+                    self.changed = true;
+                    let Apply { func, .. } = match body {
+                        MirExpr::Apply(ap) => *ap,
+                        _ => unreachable!(),
+                    };
+                    return func;
+                }
+            }
+        }
+        MirExpr::lambda(arg, body)
+    }
+
+    /// Replace the free occurrences of `name` in `expr` by `value`, whose free
+    /// variables are `free`. Binders which would capture a free variable of
+    /// `value` are renamed.
+    fn substitute(
+        &mut self,
+        expr: MirExpr,
+        name: MirInternedStr,
+        value: &MirExpr,
+        free: &HashSet<MirInternedStr>,
+    ) -> MirExpr {
+        match expr {
+            MirExpr::Ref(r) if r == name => value.clone(),
+            MirExpr::Lambda(lambda) => {
+                let Lambda { arg, body } = *lambda;
+                let (arg, body) = self.substitute_under(arg, body, name, value, free);
+                MirExpr::lambda(arg, body)
+            }
+            MirExpr::Let(let_) => {
+                let Let {
+                    ident,
+                    value: bound,
+                    body,
+                } = *let_;
+                let bound = self.substitute(bound, name, value, free);
+                let (ident, body) = self.substitute_under(ident, body, name, value, free);
+                MirExpr::let_(ident, bound, body)
+            }
+            MirExpr::If(if_) => {
+                let If {
+                    condition,
+                    consequent,
+                    alternative,
+                } = *if_;
+                MirExpr::if_(
+                    self.substitute(condition, name, value, free),
+                    self.substitute(consequent, name, value, free),
+                    self.substitute(alternative, name, value, free),
+                )
+            }
+            MirExpr::Apply(ap) => {
+                let Apply { func, arg } = *ap;
+                MirExpr::apply(
+                    self.substitute(func, name, value, free),
+                    self.substitute(arg, name, value, free),
+                )
+            }
+            MirExpr::Comment(comment, body) => {
+                MirExpr::Comment(comment, Box::new(self.substitute(*body, name, value, free)))
+            }
+            MirExpr::Primitive(_) | MirExpr::Literal(_) | MirExpr::Ref(_) => expr,
+        }
+    }
+
+    /// Substitute in `body`, where `binder` is bound.
+    fn substitute_under(
+        &mut self,
+        binder: MirInternedStr,
+        body: MirExpr,
+        name: MirInternedStr,
+        value: &MirExpr,
+        free: &HashSet<MirInternedStr>,
+    ) -> (MirInternedStr, MirExpr) {
+        if binder == name {
+            return (binder, body);
+        }
+        if free.contains(&binder) && count_uses(name, &body).count > 0 {
+            let renamed = self.fresh_name(binder);
+            let mut renamed_free = HashSet::new();
+            renamed_free.insert(renamed);
+            let body = self.substitute(body, binder, &MirExpr::Ref(renamed), &renamed_free);
+            return (renamed, self.substitute(body, name, value, free));
+        }
+        (binder, self.substitute(body, name, value, free))
+    }
+}
+
+/// How a variable is used in an expression.
+#[derive(Debug, Default)]
+struct Uses {
+    count: usize,
+    /// Whether any use is inside a lambda, where it could be evaluated many times
+    under_lambda: bool,
+}
+
+fn count_uses(name: MirInternedStr, expr: &MirExpr) -> Uses {
+    fn walk(name: MirInternedStr, expr: &MirExpr, in_lambda: bool, uses: &mut Uses) {
+        match expr {
+            MirExpr::Ref(r) if *r == name => {
+                uses.count += 1;
+                uses.under_lambda |= in_lambda;
+            }
+            MirExpr::Lambda(l) if l.arg != name => walk(name, &l.body, true, uses),
+            MirExpr::Let(l) => {
+                walk(name, &l.value, in_lambda, uses);
+                if l.ident != name {
+                    walk(name, &l.body, in_lambda, uses);
+                }
+            }
+            MirExpr::If(if_) => {
+                walk(name, &if_.condition, in_lambda, uses);
+                walk(name, &if_.consequent, in_lambda, uses);
+                walk(name, &if_.alternative, in_lambda, uses);
+            }
+            MirExpr::Apply(ap) => {
+                walk(name, &ap.func, in_lambda, uses);
+                walk(name, &ap.arg, in_lambda, uses);
+            }
+            MirExpr::Comment(_, body) => walk(name, body, in_lambda, uses),
+            MirExpr::Lambda(_) | MirExpr::Ref(_) | MirExpr::Primitive(_) | MirExpr::Literal(_) => {}
+        }
+    }
+    let mut uses = Uses::default();
+    walk(name, expr, false, &mut uses);
+    uses
+}
+
+fn free_variables(
+    expr: &MirExpr,
+    bound: &mut HashSet<MirInternedStr>,
+    free: &mut HashSet<MirInternedStr>,
+) {
+    // only add a binder to `bound` if it wasn't already there, so that removing it again
+    // after its scope doesn't unbind an outer variable with the same name
+    fn under(
+        binder: MirInternedStr,
+        body: &MirExpr,
+        bound: &mut HashSet<MirInternedStr>,
+        free: &mut HashSet<MirInternedStr>,
+    ) {
+        let new = bound.insert(binder);
+        free_variables(body, bound, free);
+        if new {
+            bound.remove(&binder);
+        }
+    }
+    match expr {
+        MirExpr::Ref(r) => {
+            if !bound.contains(r) {
+                free.insert(*r);
+            }
+        }
+        MirExpr::Lambda(l) => under(l.arg, &l.body, bound, free),
+        MirExpr::Let(l) => {
+            free_variables(&l.value, bound, free);
+            under(l.ident, &l.body, bound, free);
+        }
+        MirExpr::If(if_) => {
+            free_variables(&if_.condition, bound, free);
+            free_variables(&if_.consequent, bound, free);
+            free_variables(&if_.alternative, bound, free);
+        }
+        MirExpr::Apply(ap) => {
+            free_variables(&ap.func, bound, free);
+            free_variables(&ap.arg, bound, free);
+        }
+        MirExpr::Comment(_, body) => free_variables(body, bound, free),
+        MirExpr::Primitive(_) | MirExpr::Literal(_) => {}
+    }
+}
+
+fn collect_names(expr: &MirExpr, names: &mut HashSet<MirInternedStr>) {
+    match expr {
+        MirExpr::Ref(r) => {
+            names.insert(*r);
+        }
+        MirExpr::Lambda(l) => {
+            names.insert(l.arg);
+            collect_names(&l.body, names);
+        }
+        MirExpr::Let(l) => {
+            names.insert(l.ident);
+            collect_names(&l.value, names);
+            collect_names(&l.body, names);
+        }
+        MirExpr::If(if_) => {
+            collect_names(&if_.condition, names);
+            collect_names(&if_.consequent, names);
+            collect_names(&if_.alternative, names);
+        }
+        MirExpr::Apply(ap) => {
+            collect_names(&ap.func, names);
+            collect_names(&ap.arg, names);
+        }
+        MirExpr::Comment(_, body) => collect_names(body, names),
+        MirExpr::Primitive(_) | MirExpr::Literal(_) => {}
+    }
+}
+
+fn as_literal(expr: &MirExpr) -> Option<&MirLiteral> {
+    match expr {
+        MirExpr::Literal(literal) => Some(literal),
+        _ => None,
+    }
+}
+
+/// The primitive at the head of a chain of applications, and its arguments.
+fn primitive_application(expr: &MirExpr) -> Option<(Primitive, Vec<&MirExpr>)> {
+    let mut args = Vec::new();
+    let mut head = expr;
+    while let MirExpr::Apply(ap) = head {
+        args.push(&ap.arg);
+        head = &ap.func;
+    }
+    args.reverse();
+    match head {
+        MirExpr::Primitive(p) => Some((*p, args)),
+        _ => None,
+    }
+}
+
+/// The number of arguments of a low-level primitive, or `None` for high-level ones.
+fn arity(primitive: Primitive) -> Option<usize> {
+    use Primitive::*;
+    match primitive {
+        Neg | BitNot | Car | Cdr | BoolToInt | PutChar | GetChar | IsNull => Some(1),
+        Plus | Minus | Times | Div | Mod | DivUnsigned | ModUnsigned | Truncate | SignExtend
+        | And | Or | Xor | BitAnd | BitOr | BitXor | Shl | Shr | ShrUnsigned | Cons | Eq | Lt
+        | Le | Gt | Ge => Some(2),
+        Get(_) | Set(_) | Load | Store | Frame(_) | Pure | Lift | Then | Y => None,
+    }
+}
+
+/// Whether applying `primitive` to arguments of the right type always succeeds,
+/// and has no side effects.
+fn is_total(primitive: Primitive) -> bool {
+    use Primitive::*;
+    match primitive {
+        Plus | Minus | Times | Truncate | SignExtend | Neg | And | Or | Xor | BitAnd | BitOr
+        | BitXor | BitNot | Shl | Shr | ShrUnsigned | Cons | Eq | Lt | Le | Gt | Ge | BoolToInt
+        | IsNull => true,
+        // division by zero, taking the `car` of null, I/O
+        Div | Mod | DivUnsigned | ModUnsigned | Car | Cdr | PutChar | GetChar => false,
+        Get(_) | Set(_) | Load | Store | Frame(_) | Pure | Lift | Then | Y => false,
+    }
+}
+
+/// Whether evaluating `expr` does nothing but return it, or a closure over it:
+/// literals, references, lambdas, primitives, and primitives applied to fewer
+/// values than they take.
+fn is_value(expr: &MirExpr) -> bool {
+    match expr {
+        MirExpr::Literal(_) | MirExpr::Ref(_) | MirExpr::Lambda(_) | MirExpr::Primitive(_) => true,
+        MirExpr::Comment(_, body) => is_value(body),
+        MirExpr::Apply(_) => match primitive_application(expr) {
+            Some((p, args)) => {
+                arity(p).is_some_and(|n| args.len() < n) && args.into_iter().all(is_value)
+            }
+            None => false,
+        },
+        MirExpr::Let(_) | MirExpr::If(_) => false,
+    }
+}
+
+/// Whether evaluating `expr` always terminates and has no side effects, so it
+/// can be moved or dropped.
+pub(crate) fn is_pure(expr: &MirExpr) -> bool {
+    match expr {
+        MirExpr::Apply(_) => match primitive_application(expr) {
+            Some((p, args)) => {
+                let applicable = is_total(p) || arity(p).is_some_and(|n| args.len() < n);
+                applicable && args.into_iter().all(is_pure)
+            }
+            None => false,
+        },
+        MirExpr::If(if_) => {
+            is_pure(&if_.condition) && is_pure(&if_.consequent) && is_pure(&if_.alternative)
+        }
+        MirExpr::Let(l) => is_pure(&l.value) && is_pure(&l.body),
+        MirExpr::Comment(_, body) => is_pure(body),
+        _ => is_value(expr),
+    }
+}
+
+/// Evaluate a total primitive applied to all of its arguments, if they are literals.
+fn fold_primitive(expr: &MirExpr) -> Option<MirExpr> {
+    let (primitive, args) = primitive_application(expr)?;
+    if !is_total(primitive) || Some(args.len()) != arity(primitive) {
+        return None;
+    }
+    if !args.iter().all(|arg| as_literal(arg).is_some()) {
+        return None;
+    }
+    let literal = match run_with_io(expr, &mut std::io::empty(), &mut std::io::sink()) {
+        Ok(Obj::Int(i)) => MirLiteral::Int(i),
+        Ok(Obj::Bool(b)) => MirLiteral::Bool(b),
+        Ok(Obj::Null) => MirLiteral::Null,
+        // a type error, or a pair
+        _ => return None,
+    };
+    Some(MirExpr::literal(literal))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{optimize, Passes};
+    use crate::mir::{mir_to_lexpr, parse_mir, MirExpr};
+    use crate::miri::run_with_io;
+
+    /// The result of running `expr` in miri, with its output.
+    fn run(expr: &MirExpr, input: &[u8]) -> (String, Vec<u8>) {
+        let mut output = Vec::new();
+        let result = run_with_io(expr, &mut &*input, &mut output);
+        (format!("{:?}", result), output)
+    }
+
+    fn size(expr: &MirExpr) -> usize {
+        mir_to_lexpr(expr).to_string().len()
+    }
+
+    /// Check that the optimized program gives the same result and output as the
+    /// original, and is no bigger, with each pass on its own and with all of them.
+    fn assert_equivalent(program: &str, input: &[u8]) {
+        let original = parse_mir(program).unwrap().desugar();
+        let expected = run(&original, input);
+        let all_passes = [
+            Passes::default(),
+            "beta".parse().unwrap(),
+            "fold".parse().unwrap(),
+            "dead-let".parse().unwrap(),
+            "eta".parse().unwrap(),
+        ];
+        for &passes in &all_passes {
+            let optimized = optimize(original.clone(), passes);
+            assert_eq!(
+                run(&optimized, input),
+                expected,
+                "{:?} on {}",
+                passes,
+                program
+            );
+            assert!(
+                size(&optimized) <= size(&original),
+                "{:?} on {}",
+                passes,
+                program
+            );
+        }
+    }
+
+    fn assert_optimizes_to(program: &str, passes: &str, expected: &str) {
+        let passes = passes.parse().unwrap();
+        let optimized = optimize(parse_mir(program).unwrap().desugar(), passes);
+        assert_eq!(
+            mir_to_lexpr(&optimized).to_string(),
+            expected,
+            "{}",
+            program
+        );
+    }
+
+    #[test]
+    fn passes() {
+        assert_optimizes_to("(#:let (x 1) (plus x 2))", "all", "3");
+        assert_optimizes_to("((#:lambda (x z) (times x z)) 6 7)", "all", "42");
+        assert_optimizes_to("(#:if (lt 1 2) 3 (put-char 4))", "all", "3");
+        assert_optimizes_to("(#:lambda (x) (plus 1 x))", "all", "(plus 1)");
+        assert_optimizes_to(
+            "(#:lambda (s) (#:let (x (plus s 1)) (car x)))",
+            "all",
+            "(#:lambda (s) (car (plus s 1)))",
+        );
+        // only the enabled passes run
+        assert_optimizes_to("(#:let (x 1) (plus x 2))", "beta", "(plus 1 2)");
+        assert_optimizes_to("(#:let (x 1) (plus 3 2))", "fold", "((#:lambda (x) 5) 1)");
+        assert_optimizes_to("(#:let (x 1) (plus 3 2))", "dead-let", "(plus 3 2)");
+        assert_optimizes_to(
+            "(#:lambda (x) (plus 1 x))",
+            "none",
+            "(#:lambda (x) (plus 1 x))",
+        );
+        assert_eq!(
+            "beta,eta".parse(),
+            Ok(Passes {
+                beta: true,
+                fold: false,
+                dead_let: false,
+                eta: true
+            })
+        );
+        assert!("beta,gamma".parse::<Passes>().is_err());
+    }
+
+    #[test]
+    fn side_effects() {
+        // bindings which print, fail or loop forever stay
+        assert_optimizes_to(
+            "(#:let (x (put-char 65)) 1)",
+            "all",
+            "((#:lambda (x) 1) (put-char 65))",
+        );
+        assert_optimizes_to(
+            "(#:let (x (div 1 0)) 1)",
+            "all",
+            "((#:lambda (x) 1) (div 1 0))",
+        );
+        assert_optimizes_to(
+            "(#:lambda (f) (#:lambda (x) ((f f) x)))",
+            "all",
+            "(#:lambda (f x) (f f x))",
+        );
+        assert_equivalent(
+            "(#:let (c (get-char ())) (#:let (d (put-char c)) (plus c 1)))",
+            b"a",
+        );
+        assert_equivalent(
+            "(#:let (x (put-char 72)) (#:let (z (put-char 105)) z))",
+            b"",
+        );
+    }
+
+    #[test]
+    fn capture() {
+        // substituting `z` under the inner lambda must not capture it
+        assert_optimizes_to(
+            "(#:lambda (z) ((#:lambda (x) (#:lambda (z) (cons x z))) z))",
+            "beta",
+            "(#:lambda (z z_0) (cons z z_0))",
+        );
+        assert_equivalent(
+            "(#:let (f (#:lambda (x) (#:lambda (z) (minus x z)))) (#:let (z 10) ((f z) 3)))",
+            b"",
+        );
+    }
+
+    #[test]
+    fn equivalence() {
+        assert_equivalent(
+            "(#:let (fac (#:lambda (f n) (#:if (eq n 1) 1 (times n (f (minus n 1)))))) ((y fac) 10))",
+            b"",
+        );
+        assert_equivalent(
+            "(#:let (twice (#:lambda (f x) (f (f x)))) (twice (#:lambda (x) (shl x 2)) 3))",
+            b"",
+        );
+        // the building blocks of compiled C are stateful computations
+        assert_equivalent(
+            "(car ((then ((store 16) 5) (#:lambda (ignored)
+               (then (load 16) (#:lambda (x) (pure (plus x 1))))))
+              (cons () ())))",
+            b"",
+        );
+    }
+}
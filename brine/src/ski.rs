@@ -0,0 +1,430 @@
+// Copyright 2020 Matthieu Felix
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! ## SKI compilation
+//!
+//! Turns desugared MIR into a tree of combinators, by first translating it to
+//! a lambda term and then removing every abstraction with bracket abstraction.
+//! Literals and primitives are replaced with their encodings from [`crate::encoding`].
+//!
+//! The combinator program is evaluated eagerly, like MIR. The usual `[x] M = K M`
+//! rule (when `x` is not free in `M`) would evaluate `M` as soon as the abstraction
+//! is built rather than when it is applied, which breaks things like the eta-expansion
+//! in the Y combinator. We only use it when `M` is already a value (a combinator,
+//! a variable, or a partial application of `K` or `S` to values), and use `S` to
+//! delay evaluation of everything else.
+
+use crate::ast::{Combinator, SyntaxNode};
+use crate::encoding;
+use crate::mir::{Apply, If, Lambda, Let, MirExpr};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Compile a desugared MIR expression into a combinator tree containing no
+/// abstractions or references.
+pub fn compile(expr: &MirExpr) -> Result<SyntaxNode, String> {
+    let mut translator = Translator::default();
+    let term = translator.translate(expr)?;
+    let combinators = eliminate_abstractions(&term);
+    let closed = encoding::PRELUDE.with(|prelude| translator.link(&combinators, prelude))?;
+    Ok(closed.to_syntax())
+}
+
+/// An identifier, unique within a compilation.
+type VarId = usize;
+
+/// A lambda term which remembers its free variables, so bracket abstraction
+/// doesn't have to walk whole subtrees to find them.
+#[derive(Debug)]
+pub(crate) struct Term {
+    kind: TermKind,
+    /// Sorted
+    free: Vec<VarId>,
+    /// Whether evaluating the term is a no-op
+    value: bool,
+}
+
+#[derive(Debug)]
+enum TermKind {
+    Combinator(Combinator),
+    Var(VarId),
+    Application(Rc<Term>, Rc<Term>),
+    Abstraction(VarId, Rc<Term>),
+}
+
+impl Term {
+    pub(crate) fn combinator(c: Combinator) -> Rc<Term> {
+        Rc::new(Term {
+            kind: TermKind::Combinator(c),
+            free: Vec::new(),
+            value: true,
+        })
+    }
+
+    fn var(id: VarId) -> Rc<Term> {
+        Rc::new(Term {
+            kind: TermKind::Var(id),
+            free: vec![id],
+            value: true,
+        })
+    }
+
+    pub(crate) fn application(func: Rc<Term>, arg: Rc<Term>) -> Rc<Term> {
+        // partial applications of `K` and `S` don't do anything until they are saturated
+        let value = arg.value
+            && match &func.kind {
+                TermKind::Combinator(Combinator::K) | TermKind::Combinator(Combinator::S) => true,
+                TermKind::Application(f, x) => {
+                    x.value && matches!(f.kind, TermKind::Combinator(Combinator::S))
+                }
+                _ => false,
+            };
+        let mut free = func.free.clone();
+        free.extend(&arg.free);
+        free.sort_unstable();
+        free.dedup();
+        Rc::new(Term {
+            kind: TermKind::Application(func, arg),
+            free,
+            value,
+        })
+    }
+
+    fn abstraction(variable: VarId, body: Rc<Term>) -> Rc<Term> {
+        let free = body
+            .free
+            .iter()
+            .copied()
+            .filter(|&v| v != variable)
+            .collect();
+        Rc::new(Term {
+            kind: TermKind::Abstraction(variable, body),
+            free,
+            value: true,
+        })
+    }
+
+    fn has_free(&self, variable: VarId) -> bool {
+        self.free.binary_search(&variable).is_ok()
+    }
+
+    fn to_syntax(&self) -> SyntaxNode {
+        match &self.kind {
+            TermKind::Combinator(c) => SyntaxNode::Combinator(*c),
+            TermKind::Var(v) => SyntaxNode::Reference(format!("v{}", v)),
+            TermKind::Application(func, arg) => {
+                SyntaxNode::application(func.to_syntax(), arg.to_syntax())
+            }
+            TermKind::Abstraction(v, body) => {
+                SyntaxNode::abstraction(format!("v{}", v), body.to_syntax())
+            }
+        }
+    }
+}
+
+/// Translates MIR to lambda terms, renaming every bound variable to a fresh `VarId`.
+#[derive(Default)]
+struct Translator {
+    next_id: VarId,
+    scopes: Vec<(String, VarId)>,
+    /// Free variables, which must be definitions from the prelude
+    globals: HashMap<String, VarId>,
+}
+
+impl Translator {
+    fn fresh(&mut self) -> VarId {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn abstraction(&mut self, variable: String, body: &MirExpr) -> Result<Rc<Term>, String> {
+        let id = self.fresh();
+        self.scopes.push((variable, id));
+        let body = self.translate(body);
+        self.scopes.pop();
+        Ok(Term::abstraction(id, body?))
+    }
+
+    /// A function which evaluates `body` when applied to anything.
+    fn thunk(&mut self, body: &MirExpr) -> Result<Rc<Term>, String> {
+        let id = self.fresh();
+        Ok(Term::abstraction(id, self.translate(body)?))
+    }
+
+    fn reference(&mut self, name: String) -> Rc<Term> {
+        if let Some(&(_, id)) = self.scopes.iter().rev().find(|(n, _)| *n == name) {
+            return Term::var(id);
+        }
+        if let Some(c) = encoding::raw_combinator(&name) {
+            return Term::combinator(c);
+        }
+        if let Some(&id) = self.globals.get(&name) {
+            return Term::var(id);
+        }
+        let id = self.fresh();
+        self.globals.insert(name, id);
+        Term::var(id)
+    }
+
+    fn translate(&mut self, expr: &MirExpr) -> Result<Rc<Term>, String> {
+        Ok(match expr {
+            MirExpr::Lambda(l) => {
+                let Lambda { arg, body } = &**l;
+                self.abstraction(arg.resolve_and_clone(), body)?
+            }
+            MirExpr::Apply(ap) => {
+                let Apply { func, arg } = &**ap;
+                Term::application(self.translate(func)?, self.translate(arg)?)
+            }
+            MirExpr::Let(let_) => {
+                let Let { ident, value, body } = &**let_;
+                let value = self.translate(value)?;
+                Term::application(self.abstraction(ident.resolve_and_clone(), body)?, value)
+            }
+            // both branches are delayed, and the boolean picks which one to run
+            MirExpr::If(if_) => {
+                let If {
+                    condition,
+                    consequent,
+                    alternative,
+                } = &**if_;
+                let condition = self.translate(condition)?;
+                let consequent = self.thunk(consequent)?;
+                let alternative = self.thunk(alternative)?;
+                Term::application(
+                    Term::application(Term::application(condition, consequent), alternative),
+                    Term::combinator(Combinator::I),
+                )
+            }
+            MirExpr::Ref(name) => self.reference(name.resolve_and_clone()),
+            MirExpr::Comment(_, body) => self.translate(body)?,
+            MirExpr::Literal(l) => encoding::literal(l),
+            MirExpr::Primitive(p) => match encoding::primitive_name(*p) {
+                Some(name) => self.reference(name.to_string()),
+                None => {
+                    return Err(format!(
+                        "primitive {:?} should have been desugared before compiling to combinators",
+                        p
+                    ))
+                }
+            },
+        })
+    }
+
+    /// Replace references to the prelude in `term` by their definitions.
+    fn link(
+        &self,
+        term: &Rc<Term>,
+        prelude: &HashMap<String, Rc<Term>>,
+    ) -> Result<Rc<Term>, String> {
+        let mut definitions = HashMap::new();
+        for (name, &id) in &self.globals {
+            match prelude.get(name) {
+                Some(def) => definitions.insert(id, def.clone()),
+                None => return Err(format!("reference to undefined name {}", name)),
+            };
+        }
+        Ok(substitute(term, &definitions))
+    }
+}
+
+/// Compile a definition of the prelude, which may refer to earlier definitions.
+pub(crate) fn compile_definition(
+    expr: &MirExpr,
+    prelude: &HashMap<String, Rc<Term>>,
+) -> Result<Rc<Term>, String> {
+    let mut translator = Translator::default();
+    let term = eliminate_abstractions(&translator.translate(expr)?);
+    translator.link(&term, prelude)
+}
+
+fn substitute(term: &Rc<Term>, definitions: &HashMap<VarId, Rc<Term>>) -> Rc<Term> {
+    if term.free.is_empty() {
+        return term.clone();
+    }
+    match &term.kind {
+        TermKind::Var(v) => definitions.get(v).cloned().unwrap_or_else(|| term.clone()),
+        TermKind::Application(func, arg) => {
+            Term::application(substitute(func, definitions), substitute(arg, definitions))
+        }
+        TermKind::Abstraction(v, body) => Term::abstraction(*v, substitute(body, definitions)),
+        TermKind::Combinator(_) => term.clone(),
+    }
+}
+
+/// Remove all abstractions from `term`, innermost first.
+///
+/// References to variables bound outside of `term` are left in place.
+fn eliminate_abstractions(term: &Rc<Term>) -> Rc<Term> {
+    match &term.kind {
+        TermKind::Abstraction(variable, body) => bracket(*variable, &eliminate_abstractions(body)),
+        TermKind::Application(func, arg) => {
+            Term::application(eliminate_abstractions(func), eliminate_abstractions(arg))
+        }
+        TermKind::Combinator(_) | TermKind::Var(_) => term.clone(),
+    }
+}
+
+/// Bracket abstraction: returns a term `T` without `variable` such that `T x`
+/// behaves like `body` with `variable` bound to `x`.
+///
+/// `body` must not contain abstractions.
+fn bracket(variable: VarId, body: &Rc<Term>) -> Rc<Term> {
+    if body.value && !body.has_free(variable) {
+        return Term::application(Term::combinator(Combinator::K), body.clone());
+    }
+    match &body.kind {
+        TermKind::Var(_) => Term::combinator(Combinator::I),
+        TermKind::Application(func, arg) => match arg.kind {
+            // [x] (f x) = f, which is only safe when `f` is already a value
+            TermKind::Var(v) if v == variable && func.value && !func.has_free(variable) => {
+                func.clone()
+            }
+            _ => Term::application(
+                Term::application(Term::combinator(Combinator::S), bracket(variable, func)),
+                bracket(variable, arg),
+            ),
+        },
+        TermKind::Combinator(_) => unreachable!("combinators are values"),
+        TermKind::Abstraction(..) => unreachable!("inner abstractions are eliminated first"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compile;
+    use crate::ast::{Combinator, SyntaxNode};
+    use crate::mir::parse_mir;
+
+    fn compile_str(s: &str) -> Result<SyntaxNode, String> {
+        compile(&parse_mir(s).unwrap())
+    }
+
+    fn dot(c: char) -> SyntaxNode {
+        SyntaxNode::Combinator(Combinator::Dot(c))
+    }
+
+    /// Normal-order reduction of S, K and I, leaving other combinators inert.
+    fn reduce(node: SyntaxNode, fuel: &mut usize) -> SyntaxNode {
+        let mut head = node;
+        let mut args = Vec::new();
+        loop {
+            assert!(*fuel > 0, "term did not reduce to normal form");
+            *fuel -= 1;
+            match head {
+                SyntaxNode::Application(ap) => {
+                    args.push(ap.arg);
+                    head = ap.func;
+                }
+                SyntaxNode::Combinator(Combinator::I) if !args.is_empty() => {
+                    head = args.pop().unwrap();
+                }
+                SyntaxNode::Combinator(Combinator::K) if args.len() >= 2 => {
+                    head = args.pop().unwrap();
+                    args.pop();
+                }
+                SyntaxNode::Combinator(Combinator::S) if args.len() >= 3 => {
+                    let x = args.pop().unwrap();
+                    let y = args.pop().unwrap();
+                    let z = args.pop().unwrap();
+                    args.push(SyntaxNode::application(y, z.clone()));
+                    args.push(z);
+                    head = x;
+                }
+                _ => break,
+            }
+        }
+        args.into_iter().rev().fold(head, |func, arg| {
+            SyntaxNode::application(func, reduce(arg, fuel))
+        })
+    }
+
+    fn apply_all(func: SyntaxNode, args: &[char]) -> SyntaxNode {
+        let applied = args
+            .iter()
+            .fold(func, |func, &c| SyntaxNode::application(func, dot(c)));
+        reduce(applied, &mut 10_000)
+    }
+
+    fn is_value(node: &SyntaxNode) -> bool {
+        match node {
+            SyntaxNode::Application(ap) => {
+                is_value(&ap.arg)
+                    && match &ap.func {
+                        SyntaxNode::Combinator(Combinator::K)
+                        | SyntaxNode::Combinator(Combinator::S) => true,
+                        SyntaxNode::Application(inner) => {
+                            inner.func == SyntaxNode::Combinator(Combinator::S)
+                                && is_value(&inner.arg)
+                        }
+                        _ => false,
+                    }
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether a `K` is applied to something which would be evaluated eagerly.
+    fn has_eager_constant(node: &SyntaxNode) -> bool {
+        match node {
+            SyntaxNode::Application(ap) => {
+                let eager = ap.func == SyntaxNode::Combinator(Combinator::K) && !is_value(&ap.arg);
+                eager || has_eager_constant(&ap.func) || has_eager_constant(&ap.arg)
+            }
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn identity() {
+        assert_eq!(
+            compile_str("(#:lambda (x) x)"),
+            Ok(SyntaxNode::Combinator(Combinator::I))
+        );
+    }
+
+    #[test]
+    fn behaves_like_lambda() {
+        let first = compile_str("(#:lambda (u v) u)").unwrap();
+        assert_eq!(apply_all(first, &['a', 'b']), dot('a'));
+
+        let second = compile_str("(#:lambda (u v) v)").unwrap();
+        assert_eq!(apply_all(second, &['a', 'b']), dot('b'));
+
+        let s = compile_str("(#:lambda (u v w) ((u w) (v w)))").unwrap();
+        let expected = SyntaxNode::application(
+            SyntaxNode::application(dot('a'), dot('c')),
+            SyntaxNode::application(dot('b'), dot('c')),
+        );
+        assert_eq!(apply_all(s, &['a', 'b', 'c']), expected);
+
+        let flip = compile_str("(#:let (f (#:lambda (a b) (b a))) (#:lambda (x) (f x x)))");
+        let expected = SyntaxNode::application(dot('a'), dot('a'));
+        assert_eq!(apply_all(flip.unwrap(), &['a']), expected);
+    }
+
+    #[test]
+    fn no_eager_constants() {
+        let delayed = compile_str("(#:lambda (g x) ((g g) x))").unwrap();
+        assert!(!has_eager_constant(&delayed), "{:?}", delayed);
+        let applied = SyntaxNode::application(delayed, dot('a'));
+        assert!(!has_eager_constant(&applied));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(compile_str("(#:lambda (x) z)").is_err());
+        assert!(compile_str("(pure 1)").is_err());
+    }
+}
@@ -12,15 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::ast::SyntaxNode;
-use crate::cfg::Jump;
-use crate::create_res_lambda;
+use crate::cfg::{BlockId, Jump};
 use crate::expr::Value;
-use crate::mir::{MirExpr, MirLiteral, Primitive};
-use crate::Compiler;
+use crate::mir::{MirExpr, MirLiteral};
+use crate::{create_res_lambda, lift, Compiler};
 use saltwater_parser::data::hir::StmtType;
 use saltwater_parser::hir::{Expr, Stmt};
-use saltwater_parser::CompileResult;
+use saltwater_parser::{CompileResult, InternedStr, Location, SemanticError, Type};
+
+/// The cases seen so far in the body of a switch statement.
+#[derive(Debug, Default)]
+pub struct SwitchCases {
+    cases: Vec<(i64, BlockId)>,
+    default: Option<BlockId>,
+}
 
 impl Compiler {
     pub fn compile_all(&mut self, prev: Value, stmts: Vec<Stmt>) -> CompileResult<Value> {
@@ -28,50 +33,281 @@ impl Compiler {
         for stmt in stmts {
             v = self.compile_stmt(v, stmt)?;
         }
-        Ok(todo!())
+        Ok(v)
     }
 
     pub fn compile_stmt(&mut self, prev: Value, stmt: Stmt) -> CompileResult<Value> {
+        let location = stmt.location;
         match stmt.data {
             StmtType::Compound(stmts) => self.compile_all(prev, stmts),
             StmtType::Decl(decls) => {
+                let mut v = prev;
                 for decl in decls {
-                    self.declare_stack(decl.data, decl.location);
+                    v = self.declare_stack(v, decl.data, decl.location)?;
                 }
-                Ok(prev)
+                Ok(v)
             }
             StmtType::Return(expr) => {
                 let retval = if let Some(e) = expr {
-                    self.compile_expr(e)?.val
+                    self.compile_located(e)?
                 } else {
-                    MirExpr::literal(MirLiteral::Null)
+                    Value {
+                        val: MirExpr::literal(MirLiteral::Null),
+                        ctype: Type::Void,
+                        pure: true,
+                    }
+                };
+                let retval = self.seq(prev, retval);
+                Ok(self.terminate(retval, Jump::Jmp(self.return_block)))
+            }
+            StmtType::Expr(expr) => {
+                let expr = self.compile_discarded(expr)?;
+                let expr = self.locate(expr, location);
+                Ok(self.seq(prev, expr))
+            }
+            StmtType::If(condition, body, otherwise) => {
+                self.if_stmt(prev, condition, *body, otherwise)
+            }
+            StmtType::While(condition, body) => self.while_stmt(prev, condition, *body),
+            StmtType::Do(body, condition) => self.do_loop(prev, *body, condition),
+            StmtType::For(init, condition, post_loop, body) => {
+                let prev = self.compile_stmt(prev, *init)?;
+                self.for_loop(prev, condition.map(|e| *e), post_loop.map(|e| *e), *body)
+            }
+            StmtType::Break => match self.break_targets.last() {
+                Some(&target) => Ok(self.terminate(prev, Jump::Jmp(target))),
+                None => semantic_err(
+                    "'break' statement not in loop or switch statement",
+                    location,
+                ),
+            },
+            StmtType::Continue => match self.continue_targets.last() {
+                Some(&target) => Ok(self.terminate(prev, Jump::Jmp(target))),
+                None => semantic_err("'continue' statement not in loop", location),
+            },
+            StmtType::Switch(condition, body) => self.switch(prev, condition, *body),
+            StmtType::Case(value, inner) => {
+                let block = self.fallthrough(prev);
+                let switch = match self.switches.last_mut() {
+                    Some(switch) => switch,
+                    None => {
+                        return Err(
+                            location.error(SemanticError::CaseOutsideSwitch { is_default: false })
+                        )
+                    }
                 };
-                self.cfg.add_instr(create_res_lambda(retval));
-                self.cfg.set_jump(Jump::Jmp(self.return_block));
-                Ok(prev)
+                // case labels are stored as u64 but may have been negative in C
+                let value = value as i64;
+                if switch.cases.iter().any(|&(existing, _)| existing == value) {
+                    return Err(location.error(SemanticError::DuplicateCase { is_default: false }));
+                }
+                switch.cases.push((value, block));
+                self.compile_stmt(self.block_start(), *inner)
+            }
+            StmtType::Default(inner) => {
+                let block = self.fallthrough(prev);
+                let switch = match self.switches.last_mut() {
+                    Some(switch) => switch,
+                    None => {
+                        return Err(
+                            location.error(SemanticError::CaseOutsideSwitch { is_default: true })
+                        )
+                    }
+                };
+                if switch.default.replace(block).is_some() {
+                    return Err(location.error(SemanticError::DuplicateCase { is_default: true }));
+                }
+                self.compile_stmt(self.block_start(), *inner)
+            }
+            StmtType::Label(name, inner) => {
+                if self.labels.contains_key(&name) && !self.pending_labels.contains_key(&name) {
+                    return semantic_err(&format!("redeclaration of label {}", name), location);
+                }
+                self.pending_labels.remove(&name);
+                let block = self.label_block(name);
+                self.end_block(prev, Jump::Jmp(block));
+                self.cfg.switch_to_block(block);
+                self.compile_stmt(self.block_start(), *inner)
+            }
+            StmtType::Goto(name) => {
+                if !self.labels.contains_key(&name) {
+                    self.pending_labels.insert(name, location);
+                }
+                let block = self.label_block(name);
+                Ok(self.terminate(prev, Jump::Jmp(block)))
             }
-            StmtType::Expr(expr) => self.compile_expr(expr),
-            //StmtType::If(condition, body, otherwise) => self.if_stmt(condition, *body, otherwise),
-            _ => todo!("statement type not yet supported: {:?}", stmt.data),
         }
     }
 
-    /*
+    /// End the current block with `jump`, and start a new one which is only
+    /// reachable through labels.
+    fn terminate(&mut self, prev: Value, jump: Jump) -> Value {
+        self.end_block(prev, jump);
+        let next = self.cfg.add_block();
+        self.cfg.switch_to_block(next);
+        self.block_start()
+    }
+
+    /// End the current block by jumping to a new block, and switch to the new block.
+    fn fallthrough(&mut self, prev: Value) -> BlockId {
+        let next = self.cfg.add_block();
+        self.end_block(prev, Jump::Jmp(next));
+        self.cfg.switch_to_block(next);
+        next
+    }
+
+    fn label_block(&mut self, name: InternedStr) -> BlockId {
+        let cfg = &mut self.cfg;
+        *self.labels.entry(name).or_insert_with(|| cfg.add_block())
+    }
+
+    /// Compile an expression, in a comment with its location.
+    fn compile_located(&mut self, expr: Expr) -> CompileResult<Value> {
+        let location = expr.location;
+        let value = self.compile_expr(expr)?;
+        Ok(self.locate(value, location))
+    }
+
     fn if_stmt(
         &mut self,
+        prev: Value,
         condition: Expr,
-        consequent: Stmt,
-        alternative: Option<Box<Stmt>>,
-    ) -> MirResult {
-        // TODO do I need to check the ctype here?
-        let condition = self.compile_expr(condition)?.val;
-        let consequent = self.compile_stmt(consequent)?;
-        let alternative = if let Some(alt) = alternative {
-            self.compile_stmt(*alt)?
+        body: Stmt,
+        otherwise: Option<Box<Stmt>>,
+    ) -> CompileResult<Value> {
+        let condition = self.compile_located(condition)?;
+        let then_block = self.cfg.add_block();
+        let end_block = self.cfg.add_block();
+        let else_block = if otherwise.is_some() {
+            self.cfg.add_block()
+        } else {
+            end_block
+        };
+        self.end_block(self.seq(prev, condition), Jump::Br(then_block, else_block));
+
+        self.cfg.switch_to_block(then_block);
+        let body = self.compile_stmt(self.block_start(), body)?;
+        self.end_block(body, Jump::Jmp(end_block));
+
+        if let Some(otherwise) = otherwise {
+            self.cfg.switch_to_block(else_block);
+            let otherwise = self.compile_stmt(self.block_start(), *otherwise)?;
+            self.end_block(otherwise, Jump::Jmp(end_block));
+        }
+
+        self.cfg.switch_to_block(end_block);
+        Ok(self.block_start())
+    }
+
+    /// Compile the body of a loop in the current block.
+    fn loop_body(
+        &mut self,
+        body: Stmt,
+        break_target: BlockId,
+        continue_target: BlockId,
+    ) -> CompileResult<Value> {
+        self.break_targets.push(break_target);
+        self.continue_targets.push(continue_target);
+        let body = self.compile_stmt(self.block_start(), body);
+        self.break_targets.pop();
+        self.continue_targets.pop();
+        body
+    }
+
+    fn while_stmt(&mut self, prev: Value, condition: Expr, body: Stmt) -> CompileResult<Value> {
+        self.for_loop(prev, Some(condition), None, body)
+    }
+
+    fn do_loop(&mut self, prev: Value, body: Stmt, condition: Expr) -> CompileResult<Value> {
+        let body_block = self.cfg.add_block();
+        let condition_block = self.cfg.add_block();
+        let end_block = self.cfg.add_block();
+        self.end_block(prev, Jump::Jmp(body_block));
+
+        self.cfg.switch_to_block(body_block);
+        let body = self.loop_body(body, end_block, condition_block)?;
+        self.end_block(body, Jump::Jmp(condition_block));
+
+        self.cfg.switch_to_block(condition_block);
+        let condition = self.compile_located(condition)?;
+        self.end_block(condition, Jump::Br(body_block, end_block));
+
+        self.cfg.switch_to_block(end_block);
+        Ok(self.block_start())
+    }
+
+    fn for_loop(
+        &mut self,
+        prev: Value,
+        condition: Option<Expr>,
+        post_loop: Option<Expr>,
+        body: Stmt,
+    ) -> CompileResult<Value> {
+        let body_block = self.cfg.add_block();
+        let end_block = self.cfg.add_block();
+        // for loops can loop forever: `for (;;) {}`
+        let condition_block = if condition.is_some() {
+            self.cfg.add_block()
         } else {
-            MirExpr::nop()
+            body_block
         };
-        Ok(MirExpr::if_(condition, consequent, alternative))
+        let post_block = if post_loop.is_some() {
+            self.cfg.add_block()
+        } else {
+            condition_block
+        };
+        self.end_block(prev, Jump::Jmp(condition_block));
+
+        if let Some(condition) = condition {
+            self.cfg.switch_to_block(condition_block);
+            let condition = self.compile_located(condition)?;
+            self.end_block(condition, Jump::Br(body_block, end_block));
+        }
+
+        self.cfg.switch_to_block(body_block);
+        let body = self.loop_body(body, end_block, post_block)?;
+        self.end_block(body, Jump::Jmp(post_block));
+
+        if let Some(post_loop) = post_loop {
+            self.cfg.switch_to_block(post_block);
+            let location = post_loop.location;
+            let post_loop = self.compile_discarded(post_loop)?;
+            let post_loop = self.locate(post_loop, location);
+            self.end_block(post_loop, Jump::Jmp(condition_block));
+        }
+
+        self.cfg.switch_to_block(end_block);
+        Ok(self.block_start())
     }
-    */
+
+    fn switch(&mut self, prev: Value, condition: Expr, body: Stmt) -> CompileResult<Value> {
+        // the jump of this block depends on the cases in `body`, so it's set last
+        let condition = self.compile_located(condition)?;
+        let condition = self.seq(prev, condition);
+        self.cfg.add_instr(create_res_lambda(lift(condition).val));
+        let dispatch_block = self.cfg.current_block();
+        let end_block = self.cfg.add_block();
+
+        // statements before the first case are unreachable
+        let start_block = self.cfg.add_block();
+        self.cfg.switch_to_block(start_block);
+        self.switches.push(SwitchCases::default());
+        self.break_targets.push(end_block);
+        let body = self.compile_stmt(self.block_start(), body);
+        self.break_targets.pop();
+        let SwitchCases { cases, default } = self.switches.pop().unwrap();
+        self.end_block(body?, Jump::Jmp(end_block));
+
+        self.cfg.switch_to_block(dispatch_block);
+        self.cfg.set_jump(Jump::Switch {
+            cases,
+            default: default.unwrap_or(end_block),
+        });
+        self.cfg.switch_to_block(end_block);
+        Ok(self.block_start())
+    }
+}
+
+fn semantic_err<T>(message: &str, location: Location) -> CompileResult<T> {
+    Err(location.with(message.to_string()).into())
 }
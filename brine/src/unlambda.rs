@@ -0,0 +1,516 @@
+//! ## Unlambda interpreter
+//!
+//! Reads Unlambda 2 programs into [`SyntaxNode`]s, and runs them.
+//!
+//! The evaluator keeps its continuation as an explicit, persistent linked list,
+//! so deep recursion doesn't overflow the Rust stack and `c` can capture the
+//! continuation without copying it.
+//!
+//! Input and output are done a byte at a time: bytes are read as the characters
+//! U+0000 to U+00FF, and those characters are written back as single bytes.
+
+use crate::ast::{Combinator, SyntaxNode};
+use crate::encoding::INT_BITS;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+/// Parse an Unlambda program, ignoring whitespace and `#` comments.
+pub fn parse(text: &str) -> Result<SyntaxNode, String> {
+    // `None` marks an application whose function hasn't been parsed yet
+    let mut pending: Vec<Option<SyntaxNode>> = Vec::new();
+    let mut chars = text.chars().enumerate();
+    while let Some((pos, c)) = chars.next() {
+        let mut next_char = |what: &str| {
+            chars
+                .next()
+                .map(|(_, c)| c)
+                .ok_or_else(|| format!("expected a character after {} at {}", what, pos))
+        };
+        let combinator = match c {
+            '`' => {
+                pending.push(None);
+                continue;
+            }
+            '#' => {
+                while let Ok(c) = next_char("#") {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            '.' => Combinator::Dot(next_char(".")?),
+            '?' => Combinator::Compare(next_char("?")?),
+            '@' => Combinator::Read,
+            '|' => Combinator::Reprint,
+            c => match c.to_ascii_lowercase() {
+                'i' => Combinator::I,
+                'k' => Combinator::K,
+                's' => Combinator::S,
+                'v' => Combinator::V,
+                'd' => Combinator::D,
+                'c' => Combinator::C,
+                'e' => Combinator::E,
+                'r' => Combinator::Dot('\n'),
+                _ => return Err(format!("unexpected character {:?} at {}", c, pos)),
+            },
+        };
+        let mut node = SyntaxNode::Combinator(combinator);
+        // complete every application this finishes
+        loop {
+            match pending.pop() {
+                None => {
+                    if chars.any(|(_, c)| !c.is_whitespace()) {
+                        return Err(format!("trailing input after the program at {}", pos));
+                    }
+                    return Ok(node);
+                }
+                Some(None) => {
+                    pending.push(Some(node));
+                    break;
+                }
+                Some(Some(func)) => node = SyntaxNode::application(func, node),
+            }
+        }
+    }
+    Err("unexpected end of program".to_string())
+}
+
+/// A fully evaluated Unlambda expression.
+#[derive(Debug, Clone)]
+pub enum Value<'a> {
+    Combinator(Combinator),
+    /// `` `kX ``
+    K1(Rc<Value<'a>>),
+    /// `` `sX ``
+    S1(Rc<Value<'a>>),
+    /// ``` ``sXY ```
+    S2(Rc<Value<'a>>, Rc<Value<'a>>),
+    /// `d` applied to a computation, which runs each time the promise is applied
+    Promise(Rc<Delayed<'a>>),
+    /// A continuation captured by `c`
+    Continuation(Rc<Continuation<'a>>),
+}
+
+#[derive(Debug)]
+pub enum Delayed<'a> {
+    Expression(&'a SyntaxNode),
+    Application(Rc<Value<'a>>, Rc<Value<'a>>),
+    Value(Rc<Value<'a>>),
+}
+
+#[derive(Debug)]
+pub enum Continuation<'a> {
+    Done,
+    /// The function of an application was evaluated, evaluate the argument
+    EvalArg(&'a SyntaxNode, Rc<Continuation<'a>>),
+    /// The argument was evaluated, apply the function to it
+    ApplyFunc(Rc<Value<'a>>, Rc<Continuation<'a>>),
+    /// A function was evaluated, apply it to the argument
+    ApplyTo(Rc<Value<'a>>, Rc<Continuation<'a>>),
+    /// ```` ```sXYZ ````: ``` `XZ ``` was evaluated, evaluate `` `YZ `` and apply
+    /// the first to the second
+    SArg(Rc<Value<'a>>, Rc<Value<'a>>, Rc<Continuation<'a>>),
+}
+
+impl<'a> Continuation<'a> {
+    fn take_next(&mut self) -> Option<Rc<Continuation<'a>>> {
+        match self {
+            Continuation::Done => None,
+            Continuation::EvalArg(_, next)
+            | Continuation::ApplyFunc(_, next)
+            | Continuation::ApplyTo(_, next)
+            | Continuation::SArg(_, _, next) => {
+                Some(std::mem::replace(next, Rc::new(Continuation::Done)))
+            }
+        }
+    }
+}
+
+// Continuations can be very long, so don't drop them recursively.
+impl<'a> Drop for Continuation<'a> {
+    fn drop(&mut self) {
+        let mut next = self.take_next();
+        while let Some(cont) = next {
+            next = match Rc::try_unwrap(cont) {
+                Ok(mut cont) => cont.take_next(),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+/// Why a program stopped before returning a value.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// The program ran for more than the allowed number of applications
+    OutOfSteps,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "i/o error: {}", err),
+            Error::OutOfSteps => write!(f, "program ran for too many steps"),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+enum State<'a> {
+    Eval(&'a SyntaxNode, Rc<Continuation<'a>>),
+    Apply(Rc<Value<'a>>, Rc<Value<'a>>, Rc<Continuation<'a>>),
+    Return(Rc<Value<'a>>, Rc<Continuation<'a>>),
+}
+
+pub struct Interpreter<R, W> {
+    input: R,
+    output: W,
+    /// The character last read by `@`
+    current: Option<char>,
+    /// The number of applications left before giving up, if limited
+    steps_left: Option<usize>,
+}
+
+impl<R: Read, W: Write> Interpreter<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Interpreter {
+            input,
+            output,
+            current: None,
+            steps_left: None,
+        }
+    }
+
+    /// Stop with [`Error::OutOfSteps`] after `steps` more applications.
+    pub fn with_step_limit(mut self, steps: usize) -> Self {
+        self.steps_left = Some(steps);
+        self
+    }
+
+    /// Evaluate a program, returning its value, or the argument given to `e`.
+    pub fn eval<'a>(&mut self, program: &'a SyntaxNode) -> Result<Rc<Value<'a>>, Error> {
+        self.run(State::Eval(program, Rc::new(Continuation::Done)))
+    }
+
+    /// Apply a value to another as a complete program.
+    pub fn apply<'a>(
+        &mut self,
+        func: Rc<Value<'a>>,
+        arg: Rc<Value<'a>>,
+    ) -> Result<Rc<Value<'a>>, Error> {
+        self.run(State::Apply(func, arg, Rc::new(Continuation::Done)))
+    }
+
+    fn run<'a>(&mut self, mut state: State<'a>) -> Result<Rc<Value<'a>>, Error> {
+        loop {
+            state = match state {
+                State::Eval(node, cont) => match node {
+                    SyntaxNode::Combinator(c) => {
+                        State::Return(Rc::new(Value::Combinator(*c)), cont)
+                    }
+                    SyntaxNode::Application(ap) => {
+                        State::Eval(&ap.func, Rc::new(Continuation::EvalArg(&ap.arg, cont)))
+                    }
+                    SyntaxNode::Abstraction(_) | SyntaxNode::Reference(_) => {
+                        panic!("only combinators can be evaluated, not {:?}", node)
+                    }
+                },
+                State::Return(value, cont) => match &*cont {
+                    Continuation::Done => return Ok(value),
+                    // `d` delays evaluation of its argument
+                    Continuation::EvalArg(arg, next) => match &*value {
+                        Value::Combinator(Combinator::D) => State::Return(
+                            Rc::new(Value::Promise(Rc::new(Delayed::Expression(arg)))),
+                            next.clone(),
+                        ),
+                        _ => {
+                            State::Eval(arg, Rc::new(Continuation::ApplyFunc(value, next.clone())))
+                        }
+                    },
+                    Continuation::ApplyFunc(func, next) => {
+                        State::Apply(func.clone(), value, next.clone())
+                    }
+                    Continuation::ApplyTo(arg, next) => {
+                        State::Apply(value, arg.clone(), next.clone())
+                    }
+                    Continuation::SArg(y, z, next) => match &*value {
+                        Value::Combinator(Combinator::D) => State::Return(
+                            Rc::new(Value::Promise(Rc::new(Delayed::Application(
+                                y.clone(),
+                                z.clone(),
+                            )))),
+                            next.clone(),
+                        ),
+                        _ => State::Apply(
+                            y.clone(),
+                            z.clone(),
+                            Rc::new(Continuation::ApplyFunc(value, next.clone())),
+                        ),
+                    },
+                },
+                State::Apply(func, arg, cont) => {
+                    if let Some(steps) = &mut self.steps_left {
+                        if *steps == 0 {
+                            return Err(Error::OutOfSteps);
+                        }
+                        *steps -= 1;
+                    }
+                    self.apply_once(&func, arg, cont)?
+                }
+            }
+        }
+    }
+
+    fn apply_once<'a>(
+        &mut self,
+        func: &Rc<Value<'a>>,
+        arg: Rc<Value<'a>>,
+        cont: Rc<Continuation<'a>>,
+    ) -> Result<State<'a>, Error> {
+        let combinator = |c| Rc::new(Value::Combinator(c));
+        let succeeded = |b| combinator(if b { Combinator::I } else { Combinator::V });
+        let state = match &**func {
+            Value::Combinator(c) => match c {
+                Combinator::I => State::Return(arg, cont),
+                Combinator::K => State::Return(Rc::new(Value::K1(arg)), cont),
+                Combinator::S => State::Return(Rc::new(Value::S1(arg)), cont),
+                Combinator::V => State::Return(func.clone(), cont),
+                Combinator::D => {
+                    State::Return(Rc::new(Value::Promise(Rc::new(Delayed::Value(arg)))), cont)
+                }
+                Combinator::C => {
+                    let continuation = Rc::new(Value::Continuation(cont.clone()));
+                    State::Apply(arg, continuation, cont)
+                }
+                // drop the continuation to exit
+                Combinator::E => State::Return(arg, Rc::new(Continuation::Done)),
+                Combinator::Dot(c) => {
+                    self.write_char(*c)?;
+                    State::Return(arg, cont)
+                }
+                Combinator::Read => {
+                    self.current = self.read_char()?;
+                    State::Apply(arg, succeeded(self.current.is_some()), cont)
+                }
+                Combinator::Compare(c) => {
+                    State::Apply(arg, succeeded(self.current == Some(*c)), cont)
+                }
+                Combinator::Reprint => {
+                    let reprint = match self.current {
+                        Some(c) => combinator(Combinator::Dot(c)),
+                        None => combinator(Combinator::V),
+                    };
+                    State::Apply(arg, reprint, cont)
+                }
+            },
+            Value::K1(x) => State::Return(x.clone(), cont),
+            Value::S1(x) => State::Return(Rc::new(Value::S2(x.clone(), arg)), cont),
+            Value::S2(x, y) => State::Apply(
+                x.clone(),
+                arg.clone(),
+                Rc::new(Continuation::SArg(y.clone(), arg, cont)),
+            ),
+            Value::Promise(delayed) => {
+                let cont = Rc::new(Continuation::ApplyTo(arg, cont));
+                match &**delayed {
+                    Delayed::Expression(node) => State::Eval(node, cont),
+                    Delayed::Application(f, x) => State::Apply(f.clone(), x.clone(), cont),
+                    Delayed::Value(v) => State::Return(v.clone(), cont),
+                }
+            }
+            Value::Continuation(k) => State::Return(arg, k.clone()),
+        };
+        Ok(state)
+    }
+
+    fn write_char(&mut self, c: char) -> Result<(), Error> {
+        match u8::try_from(c) {
+            Ok(byte) => self.output.write_all(&[byte])?,
+            Err(_) => write!(self.output, "{}", c)?,
+        }
+        Ok(())
+    }
+
+    fn read_char(&mut self) -> Result<Option<char>, Error> {
+        let mut byte = [0];
+        Ok(match self.input.read(&mut byte)? {
+            0 => None,
+            _ => Some(char::from(byte[0])),
+        })
+    }
+
+    /// Decode a boolean encoded as described in [`crate::encoding`].
+    pub fn decode_bool(&mut self, value: Rc<Value>) -> Result<Option<bool>, Error> {
+        let marker = |c| Rc::new(Value::Combinator(Combinator::Dot(c)));
+        let partial = self.apply(value, marker('t'))?;
+        Ok(match &*self.apply(partial, marker('f'))? {
+            Value::Combinator(Combinator::Dot('t')) => Some(true),
+            Value::Combinator(Combinator::Dot('f')) => Some(false),
+            _ => None,
+        })
+    }
+
+    /// Decode an integer encoded as described in [`crate::encoding`].
+    pub fn decode_int(&mut self, value: Rc<Value>) -> Result<Option<i64>, Error> {
+        let car = Rc::new(Value::Combinator(Combinator::K));
+        let cdr = Rc::new(Value::K1(Rc::new(Value::Combinator(Combinator::I))));
+        let mut list = value;
+        let mut result = 0;
+        for bit in 0..INT_BITS {
+            if self.decode_null(list.clone())? != Some(false) {
+                return Ok(None);
+            }
+            let b = self.apply(list.clone(), car.clone())?;
+            match self.decode_bool(b)? {
+                Some(true) => result |= 1 << bit,
+                Some(false) => {}
+                None => return Ok(None),
+            }
+            list = self.apply(list, cdr.clone())?;
+        }
+        if self.decode_null(list)? != Some(true) {
+            return Ok(None);
+        }
+        Ok(Some(result))
+    }
+
+    /// Whether the value is the encoding of null, if it's null or a pair.
+    fn decode_null(&mut self, value: Rc<Value>) -> Result<Option<bool>, Error> {
+        // a pair applies this to its car and cdr, and null ignores it
+        let k = |x| Rc::new(Value::K1(x));
+        let is_pair = k(k(Rc::new(Value::Combinator(Combinator::V))));
+        let answer = self.apply(value, is_pair)?;
+        Ok(match &*answer {
+            Value::Combinator(Combinator::V) => Some(false),
+            Value::Combinator(Combinator::K) => Some(true),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Error, Interpreter, Value};
+    use crate::ast::Combinator;
+    use crate::mir::{MirExpr, MirLiteral};
+    use crate::ski;
+
+    /// Run a program and return its output, and what it evaluated to if that
+    /// is a combinator.
+    fn run(program: &str, input: &str) -> (String, Option<Combinator>) {
+        let program = parse(program).unwrap();
+        let mut output = Vec::new();
+        let value = Interpreter::new(input.as_bytes(), &mut output)
+            .eval(&program)
+            .unwrap();
+        let value = match &*value {
+            Value::Combinator(c) => Some(*c),
+            _ => None,
+        };
+        (String::from_utf8(output).unwrap(), value)
+    }
+
+    fn output(program: &str, input: &str) -> String {
+        run(program, input).0
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let text = "```s`kr``si`k.\n # comment\n `@`?a`|i";
+        let parsed = parse(text).unwrap();
+        let reparsed = parse(&parsed.output(10)).unwrap();
+        assert_eq!(parsed, reparsed);
+        assert_eq!(parse("`kI").unwrap(), parse("`ki").unwrap());
+
+        assert!(parse("`k").is_err());
+        assert!(parse("ki").is_err());
+        assert!(parse("`kx").is_err());
+        assert!(parse(".").is_err());
+    }
+
+    #[test]
+    fn basic_combinators() {
+        assert_eq!(run("`r`.a`.bi", ""), ("ba\n".into(), Some(Combinator::I)));
+        // arguments are only evaluated once
+        assert_eq!(output("```sii`.ai", ""), "a");
+        assert_eq!(
+            run("``k.av", ""),
+            (String::new(), Some(Combinator::Dot('a')))
+        );
+        assert_eq!(run("``v.a.b", ""), (String::new(), Some(Combinator::V)));
+        // arguments are evaluated before functions are applied
+        assert_eq!(output("``k.a`.bi", ""), "b");
+    }
+
+    #[test]
+    fn promises() {
+        assert_eq!(run("`d`.ai", ""), (String::new(), None));
+        // the promise is forced each time it is applied
+        assert_eq!(output("``d`.aii", ""), "a");
+        assert_eq!(output("````sii`d`.aii", ""), "aa");
+        // `d` can also be the result of an application
+        assert_eq!(output("```s`kd`k.ai", ""), "");
+        assert_eq!(output("````s`kd`k.aii", ""), "a");
+    }
+
+    #[test]
+    fn continuations() {
+        // `(c (lambda (k) (.x (k i))))` returns `i` without printing
+        assert_eq!(output("`.a`c``s`k.x``si`ki", ""), "a");
+        assert_eq!(run("`ci", ""), (String::new(), None));
+    }
+
+    #[test]
+    fn exit() {
+        assert_eq!(
+            run("`.b``e.a`.ci", ""),
+            (String::new(), Some(Combinator::Dot('a')))
+        );
+    }
+
+    #[test]
+    fn input() {
+        assert_eq!(output("``@|i", "xy"), "x");
+        assert_eq!(output("``@i``@|i", "xy"), "y");
+        assert_eq!(output("``@|i", ""), "");
+        assert_eq!(output("```@?x.ai", "x"), "a");
+        assert_eq!(output("```@?x.ai", "y"), "");
+        // the current character is kept until the next read
+        assert_eq!(output("``@i``?x.ai", "x"), "a");
+    }
+
+    #[test]
+    fn decode() {
+        let mut interpreter = Interpreter::new(std::io::empty(), std::io::sink());
+        for &i in &[0, -5, i64::MAX] {
+            let program = ski::compile(&MirExpr::literal(MirLiteral::Int(i))).unwrap();
+            let value = interpreter.eval(&program).unwrap();
+            assert_eq!(interpreter.decode_int(value).unwrap(), Some(i));
+        }
+        let program = ski::compile(&MirExpr::literal(MirLiteral::Bool(false))).unwrap();
+        let value = interpreter.eval(&program).unwrap();
+        assert_eq!(interpreter.decode_bool(value.clone()).unwrap(), Some(false));
+        assert_eq!(interpreter.decode_int(value).unwrap(), None);
+        let program = parse("i").unwrap();
+        let value = interpreter.eval(&program).unwrap();
+        assert_eq!(interpreter.decode_int(value).unwrap(), None);
+    }
+
+    #[test]
+    fn step_limit() {
+        let program = parse("```sii``sii").unwrap();
+        let result = Interpreter::new(std::io::empty(), std::io::sink())
+            .with_step_limit(1000)
+            .eval(&program);
+        assert!(matches!(result, Err(Error::OutOfSteps)));
+    }
+}
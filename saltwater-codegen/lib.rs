@@ -6,7 +6,7 @@ macro_rules! semantic_err {
         return Err(CompileError::semantic(Locatable {
             data: $message,
             location: $location,
-        }));
+        }))
     };
 }
 
@@ -374,7 +374,7 @@ impl PureAnalyzer {
             | (Type::Array(to, _), i) if i.is_integral() && to.is_complete() => {
                 let to = to.clone();
                 let (left, right) = (left.rval(), right.rval());
-                return self.pointer_arithmetic(left, right, &*to, location);
+                return self.pointer_arithmetic(left, right, &*to, op, location);
             }
             // `i + p`
             (i, Type::Pointer(to, _))
@@ -382,7 +382,7 @@ impl PureAnalyzer {
             | (i, Type::Array(to, _)) if i.is_integral() && is_add && to.is_complete() => {
                 let to = to.clone();
                 let (left, right) = (left.rval(), right.rval());
-                return self.pointer_arithmetic(right, left, &*to, location);
+                return self.pointer_arithmetic(right, left, &*to, op, location);
             }
             _ => {}
         };
@@ -462,9 +462,10 @@ impl PureAnalyzer {
         base: Expr,
         index: Expr,
         pointee: &Type,
+        op: BinaryOp,
         location: Location,
     ) -> Expr {
-        // the idea is to desugar to `base + sizeof(base)*index`
+        // the idea is to desugar to `base + sizeof(base)*index` (or `base - ...` for `op == Sub`)
         let offset = Expr {
             lval: false,
             location: index.location,
@@ -499,7 +500,7 @@ impl PureAnalyzer {
             lval: false,
             location,
             ctype: base.ctype.clone(),
-            expr: ExprType::Binary(BinaryOp::Add, Box::new(base), Box::new(offset)),
+            expr: ExprType::Binary(op, Box::new(base), Box::new(offset)),
         }
     }
     // `func(args)`
@@ -674,7 +675,8 @@ impl PureAnalyzer {
                 return left;
             }
         };
-        let mut addr = self.pointer_arithmetic(array, index, &target_type, location);
+        let mut addr =
+            self.pointer_arithmetic(array, index, &target_type, BinaryOp::Add, location);
         addr.ctype = target_type;
         // `p + i` -> `*(p + i)`
         addr.lval = true;
@@ -1481,6 +1483,25 @@ mod test {
         },);
     }
     #[test]
+    fn test_pointer_arithmetic() {
+        let p = Variable {
+            id: InternedStr::get_or_intern("p"),
+            qualifiers: Default::default(),
+            storage_class: Default::default(),
+            ctype: Type::Pointer(Box::new(Type::Int(true)), Qualifiers::default()),
+        }
+        .insert();
+        for &(input, op) in &[("p + 1", BinaryOp::Add), ("p - 1", BinaryOp::Sub)] {
+            match expr_with_scope(input, &[p]) {
+                Ok(Expr {
+                    expr: ExprType::Binary(actual, _, _),
+                    ..
+                }) => assert_eq!(actual, op, "{}", input),
+                other => panic!("{} parsed as {:?}", input, other),
+            }
+        }
+    }
+    #[test]
     fn test_type_errors() {
         assert!(expr("1 % 2.0").is_err());
         assert!(expr("0 ? \"error message\" : 0.0").is_err());
@@ -108,6 +108,13 @@ pub enum SemanticError {
     #[error("{0}")]
     Generic(String),
 
+    /// The program is valid, but uses a feature which a backend can't compile yet
+    #[error("{feature} are not yet supported by {backend}")]
+    Unsupported {
+        feature: String,
+        backend: &'static str,
+    },
+
     // Declaration specifier errors
     #[error("cannot combine '{new}' specifier with previous '{existing}' type specifier")]
     InvalidSpecifier {
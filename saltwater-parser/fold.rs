@@ -490,7 +490,7 @@ fn shift_right(
             location: *location,
         })?;
         // Rust panics if the shift is greater than the size of the type
-        if shift >= sizeof {
+        if shift >= u64::from(CHAR_BIT) * sizeof {
             return Ok(ExprType::Literal(if ctype.is_signed() {
                 Int(0)
             } else {
@@ -729,6 +729,7 @@ mod tests {
     fn test_right_shift() {
         assert_fold("8 >> 0", "8");
         assert_fold("32 >> 5", "1");
+        assert_fold("256 >> 8", "1");
         assert_eq!(
             test_const_fold("8 >> -1").unwrap_err().data,
             SemanticError::NegativeShift { is_left: false }.into()
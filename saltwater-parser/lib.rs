@@ -251,6 +251,51 @@ pub fn check_semantics(buf: &str, opt: Opt) -> Program<Vec<Locatable<hir::Declar
     }
 }
 
+/// Show `msg` at `location`, followed by the line it is on with the location underlined.
+///
+/// `prefix` says what kind of message it is, like "error" or "warning".
+#[must_use]
+pub fn pretty_print<P: std::fmt::Display, T: std::fmt::Display>(
+    prefix: P,
+    msg: T,
+    location: Location,
+    file_db: &Files,
+) -> String {
+    let file = location.file;
+    let start = file_db
+        .location(file, location.span.start)
+        .expect("start location should be in bounds");
+    let buf = format!(
+        "{}:{}:{} {}: {}\n",
+        file_db.name(file).to_string_lossy(),
+        start.line.number(),
+        start.column.number(),
+        prefix,
+        msg
+    );
+    // avoid printing spurious newline for errors and EOF
+    if location.span.end == 0 {
+        return buf;
+    }
+    let end = file_db
+        .location(file, location.span.end)
+        .expect("end location should be in bounds");
+    if start.line == end.line {
+        let line = file_db
+            .line_span(file, start.line)
+            .expect("line should be in bounds");
+        format!(
+            "{}{}{}{}\n",
+            buf,
+            file_db.source_slice(file, line).unwrap(),
+            " ".repeat(start.column.0 as usize),
+            "^".repeat((end.column - start.column).0 as usize)
+        )
+    } else {
+        buf
+    }
+}
+
 impl<T: Into<ArcStr>> From<T> for Source {
     fn from(src: T) -> Self {
         Self {
@@ -294,4 +339,23 @@ mod tests {
         assert!(parse_err.is_empty());
         assert!(err.unwrap().data.is_syntax_err());
     }
+
+    fn pp<S: Into<data::lex::Span>>(span: S, source: &str) -> String {
+        let mut file_db = Files::new();
+        let source = String::from(source).into();
+        let file = file_db.add("<test-suite>", source);
+        let location = Location {
+            file,
+            span: span.into(),
+        };
+        super::pretty_print("", "", location, &file_db)
+    }
+    #[test]
+    fn pretty_print() {
+        assert_eq!(
+            dbg!(pp(8..15, "int i = \"hello\";\n")).lines().nth(2),
+            Some("        ^^^^^^^")
+        );
+        pp(0..0, "");
+    }
 }
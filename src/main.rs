@@ -8,10 +8,13 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 
 use ansi_term::{ANSIString, Colour};
 use arcstr::ArcStr;
+use brine::miri::{self, Obj};
+use brine::opt::{optimize, Passes};
+use brine::SourceMap;
 use pico_args::Arguments;
 use saltwater_codegen::{assemble, compile, link};
 use saltwater_parser::data::{error::CompileWarning, Location};
-use saltwater_parser::{preprocess, Error, Files, Opt, Program};
+use saltwater_parser::{preprocess, pretty_print, Error, Files, Opt, Program};
 use tempfile::NamedTempFile;
 
 static ERRORS: AtomicUsize = AtomicUsize::new(0);
@@ -34,6 +37,9 @@ FLAGS:
         --debug-lex        If set, print all tokens found by the lexer in addition to compiling.
         --jit              If set, will use JIT compilation for C code and instantly run compiled code (No files produced).
                             NOTE: this option only works if saltwater was compiled with the `jit` feature.
+                            It uses cranelift, so it can't be combined with `--backend=brine`, `--emit` or `--interpret`.
+        --interpret        If set, will compile C code with brine and instantly run it in miri (No files produced).
+                            This is the same as `--backend=brine --emit=interpret`.
     -h, --help             Prints help information
     -c, --no-link          If set, compile and assemble but do not link. Object file is machine-dependent.
     -E, --preprocess-only  If set, preprocess only, but do not do anything else.
@@ -42,7 +48,23 @@ FLAGS:
     -V, --version          Prints version information
 
 OPTIONS:
+        --backend <name>     The backend to compile with. May be \"cranelift\" or \"brine\".
+                              \"cranelift\" produces native object files and executables.
+                              \"brine\" compiles to combinators, see --emit.
+                              [default: brine if --emit is given, cranelift otherwise]
         --color <when>       When to use color. May be \"never\", \"auto\", or \"always\". [default: auto]
+        --emit <kind>        What the brine backend should produce. May be \"unlambda\", \"mir\"
+                              or \"interpret\". [default: unlambda]
+                              \"unlambda\" writes an Unlambda 2 program: a header of `#` comments,
+                              then a single expression, then a newline.
+                              \"mir\" writes the MIR brine generates, before desugaring and
+                              optimization, in the text format miri reads.
+                              \"interpret\" runs the program in miri instead of writing a file,
+                              and exits with the value `main` returns.
+        --line-width <cols>  The maximum width of lines in generated Unlambda programs. [default: 80]
+        --passes <list>      The optimization passes to run on the brine IR, separated by commas.
+                              May include \"beta\", \"fold\", \"dead-let\" and \"eta\",
+                              or be \"all\" or \"none\". [default: all]
     -o, --output <output>    The output file to use. [default: a.out]
         --max-errors <max>   The maximum number of errors to allow before giving up.
                              Use 0 to allow unlimited errors. [default: 10]
@@ -59,8 +81,9 @@ ARGS:
 
 const USAGE: &str = "\
 usage: swcc [--help | -h] [--version | -V] [--debug-ir] [--debug-ast] [--debug-lex]
-            [--debug-hir] [--jit] [--no-link | -c] [--preprocess-only | -E]
-            [-I <dir>] [-D <id[=val]>] [<file>]";
+            [--debug-hir] [--jit] [--interpret] [--no-link | -c] [--preprocess-only | -E]
+            [--backend <name>] [--emit <kind>] [--line-width <cols>] [--passes <list>]
+            [-o <output>] [-I <dir>] [-D <id[=val]>] [<file>]";
 
 struct BinOpt {
     /// The options that will be passed to `compile()`
@@ -72,6 +95,56 @@ struct BinOpt {
     preprocess_only: bool,
     /// Whether or not to use color
     color: ColorChoice,
+    /// The backend to compile with, unless JIT compiling
+    backend: Backend,
+    /// Options for the brine backend
+    brine: BrineOpt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Cranelift,
+    Brine,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Backend, &'static str> {
+        match s {
+            "cranelift" => Ok(Backend::Cranelift),
+            "brine" => Ok(Backend::Brine),
+            _ => Err("Invalid backend"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BrineOpt {
+    /// What to write to the output file
+    emit: EmitKind,
+    /// The maximum width of lines in generated Unlambda programs
+    line_width: usize,
+    /// The optimization passes to run on MIR
+    passes: Passes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EmitKind {
+    Unlambda,
+    Mir,
+    Interpret,
+}
+
+impl std::str::FromStr for EmitKind {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<EmitKind, &'static str> {
+        match s {
+            "unlambda" => Ok(EmitKind::Unlambda),
+            "mir" => Ok(EmitKind::Mir),
+            "interpret" => Ok(EmitKind::Interpret),
+            _ => Err("Invalid output kind"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -138,7 +211,14 @@ fn real_main(buf: ArcStr, bin_opt: BinOpt, output: &Path) -> Result<(), (Error,
     #[cfg(feature = "jit")]
     {
         if !opt.jit {
-            aot_main(&buf, opt, output, bin_opt.color)
+            aot_main(
+                &buf,
+                opt,
+                output,
+                bin_opt.color,
+                bin_opt.backend,
+                bin_opt.brine,
+            )
         } else {
             let module = saltwater_codegen::initialize_jit_module();
             let Program {
@@ -155,24 +235,145 @@ fn real_main(buf: ArcStr, bin_opt: BinOpt, output: &Path) -> Result<(), (Error,
         }
     }
     #[cfg(not(feature = "jit"))]
-    aot_main(&buf, opt, output, bin_opt.color)
+    aot_main(
+        &buf,
+        opt,
+        output,
+        bin_opt.color,
+        bin_opt.backend,
+        bin_opt.brine,
+    )
 }
 
 #[inline]
-fn aot_main(buf: &str, opt: Opt, output: &Path, color: ColorChoice) -> Result<(), (Error, Files)> {
+fn aot_main(
+    buf: &str,
+    opt: Opt,
+    output: &Path,
+    color: ColorChoice,
+    backend: Backend,
+    brine_opt: BrineOpt,
+) -> Result<(), (Error, Files)> {
+    match backend {
+        Backend::Cranelift => cranelift_main(buf, opt, output, color),
+        Backend::Brine => brine_main(buf, opt, output, color, brine_opt),
+    }
+}
+
+fn cranelift_main(
+    buf: &str,
+    opt: Opt,
+    output: &Path,
+    color: ColorChoice,
+) -> Result<(), (Error, Files)> {
+    let no_link = opt.no_link;
+    let module = saltwater_codegen::initialize_aot_module("saltwater_main".to_owned());
+    let Program {
+        result,
+        warnings,
+        files,
+    } = compile(module, buf, opt);
+    handle_warnings(warnings, &files, color);
+
+    let product = sw_try!(result.map(|module| module.finish()), files);
+    if no_link {
+        sw_try!(assemble(product, output), files);
+        return Ok(());
+    }
+    let tmp_file = sw_try!(NamedTempFile::new(), files);
+    sw_try!(assemble(product, tmp_file.as_ref()), files);
+    sw_try!(link(tmp_file.as_ref(), output), files);
+    Ok(())
+}
+
+fn brine_main(
+    buf: &str,
+    opt: Opt,
+    output: &Path,
+    color: ColorChoice,
+    brine_opt: BrineOpt,
+) -> Result<(), (Error, Files)> {
+    let filename = opt.filename.clone();
+    let (program, source_map) = brine::compile_with_source_map(buf, opt);
     let Program {
         result,
         warnings,
         files,
-    } = brine::compile(buf, opt);
+    } = program;
     handle_warnings(warnings, &files, color);
 
     let product = sw_try!(result, files);
-    println!("{:?}", product);
+    let mir = product.desugar();
+    if let Err(errors) = brine::check::check(&mir) {
+        let errors = errors.iter().map(|err| err.to_string()).collect::<Vec<_>>();
+        fatal(
+            format!("brine generated invalid MIR: {}", errors.join("; ")),
+            5,
+            color,
+        )
+    }
+    if brine_opt.emit == EmitKind::Interpret {
+        interpret(&mir, &source_map, &files, color);
+    }
+    let header = format!(
+        "Generated by {} {} from {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        filename.to_string_lossy()
+    );
+    let file = sw_try!(File::create(output), files);
+    let mut writer = io::BufWriter::new(file);
+    match brine_opt.emit {
+        EmitKind::Unlambda => {
+            let mir = optimize(mir, brine_opt.passes);
+            let program = brine::ski::compile(&mir).unwrap_or_else(|err| {
+                fatal(
+                    format!("could not compile to combinators: {}", err),
+                    5,
+                    color,
+                )
+            });
+            sw_try!(
+                program.write_unlambda(&header, brine_opt.line_width, &mut writer),
+                files
+            );
+        }
+        EmitKind::Mir => sw_try!(product.write_mir(&header, &mut writer), files),
+        EmitKind::Interpret => unreachable!("interpreted programs don't write a file"),
+    }
+    sw_try!(io::Write::flush(&mut writer), files);
 
     Ok(())
 }
 
+/// Run a desugared program in miri and exit with the value its `main` returns.
+fn interpret(
+    mir: &brine::mir::MirExpr,
+    source_map: &SourceMap,
+    files: &Files,
+    color: ColorChoice,
+) -> ! {
+    match miri::run(mir) {
+        // like a native program, only the low 8 bits of the return value are kept
+        Ok(Obj::Int(status)) => process::exit(status as i32),
+        Ok(other) => fatal(format!("main returned {}, not an int", other), 5, color),
+        Err(err) => {
+            // show where the error happened in the C program, if we know
+            let location = match source_map.locate(&err.context) {
+                Some(location) => location,
+                None => fatal(format!("runtime error: {}", err), 6, color),
+            };
+            let prefix = if color.use_color_for(atty::Stream::Stderr) {
+                Colour::Red.bold().paint("runtime error")
+            } else {
+                ANSIString::from("runtime error")
+            };
+            eprint!("{}", pretty_print(prefix, err.kind, location, files));
+            process::exit(6);
+        }
+    }
+}
+
 fn handle_warnings(warnings: VecDeque<CompileWarning>, file_db: &Files, color: ColorChoice) {
     WARNINGS.fetch_add(warnings.len(), Ordering::Relaxed);
     #[cfg(not(feature = "salty"))]
@@ -201,13 +402,13 @@ fn handle_warnings(warnings: VecDeque<CompileWarning>, file_db: &Files, color: C
         msgs[rng.gen_range(0, msgs.len())]
     };
 
-    let tag = if color.use_color_for(atty::Stream::Stdout) {
+    let tag = if color.use_color_for(atty::Stream::Stderr) {
         Colour::Yellow.bold().paint(warn)
     } else {
         ANSIString::from(warn)
     };
     for warning in warnings {
-        print!(
+        eprint!(
             "{}",
             pretty_print(tag.clone(), warning.data, warning.location, file_db)
         );
@@ -327,6 +528,48 @@ fn parse_args() -> Result<(BinOpt, PathBuf), pico_args::Error> {
     let color_choice = input
         .opt_value_from_str("--color")?
         .unwrap_or(ColorChoice::Auto);
+    let interpret = input.contains("--interpret");
+    #[cfg(feature = "jit")]
+    let jit = input.contains("--jit");
+    #[cfg(feature = "jit")]
+    {
+        if jit && interpret {
+            return Err(pico_args::Error::ArgumentParsingFailed {
+                cause: "--jit and --interpret can't be used together".into(),
+            });
+        }
+    }
+    let emit = match input.opt_value_from_str("--emit")? {
+        None if interpret => Some(EmitKind::Interpret),
+        Some(emit) if interpret && emit != EmitKind::Interpret => {
+            return Err(pico_args::Error::ArgumentParsingFailed {
+                cause: "--interpret can't be used with another --emit".into(),
+            })
+        }
+        emit => emit,
+    };
+    let backend = match input.opt_value_from_str("--backend")? {
+        Some(Backend::Cranelift) if emit.is_some() => {
+            return Err(pico_args::Error::ArgumentParsingFailed {
+                cause: "--emit and --interpret only apply to --backend=brine".into(),
+            })
+        }
+        Some(backend) => backend,
+        None if emit.is_some() => Backend::Brine,
+        None => Backend::Cranelift,
+    };
+    #[cfg(feature = "jit")]
+    {
+        if jit && backend == Backend::Brine {
+            return Err(pico_args::Error::ArgumentParsingFailed {
+                cause: "--jit can't be used with --backend=brine or --emit".into(),
+            });
+        }
+    }
+    let line_width = input
+        .opt_value_from_fn("--line-width", |s| usize::from_str_radix(s, 10))?
+        .unwrap_or(80);
+    let passes = input.opt_value_from_str("--passes")?.unwrap_or_default();
     let mut search_path = Vec::new();
     while let Some(include) = input.opt_value_from_fn(["-I", "--include"], str_to_path_buf)? {
         search_path.push(include);
@@ -358,7 +601,7 @@ fn parse_args() -> Result<(BinOpt, PathBuf), pico_args::Error> {
             debug_hir: input.contains("--debug-hir"),
             no_link: input.contains(["-c", "--no-link"]),
             #[cfg(feature = "jit")]
-            jit: input.contains("--jit"),
+            jit,
             max_errors,
             definitions,
             search_path,
@@ -369,6 +612,12 @@ fn parse_args() -> Result<(BinOpt, PathBuf), pico_args::Error> {
                 .unwrap_or_else(|| "-".into()),
         },
         color: color_choice,
+        backend,
+        brine: BrineOpt {
+            emit: emit.unwrap_or(EmitKind::Unlambda),
+            line_width,
+            passes,
+        },
     };
     Ok((bin_opt, output))
 }
@@ -454,54 +703,12 @@ fn error<T: std::fmt::Display>(msg: T, location: Location, file_db: &Files, colo
         msgs[rng.gen_range(0, msgs.len())]
     };
 
-    let prefix = if color.use_color_for(atty::Stream::Stdout) {
+    let prefix = if color.use_color_for(atty::Stream::Stderr) {
         Colour::Red.bold().paint(err)
     } else {
         ANSIString::from(err)
     };
-    print!("{}", pretty_print(prefix, msg, location, file_db,));
-}
-
-#[must_use]
-fn pretty_print<T: std::fmt::Display>(
-    prefix: ANSIString,
-    msg: T,
-    location: Location,
-    file_db: &Files,
-) -> String {
-    let file = location.file;
-    let start = file_db
-        .location(file, location.span.start)
-        .expect("start location should be in bounds");
-    let buf = format!(
-        "{}:{}:{} {}: {}\n",
-        file_db.name(file).to_string_lossy(),
-        start.line.number(),
-        start.column.number(),
-        prefix,
-        msg
-    );
-    // avoid printing spurious newline for errors and EOF
-    if location.span.end == 0 {
-        return buf;
-    }
-    let end = file_db
-        .location(file, location.span.end)
-        .expect("end location should be in bounds");
-    if start.line == end.line {
-        let line = file_db
-            .line_span(file, start.line)
-            .expect("line should be in bounds");
-        format!(
-            "{}{}{}{}\n",
-            buf,
-            file_db.source_slice(file, line).unwrap(),
-            " ".repeat(start.column.0 as usize),
-            "^".repeat((end.column - start.column).0 as usize)
-        )
-    } else {
-        buf
-    }
+    eprint!("{}", pretty_print(prefix, msg, location, file_db,));
 }
 
 #[inline]
@@ -591,30 +798,3 @@ fn install_panic_hook() {
         old_hook(e);
     }));
 }
-
-#[cfg(test)]
-mod test {
-    use super::{Files, Location};
-    use ansi_term::Style;
-    use saltwater_parser::data::lex::Span;
-
-    fn pp<S: Into<Span>>(span: S, source: &str) -> String {
-        let mut file_db = Files::new();
-        let source = String::from(source).into();
-        let file = file_db.add("<test-suite>", source);
-        let location = Location {
-            file,
-            span: span.into(),
-        };
-        let ansi_str = Style::new().paint("");
-        super::pretty_print(ansi_str, "", location, &file_db)
-    }
-    #[test]
-    fn pretty_print() {
-        assert_eq!(
-            dbg!(pp(8..15, "int i = \"hello\";\n")).lines().nth(2),
-            Some("        ^^^^^^^")
-        );
-        pp(0..0, "");
-    }
-}
@@ -0,0 +1,201 @@
+//! Run the runner tests through both the Cranelift JIT and brine, and check that they
+//! agree on the exit code and output of each test.
+//!
+//! The JIT runs in a separate `swcc --jit` process, so that its output can be captured
+//! and a crash can't take the harness down. brine's programs run in miri.
+
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, BufRead};
+use std::panic;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use brine::miri::{run_with_limits, Limits, Obj};
+use saltwater_parser::{pretty_print, Opt, Program};
+
+/// How far miri lets a test run before calling it stuck.
+const LIMITS: Limits = Limits {
+    steps: Some(50_000_000),
+    depth: Some(1_000_000),
+    memory: Some(10_000_000),
+};
+
+/// Tests whose behaviour is undefined, so the backends are allowed to disagree.
+const UNDEFINED: &[(&str, &str)] = &[(
+    "tests/runner-tests/decls/large_locals.c",
+    "it reads uninitialized memory",
+)];
+
+/// The exit code and output of a test.
+#[derive(Debug, PartialEq, Eq)]
+struct Run {
+    code: i32,
+    stdout: Vec<u8>,
+}
+
+enum Outcome {
+    /// Both backends gave the same exit code and output
+    Agree,
+    /// brine reported a feature the test uses as unsupported
+    Unsupported(String),
+    /// The backends disagree, or brine failed where the JIT didn't
+    Diverge(String),
+    /// The JIT couldn't compile or run the test, so there is nothing to compare to
+    Skipped(String),
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Outcome::Agree => write!(f, "ok"),
+            Outcome::Unsupported(reason) => write!(f, "unsupported: {}", reason),
+            Outcome::Diverge(reason) => write!(f, "DIVERGES: {}", reason),
+            Outcome::Skipped(reason) => write!(f, "skipped: {}", reason),
+        }
+    }
+}
+
+#[test]
+fn jit_and_brine_agree() -> Result<(), io::Error> {
+    let _ = env_logger::try_init();
+    let (mut agree, mut unsupported, mut skipped) = (0, 0, 0);
+    let mut diverge = Vec::new();
+    for entry in walkdir::WalkDir::new("tests/runner-tests").follow_links(true) {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type().is_dir() || path.extension().map_or(true, |e| e != "c") {
+            continue;
+        }
+        if !is_run(path)? {
+            continue;
+        }
+        let outcome = compare(path)?;
+        println!("{}: {}", path.display(), outcome);
+        match outcome {
+            Outcome::Agree => agree += 1,
+            Outcome::Unsupported(_) => unsupported += 1,
+            Outcome::Diverge(_) => diverge.push(path.to_owned()),
+            Outcome::Skipped(_) => skipped += 1,
+        }
+    }
+    println!(
+        "{} agree, {} unsupported by brine, {} skipped, {} diverge",
+        agree,
+        unsupported,
+        skipped,
+        diverge.len()
+    );
+    assert!(diverge.is_empty(), "the backends diverge on {:?}", diverge);
+    Ok(())
+}
+
+/// Whether the test at `path` is meant to be run, judging by its first line (see
+/// `runner.rs`).
+fn is_run(path: &Path) -> Result<bool, io::Error> {
+    let mut reader = io::BufReader::new(std::fs::File::open(path)?);
+    let mut first_line = String::new();
+    reader.read_line(&mut first_line)?;
+    Ok(!matches!(
+        first_line.trim_end(),
+        "// compile"
+            | "// no-main"
+            | "// fail"
+            | "// compile-fail"
+            | "// compile-error"
+            | "// crash"
+            | "// ignore"
+    ) && !first_line.starts_with("// errors: ")
+        && !first_line.starts_with("// ignore: "))
+}
+
+fn compare(path: &Path) -> Result<Outcome, io::Error> {
+    if let Some((_, reason)) = UNDEFINED.iter().find(|(test, _)| path == Path::new(test)) {
+        return Ok(Outcome::Skipped(reason.to_string()));
+    }
+    let jit = match run_jit(path)? {
+        Ok(run) => run,
+        Err(reason) => return Ok(Outcome::Skipped(reason)),
+    };
+    let program = std::fs::read_to_string(path)?;
+    // a panic in brine is a bug like any other divergence, so keep going after one
+    let brine = match panic::catch_unwind(|| run_brine(&program, path)) {
+        Ok(Ok(run)) => run,
+        Ok(Err(outcome)) => return Ok(outcome),
+        Err(_) => return Ok(Outcome::Diverge("brine panicked".to_string())),
+    };
+    Ok(if brine == jit {
+        Outcome::Agree
+    } else if brine.code != jit.code {
+        Outcome::Diverge(format!(
+            "exit code {} with the JIT, {} with brine",
+            jit.code, brine.code
+        ))
+    } else {
+        Outcome::Diverge(format!(
+            "output {:?} with the JIT, {:?} with brine",
+            String::from_utf8_lossy(&jit.stdout),
+            String::from_utf8_lossy(&brine.stdout)
+        ))
+    })
+}
+
+fn run_jit(path: &Path) -> Result<Result<Run, String>, io::Error> {
+    let output = Command::new(env!("CARGO_BIN_EXE_swcc"))
+        .arg("--jit")
+        .arg("--color=never")
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()?;
+    // warnings also go to stderr, so only look for errors
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Ok(match output.status.code() {
+        Some(code) if !stderr.contains("error") => Ok(Run {
+            code,
+            stdout: output.stdout,
+        }),
+        Some(code) => Err(format!(
+            "the JIT exited with {} and said {}",
+            code,
+            stderr.trim_end()
+        )),
+        None => Err("the JIT crashed".to_string()),
+    })
+}
+
+fn run_brine(program: &str, path: &Path) -> Result<Run, Outcome> {
+    let opt = Opt {
+        filename: path.to_owned(),
+        ..Opt::default()
+    };
+    let (program, source_map) = brine::compile_with_source_map(program, opt);
+    let Program { result, files, .. } = program;
+    let mir = match result {
+        Ok(mir) => mir.desugar(),
+        Err(errors) => {
+            let error = errors.front().unwrap();
+            let message = pretty_print("error", &error.data, error.location, &files);
+            return Err(if brine::is_unsupported(error) {
+                Outcome::Unsupported(error.data.to_string())
+            } else {
+                Outcome::Diverge(format!("brine could not compile it:\n{}", message))
+            });
+        }
+    };
+    let mut stdout = Vec::new();
+    let result = run_with_limits(&mir, &[], LIMITS, &mut io::empty(), &mut stdout);
+    let returned = result.map_err(|err| {
+        let message = match source_map.locate(&err.context) {
+            Some(location) => pretty_print("runtime error", &err.kind, location, &files),
+            None => err.to_string(),
+        };
+        Outcome::Diverge(format!("miri failed:\n{}", message))
+    })?;
+    match returned {
+        // exit codes are the low 8 bits of the value `main` returns
+        Obj::Int(i) => Ok(Run {
+            code: i32::from(i as u8),
+            stdout,
+        }),
+        other => Err(Outcome::Diverge(format!("main returned {} in miri", other))),
+    }
+}
@@ -0,0 +1,55 @@
+//! Run C programs with `swcc --interpret`, which compiles them with brine and runs them
+//! in miri.
+
+use std::io::{self, Write};
+use std::process::{Command, Output, Stdio};
+
+use tempfile::NamedTempFile;
+
+fn interpret(program: &str, stdin: &[u8]) -> Result<Output, io::Error> {
+    let mut file = NamedTempFile::new()?;
+    file.write_all(program.as_bytes())?;
+    let mut child = Command::new(env!("CARGO_BIN_EXE_swcc"))
+        .arg("--interpret")
+        .arg("--color=never")
+        .arg(file.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(stdin)?;
+    child.wait_with_output()
+}
+
+#[test]
+fn exit_code() -> Result<(), io::Error> {
+    let output = interpret("int main() { return 3 * 7; }", b"")?;
+    assert_eq!(output.status.code(), Some(21));
+    // only the low 8 bits are kept, like for native programs
+    let output = interpret("int main() { return 300; }", b"")?;
+    assert_eq!(output.status.code(), Some(44));
+    Ok(())
+}
+
+#[test]
+fn io() -> Result<(), io::Error> {
+    let program = "int getchar(void); int putchar(int);
+        int main() { int c; while ((c = getchar()) != -1) putchar(c + 1); }";
+    let output = interpret(program, b"HAL")?;
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"IBM");
+    Ok(())
+}
+
+#[test]
+fn runtime_error() -> Result<(), io::Error> {
+    let output = interpret("int main() {\n    int a = 0;\n    return 1 / a;\n}\n", b"")?;
+    assert_ne!(output.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(":3:12 runtime error: division by zero"),
+        "{}",
+        stderr
+    );
+    Ok(())
+}
@@ -0,0 +1,6 @@
+// code: 3
+int main() {
+    int a = 0x0102;
+    char *p = (char *)&a;
+    return p[0] + p[1];
+}